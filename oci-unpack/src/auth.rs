@@ -0,0 +1,193 @@
+//! Registry credentials, discovered like docker does: from `~/.docker/config.json`.
+//!
+//! For each registry, credentials are looked up in this order:
+//! a registry specific credential helper (`credHelpers`), the `auths` entry of the registry,
+//! and the default credential store (`credsStore`).  Without credentials, registries are accessed anonymously.
+
+use std::{
+	collections::HashMap,
+	env, fmt, fs,
+	io::Write,
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use oci_client::{secrets::RegistryAuth, Reference};
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+/// Prefix of the programs implementing the [docker credential helper protocol].
+///
+/// [docker credential helper protocol]: https://github.com/docker/docker-credential-helpers
+const HELPER_PREFIX: &str = "docker-credential-";
+
+/// The parts of a docker `config.json` describing credentials.
+#[derive(Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DockerConfig {
+	#[serde(default)]
+	auths: HashMap<String, AuthEntry>,
+	#[serde(default)]
+	creds_store: Option<String>,
+	#[serde(default)]
+	cred_helpers: HashMap<String, String>,
+
+	/// Directory to find credential helpers in.  If none is set, they are searched on `$PATH`.
+	#[serde(skip)]
+	pub(crate) helper_dir: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Default)]
+struct AuthEntry {
+	/// Base64 encoded "username:password".
+	#[serde(default)]
+	auth: Option<String>,
+	#[serde(default)]
+	username: Option<String>,
+	#[serde(default)]
+	password: Option<String>,
+}
+
+/// Response of a credential helper's `get` command.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct HelperCredentials {
+	username: String,
+	secret: String,
+}
+
+impl DockerConfig {
+	/// Location of the docker config: `$DOCKER_CONFIG/config.json` or `$HOME/.docker/config.json`.
+	/// Returns `None` if there is no config file.
+	pub fn default_path() -> Option<PathBuf> {
+		let dir = match env::var_os("DOCKER_CONFIG") {
+			Some(dir) => PathBuf::from(dir),
+			None => PathBuf::from(env::var_os("HOME")?).join(".docker"),
+		};
+		let path = dir.join("config.json");
+		path.is_file().then_some(path)
+	}
+
+	pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+		let path = path.as_ref();
+		serde_json::from_slice(&fs::read(path)?)
+			.map_err(|err| Error::Credentials(format!("failed to parse {}: {err}", path.display())))
+	}
+
+	/// Credentials to use for the registry of `image`, or its mirror registry if one is set.
+	pub fn auth_for(&self, image: &Reference) -> Result<RegistryAuth> {
+		let registry = normalize_registry(image.resolve_registry());
+
+		let helper = (self.cred_helpers.iter())
+			.find(|(server, _)| normalize_registry(server) == registry)
+			.map(|(_, helper)| helper);
+		if let Some(helper) = helper {
+			return self.run_helper(helper, registry);
+		}
+
+		let entry = (self.auths.iter())
+			.find(|(server, _)| normalize_registry(server) == registry)
+			.map(|(_, entry)| entry);
+		if let Some(auth) = entry.and_then(|entry| entry.to_auth(registry).transpose()) {
+			return auth;
+		}
+
+		match &self.creds_store {
+			Some(store) => self.run_helper(store, registry),
+			None => Ok(RegistryAuth::Anonymous),
+		}
+	}
+
+	/// Ask a credential helper for the credentials of `registry`.
+	fn run_helper(&self, helper: &str, registry: &str) -> Result<RegistryAuth> {
+		let program = format!("{HELPER_PREFIX}{helper}");
+		let program = match &self.helper_dir {
+			Some(dir) => dir.join(program),
+			None => PathBuf::from(program),
+		};
+		let credentials_error =
+			|reason: String| Error::Credentials(format!("{registry}: {reason}"));
+
+		let mut child = (Command::new(&program).arg("get"))
+			.stdin(Stdio::piped())
+			.stdout(Stdio::piped())
+			.stderr(Stdio::piped())
+			.spawn()
+			.map_err(|err| {
+				credentials_error(format!("failed to run {}: {err}", program.display()))
+			})?;
+		// Helpers know docker hub by the server address docker uses for it.
+		let server = match registry {
+			"docker.io" => "https://index.docker.io/v1/",
+			registry => registry,
+		};
+		child.stdin.take().unwrap().write_all(server.as_bytes())?;
+		let output = child.wait_with_output()?;
+
+		if !output.status.success() {
+			let message = String::from_utf8_lossy(&output.stdout);
+			// Not having credentials for a registry is fine: it might be public.
+			if message.contains("credentials not found") {
+				return Ok(RegistryAuth::Anonymous);
+			}
+			let reason = format!("{} failed: {}", program.display(), message.trim());
+			return Err(credentials_error(reason));
+		}
+
+		let credentials: HelperCredentials = serde_json::from_slice(&output.stdout)
+			.map_err(|err| credentials_error(format!("invalid helper response: {err}")))?;
+		Ok(RegistryAuth::Basic(
+			credentials.username,
+			credentials.secret,
+		))
+	}
+}
+
+impl AuthEntry {
+	fn to_auth(&self, registry: &str) -> Result<Option<RegistryAuth>> {
+		if let (Some(username), Some(password)) = (&self.username, &self.password) {
+			return Ok(Some(RegistryAuth::Basic(
+				username.clone(),
+				password.clone(),
+			)));
+		}
+		let Some(auth) = self.auth.as_ref().filter(|auth| !auth.is_empty()) else {
+			return Ok(None);
+		};
+
+		let decoded = (STANDARD.decode(auth).ok())
+			.and_then(|decoded| String::from_utf8(decoded).ok())
+			.ok_or_else(|| Error::Credentials(format!("{registry}: 'auth' is not valid base64")))?;
+		let Some((username, password)) = decoded.split_once(':') else {
+			let reason = format!("{registry}: 'auth' is not of the form 'username:password'");
+			return Err(Error::Credentials(reason));
+		};
+		Ok(Some(RegistryAuth::Basic(username.into(), password.into())))
+	}
+}
+
+/// Credentials are never printed.
+impl fmt::Debug for DockerConfig {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_struct("DockerConfig")
+			.field("auths", &self.auths.keys().collect::<Vec<_>>())
+			.field("creds_store", &self.creds_store)
+			.field("cred_helpers", &self.cred_helpers)
+			.finish()
+	}
+}
+
+/// Docker config keys are server addresses like "https://index.docker.io/v1/",
+/// while references name registries like "docker.io".
+pub(crate) fn normalize_registry(server: &str) -> &str {
+	let server = (server.strip_prefix("https://"))
+		.or_else(|| server.strip_prefix("http://"))
+		.unwrap_or(server);
+	let host = server.split('/').next().unwrap_or(server);
+	match host {
+		"index.docker.io" | "registry-1.docker.io" => "docker.io",
+		host => host,
+	}
+}
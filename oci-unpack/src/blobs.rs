@@ -0,0 +1,214 @@
+//! Blobs (manifests, configs and layers) stored on disk, addressed by their digest.
+//!
+//! Blobs are written to a temporary file first and moved into place, once their digest
+//! was verified. Therefore a blob file, which exists, is always complete.
+
+use std::{
+	fs,
+	io::{self, BufWriter, Write},
+	path::{Path, PathBuf},
+	pin::Pin,
+	task::{Context, Poll},
+};
+
+use oci_client::{manifest::OciDescriptor, Client, Reference};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWrite;
+
+use crate::{
+	digest::{Algorithm, Digest},
+	tee::WriteExt,
+	Error, Result,
+};
+
+/// A layer, whose (possibly compressed) data is stored in a blob file.
+#[derive(Clone, Debug)]
+pub(crate) struct LayerBlob {
+	pub(crate) media_type: String,
+	pub(crate) digest: String,
+	pub(crate) path: PathBuf,
+}
+
+pub(crate) fn blob_path(blob_dir: impl AsRef<Path>, digest: &str) -> Result<PathBuf> {
+	let digest: Digest = digest.parse()?;
+	let encoded = digest.encoded();
+
+	Ok((blob_dir.as_ref())
+		.join(digest.algorithm().name())
+		.join(&encoded[..2])
+		.join(&encoded[2..4])
+		.join(encoded))
+}
+
+/// A file found in a blob directory.
+pub(crate) struct BlobFile {
+	/// Digest the file is stored under.  `None` for leftover temporary files.
+	pub(crate) digest: Option<String>,
+	pub(crate) path: PathBuf,
+	pub(crate) size: u64,
+}
+
+/// List all files stored in a blob directory.
+pub(crate) fn list_blobs(blob_dir: impl AsRef<Path>) -> Result<Vec<BlobFile>> {
+	let mut blobs = Vec::new();
+	for algorithm in Algorithm::ALL {
+		let algorithm_dir = blob_dir.as_ref().join(algorithm.name());
+		if !algorithm_dir.is_dir() {
+			continue;
+		}
+
+		for level_1 in fs::read_dir(algorithm_dir)? {
+			for level_2 in fs::read_dir(level_1?.path())? {
+				for file in fs::read_dir(level_2?.path())? {
+					let file = file?;
+					let name = file.file_name().to_string_lossy().into_owned();
+					let digest = (!name.starts_with('.')).then(|| format!("{algorithm}:{name}"));
+					blobs.push(BlobFile {
+						digest,
+						path: file.path(),
+						size: file.metadata()?.len(),
+					});
+				}
+			}
+		}
+	}
+
+	blobs.sort_by(|a, b| a.path.cmp(&b.path));
+	Ok(blobs)
+}
+
+/// Hash a blob file without reading it into memory.
+pub(crate) fn digest_file(path: impl AsRef<Path>, algorithm: Algorithm) -> Result<String> {
+	let mut digester = algorithm.digester();
+	io::copy(&mut fs::File::open(path)?, &mut digester)?;
+	Ok(digester.finalize().to_string())
+}
+
+/// Read a small blob (like a manifest or config) into memory, verifying its digest.
+pub(crate) fn read_blob(blob_dir: impl AsRef<Path>, digest: &str) -> Result<Vec<u8>> {
+	let data = fs::read(blob_path(blob_dir, digest)?)?;
+
+	if !digest.parse::<Digest>()?.matches(&data) {
+		return Err(Error::CorruptCacheBlob {
+			digest: digest.to_owned(),
+		});
+	}
+
+	Ok(data)
+}
+
+/// Store a small blob, which is already held in memory.
+pub(crate) fn write_blob(blob_dir: impl AsRef<Path>, digest: &str, data: &[u8]) -> Result<()> {
+	let path = blob_path(blob_dir, digest)?;
+	if path.exists() {
+		return Ok(());
+	}
+
+	if !digest.parse::<Digest>()?.matches(data) {
+		let digest = digest.to_owned();
+		return Err(Error::DownloadDigestMismatch { digest });
+	}
+
+	let mut file = temp_file_for(&path)?;
+	file.write_all(data)?;
+	persist(file, &path)
+}
+
+/// Download a blob from the registry straight to disk, hashing it on the fly.
+/// Blobs which already exist are not downloaded again.
+pub(crate) async fn download_blob(
+	client: &Client,
+	image: &Reference,
+	descriptor: &OciDescriptor,
+	blob_dir: impl AsRef<Path>,
+) -> Result<PathBuf> {
+	let path = blob_path(blob_dir, &descriptor.digest)?;
+	if path.exists() {
+		return Ok(path);
+	}
+
+	let file = temp_file_for(&path)?;
+	let expected: Digest = descriptor.digest.parse()?;
+	let mut digester = expected.algorithm().digester();
+	{
+		let mut writer = BufWriter::new(file.as_file()).tee(&mut digester);
+		(client.pull_blob(image, descriptor, BlockingWrite(&mut writer))).await?;
+		writer.flush()?;
+	}
+
+	if digester.finalize() != expected {
+		let digest = descriptor.digest.clone();
+		return Err(Error::DownloadDigestMismatch { digest });
+	}
+
+	persist(file, &path)?;
+	Ok(path)
+}
+
+/// Copy a blob from a file outside of the blob directory, e.g. from an OCI image layout.
+/// Blobs which already exist are not copied again.
+pub(crate) fn import_blob(
+	blob_dir: impl AsRef<Path>,
+	digest: &str,
+	source: impl AsRef<Path>,
+) -> Result<PathBuf> {
+	let path = blob_path(blob_dir, digest)?;
+	if path.exists() {
+		return Ok(path);
+	}
+
+	let file = temp_file_for(&path)?;
+	let expected: Digest = digest.parse()?;
+	let mut digester = expected.algorithm().digester();
+	{
+		let mut writer = BufWriter::new(file.as_file()).tee(&mut digester);
+		io::copy(&mut fs::File::open(source)?, &mut writer)?;
+		writer.flush()?;
+	}
+
+	if digester.finalize() != expected {
+		return Err(Error::ImageInvalid(format!(
+			"blob data did not match digest: {digest}"
+		)));
+	}
+
+	persist(file, &path)?;
+	Ok(path)
+}
+
+pub(crate) fn temp_file_for(path: &Path) -> Result<NamedTempFile> {
+	let parent = path.parent().expect("blob paths have a parent");
+	fs::create_dir_all(parent)?;
+	Ok(NamedTempFile::new_in(parent)?)
+}
+
+pub(crate) fn persist(file: NamedTempFile, path: &Path) -> Result<()> {
+	file.as_file().sync_all()?;
+	// Another process might have stored the same blob in the meantime, which is fine:
+	// blobs are immutable, so both files have the same content.
+	// The index is only replaced while holding the index lock.
+	file.persist(path).map_err(|err| err.error)?;
+	Ok(())
+}
+
+/// Adapter to use a blocking writer, where an async writer is required.
+/// Writing to local files is fast compared to downloading, so we don't bother spawning blocking tasks.
+struct BlockingWrite<W>(W);
+
+impl<W: Write + Unpin> AsyncWrite for BlockingWrite<W> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		_: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		Poll::Ready(self.get_mut().0.write(buf))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(self.get_mut().0.flush())
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		self.poll_flush(cx)
+	}
+}
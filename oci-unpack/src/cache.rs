@@ -1,61 +1,99 @@
-// TODO: Function to remove image from cache.
-// TODO: GC function to remove orpahn blobs.
+//! Cache of pulled images, shared by all pulls using the same cache directory.
+//!
+//! Besides the cache used by [crate::pull_image], this module provides functions
+//! to inspect and maintain a cache directory: [list], [remove], [verify] and [gc].
+//!
+//! Changes to the index are serialized by an advisory lock (`flock`) on `index.lock`.
+//! Taking a lock blocks until it is available, however long its current holder needs.
+//! The kernel releases the lock when its holder exits, so crashed processes can't leave a stale lock behind.
+//! The index itself is replaced atomically, so it can always be read without taking the lock.
+//!
+//! Blobs are only removed by [gc], which takes an exclusive lock on `blobs.lock` as well.
+//! Images read from the cache hold a shared lock on it until they were unpacked,
+//! so their blobs can't be removed in the meantime, without waiting for downloads holding the index lock.
+//! [verify] and exports take the same shared lock.
 
 use std::{
-	fs::{self, OpenOptions},
-	io::{ErrorKind, Write},
+	collections::HashSet,
+	fs::{self, File, OpenOptions},
+	io::Write,
 	path::{Path, PathBuf},
-	thread::sleep,
-	time::{Duration, Instant},
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use indexmap::{map, IndexMap};
-use oci_client::{
-	client::{ImageData, ImageLayer},
-	manifest::{OciImageManifest, OCI_IMAGE_MEDIA_TYPE},
-	Reference,
-};
+use oci_client::{manifest::OciImageManifest, Reference};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 
-use crate::{Error, PullConfig, Result};
+use crate::{
+	blobs::{
+		blob_path, digest_file, list_blobs, persist, read_blob, temp_file_for, write_blob,
+		LayerBlob,
+	},
+	digest::Digest,
+	Error, ImageData, Platform, PullConfig, ResolvedManifest, Result,
+};
 
 const BLOBS_DIR: &str = "blobs";
 const INDEX_FILE: &str = "index.json";
 const LOCK_FILE: &str = "index.lock";
+const BLOBS_LOCK_FILE: &str = "blobs.lock";
+/// Prefix of the temporary files created by [tempfile], e.g. by [write_index].
+const TEMP_FILE_PREFIX: &str = ".tmp";
 
-const CACHE_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+/// Version of the index format, see [migrate_index].
+const INDEX_VERSION: u32 = 1;
 
 pub(crate) struct Cache<'a> {
 	config: &'a PullConfig,
-	has_lock: bool,
+	lock: Option<IndexLock>,
+}
+
+/// Lock on the cache index (or its blobs, or the snapshots), released when dropped.
+pub(crate) struct IndexLock {
+	/// The lock is held as long as the file is open.
+	_file: File,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct Index {
+	/// Indexes without version are version 0.
+	#[serde(default)]
+	version: u32,
 	images: IndexMap<String, IndexImage>,
+	/// Last known resolution of tags, keyed like [Index::images].
+	#[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+	tags: IndexMap<String, IndexTag>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct IndexImage {
 	manifest_digest: String,
+	/// Digest of the image index, if the manifest was selected from one.
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	index_digest: Option<String>,
 }
 
-impl<'a> Drop for Cache<'a> {
-	fn drop(&mut self) {
-		if self.has_lock {
-			let path = self.config.cache.as_ref().unwrap().join(LOCK_FILE);
-			fs::remove_file(path).expect("failed to remove cache lock file");
-		}
-	}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IndexTag {
+	manifest_digest: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	index_digest: Option<String>,
+	/// Seconds since the unix epoch.
+	resolved_at: u64,
+}
+
+/// The manifest a tag resolved to when it was last looked up in a registry, see [resolved_tag].
+#[derive(Clone, Debug)]
+pub struct ResolvedTag {
+	pub manifest_digest: String,
+	pub index_digest: Option<String>,
+	pub resolved_at: SystemTime,
 }
 
 impl<'a> Cache<'a> {
 	pub(crate) fn new(config: &'a PullConfig) -> Self {
-		Cache {
-			config,
-			has_lock: false,
-		}
+		Cache { config, lock: None }
 	}
 
 	pub(crate) fn before_pull(&mut self, image: &Reference) -> Result<Option<ImageData>> {
@@ -63,237 +101,530 @@ impl<'a> Cache<'a> {
 			return Ok(None);
 		};
 
-		if !cache_dir.is_dir() {
+		if !cache_dir.join(INDEX_FILE).is_file() {
 			init_cache(cache_dir)?;
 		}
 
-		match image_from_reference(cache_dir, image) {
+		let key = cache_key(image, &self.config.platform);
+		let blobs_lock = lock_blobs_shared(cache_dir)?;
+		match image_from_reference(cache_dir, &key) {
 			Ok(None) | Err(Error::ParseCacheIndex(_)) => {}
-			result @ (Ok(Some(_)) | Err(_)) => return result,
+			result @ (Ok(Some(_)) | Err(_)) => return with_lock(result, blobs_lock),
+		}
+
+		if self.config.offline {
+			// Without a registry, a tag can still be found, if the image was pulled by its digest.
+			if image.digest().is_some() {
+				return Ok(None);
+			}
+			let Some(tag) = self.resolved_tag(image)? else {
+				return Ok(None);
+			};
+			let key = cache_key(
+				&image.clone_with_digest(tag.manifest_digest),
+				&self.config.platform,
+			);
+			return with_lock(image_from_reference(cache_dir, &key), blobs_lock);
 		}
+		// Blobs are only locked again after taking the index lock, in the same order as [gc].
+		drop(blobs_lock);
 
 		// Trying to lock index. We do this, because we are going to report
 		// a cache miss and therefore trigger a pull from the registry next.
 		// To prevent redundant downloads (e.g. during testing), we lock here.
-		self.lock_index(cache_dir)?;
+		if self.lock.is_none() {
+			self.lock = Some(lock_index(cache_dir)?);
+		}
 
 		// Prevent race condition: check again if we do not have image by now.
-		if let Some(image_data) = image_from_reference(cache_dir, image)? {
-			return Ok(Some(image_data));
+		if let Some(cached) = image_from_reference(cache_dir, &key)? {
+			return with_lock(Ok(Some(cached)), lock_blobs_shared(cache_dir)?);
 		}
 
 		Ok(None)
 	}
 
-	pub(crate) async fn after_pull(
+	/// Shared lock on the blobs, which keeps [gc] from removing the blobs of a pulled image
+	/// until it was unpacked.  Must be called while holding the index lock taken by [Self::before_pull].
+	pub(crate) fn lock_blobs(&self) -> Result<Option<IndexLock>> {
+		let Some(cache_dir) = &self.config.cache else {
+			return Ok(None);
+		};
+		assert!(self.lock.is_some(), "should have called before_pull");
+		lock_blobs_shared(cache_dir).map(Some)
+	}
+
+	/// Look up the last resolution of a tag.
+	pub(crate) fn resolved_tag(&self, image: &Reference) -> Result<Option<ResolvedTag>> {
+		match &self.config.cache {
+			Some(cache_dir) => resolved_tag(cache_dir, image, &self.config.platform),
+			None => Ok(None),
+		}
+	}
+
+	/// Record the manifest a tag was resolved to.
+	/// Must only be called after [Self::before_pull] reported a cache miss.
+	pub(crate) fn after_resolve(
 		&mut self,
 		image: &Reference,
-		image_data: &ImageData,
-		client: &oci_client::Client,
-		config: &PullConfig,
+		resolved: &ResolvedManifest,
 	) -> Result<()> {
-		let Some(cache_dir) = &config.cache else {
+		let Some(cache_dir) = &self.config.cache else {
 			return Ok(());
 		};
-		assert!(self.has_lock, "should have called before_pull");
-
-		// Obtain exact manifest bytes, because we need to make sure digest of manifest is correct.
-		let digest = image_data.digest.as_ref().unwrap();
-		let media_type = &image_data.manifest.as_ref().unwrap().media_type;
-		let media_type = media_type.as_ref().map(AsRef::as_ref);
-		let media_type = [media_type.unwrap_or(OCI_IMAGE_MEDIA_TYPE)];
-		let reference = image.clone_with_digest(digest.to_owned());
-		let (manifest_raw, digest_raw) = client
-			.pull_manifest_raw(&reference, &config.auth, &media_type)
-			.await?;
-		assert_eq!(digest, &digest_raw);
-
-		add_image_to_cache(cache_dir, image, image_data, &manifest_raw)
+		assert!(self.lock.is_some(), "should have called before_pull");
+		if image.digest().is_some() {
+			return Ok(());
+		}
+
+		let mut index = get_index(cache_dir)?;
+		index.tags.insert(
+			cache_key(image, &self.config.platform),
+			IndexTag {
+				manifest_digest: resolved.manifest_digest.clone(),
+				index_digest: resolved.index_digest.clone(),
+				resolved_at: unix_time(),
+			},
+		);
+		write_index(cache_dir, &index)
+	}
+
+	/// Directory where blobs should be downloaded to, if a cache is used.
+	pub(crate) fn blob_dir(&self) -> Option<PathBuf> {
+		(self.config.cache.as_ref()).map(|cache_dir| cache_dir.join(BLOBS_DIR))
 	}
 
-	fn lock_index(&mut self, cache_dir: impl AsRef<Path>) -> Result<()> {
-		if self.has_lock {
+	/// Record a pulled image in the cache index.
+	/// Its config and layers must already have been downloaded into [Self::blob_dir].
+	pub(crate) fn after_pull(
+		&mut self,
+		image: &Reference,
+		manifest_digest: &str,
+		manifest_raw: &[u8],
+		index_digest: Option<String>,
+	) -> Result<()> {
+		let Some(cache_dir) = &self.config.cache else {
 			return Ok(());
-		}
+		};
+		assert!(self.lock.is_some(), "should have called before_pull");
 
-		let path = cache_dir.as_ref().join(LOCK_FILE);
-		let start = Instant::now();
-
-		loop {
-			match OpenOptions::new().write(true).create_new(true).open(&path) {
-				Ok(_) => {
-					self.has_lock = true;
-					return Ok(());
-				}
-				Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-				_ => {
-					// Lock file already exists: wait and try again.
-					// TODO: Use notify instead of polling repeatedly.
-					while path.exists() {
-						if start.elapsed() >= CACHE_LOCK_TIMEOUT {
-							return Err(Error::CacheLockTimeout(path));
-						}
-						sleep(Duration::from_millis(50));
-					}
-				}
-			}
-		}
+		let key = cache_key(image, &self.config.platform);
+		add_image_to_cache(cache_dir, &key, manifest_digest, manifest_raw, index_digest)
 	}
 }
 
+/// Take the exclusive lock on the index, needed to change the index or remove blobs.
+/// Blocks until the lock is available, e.g. until a pull in progress has finished.
+pub(crate) fn lock_index(cache_dir: impl AsRef<Path>) -> Result<IndexLock> {
+	lock_file(cache_dir.as_ref().join(LOCK_FILE), File::lock)
+}
+
+/// Take the exclusive lock on the blobs, needed to remove blobs.  Callers must hold the index lock.
+fn lock_blobs(cache_dir: impl AsRef<Path>) -> Result<IndexLock> {
+	lock_file(cache_dir.as_ref().join(BLOBS_LOCK_FILE), File::lock)
+}
+
+/// Take a shared lock on the blobs, which keeps [gc] from removing blobs while reading them.
+/// Any number of processes can hold it at the same time, and it doesn't wait for downloads in progress.
+pub(crate) fn lock_blobs_shared(cache_dir: impl AsRef<Path>) -> Result<IndexLock> {
+	lock_file(cache_dir.as_ref().join(BLOBS_LOCK_FILE), File::lock_shared)
+}
+
+pub(crate) fn lock_file(
+	path: PathBuf,
+	lock: fn(&File) -> std::io::Result<()>,
+) -> Result<IndexLock> {
+	// Lock files left behind by older versions, which used the existence of the file as lock, are simply reused.
+	let file = (OpenOptions::new().create(true).truncate(false).write(true)).open(path)?;
+	lock(&file)?;
+	Ok(IndexLock { _file: file })
+}
+
 fn get_index(cache_dir: impl AsRef<Path>) -> Result<Index> {
-	fn get_index_raw(cache_dir: impl AsRef<Path>) -> Result<Index> {
-		let contents = fs::read(cache_dir.as_ref().join(INDEX_FILE))?;
-		serde_json::from_slice(&contents[..]).map_err(Error::ParseCacheIndex)
+	let contents = fs::read(cache_dir.as_ref().join(INDEX_FILE))?;
+	let mut index: Index = serde_json::from_slice(&contents[..]).map_err(Error::ParseCacheIndex)?;
+	if index.version < INDEX_VERSION {
+		migrate_index(&cache_dir, &mut index);
 	}
+	Ok(index)
+}
 
-	match get_index_raw(&cache_dir) {
-		Ok(index) => Ok(index),
-		Err(_) => {
-			sleep(Duration::from_millis(100));
-			get_index_raw(&cache_dir) // retry
+/// Upgrade an index of an older version.  The migrated index is stored by the next write of the index.
+///
+/// Version 0 keyed images by reference only, as images were always pulled for the current platform.
+/// They are keyed by the platform of their image configuration now.
+/// Images whose configuration can't be read are dropped, so their blobs are removed by the next [gc].
+fn migrate_index(cache_dir: impl AsRef<Path>, index: &mut Index) {
+	let images = std::mem::take(&mut index.images);
+	for (key, image) in images {
+		if key.contains(' ') {
+			index.images.insert(key, image);
+			continue;
 		}
+		let Ok(image_data) = image_from_blobs(&cache_dir, &image) else {
+			continue;
+		};
+		// Pulls always requested a platform without variant.
+		let platform = Platform {
+			os: image_data.config.os().to_string(),
+			architecture: image_data.config.architecture().to_string(),
+			variant: None,
+		};
+		index.images.insert(format!("{key} {platform}"), image);
 	}
+	// Tags were only recorded with platform.
+	index.tags.retain(|key, _| key.contains(' '));
+	index.version = INDEX_VERSION;
 }
 
-fn init_cache(cache_dir: impl AsRef<Path>) -> Result<()> {
+pub(crate) fn init_cache(cache_dir: impl AsRef<Path>) -> Result<()> {
 	fs::create_dir_all(cache_dir.as_ref().join(BLOBS_DIR))?;
 
-	let file =
-		(OpenOptions::new().write(true).create_new(true)).open(cache_dir.as_ref().join(INDEX_FILE));
-	match file {
-		Ok(mut file) => {
-			let index = Index {
-				images: IndexMap::with_capacity(0),
-			};
-			let data = serde_json::to_vec(&index).map_err(Error::ParseCacheIndex)?;
-			file.write_all(&data[..])?;
-		}
-		Err(error) if error.kind() != ErrorKind::AlreadyExists => return Err(error.into()),
-		_ => {}
+	let _lock = lock_index(&cache_dir)?;
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		let index = Index {
+			version: INDEX_VERSION,
+			images: IndexMap::with_capacity(0),
+			tags: IndexMap::with_capacity(0),
+		};
+		write_index(&cache_dir, &index)?;
 	}
 
 	Ok(())
 }
 
-fn image_from_reference(
+pub(crate) fn blob_dir(cache_dir: impl AsRef<Path>) -> PathBuf {
+	cache_dir.as_ref().join(BLOBS_DIR)
+}
+
+/// Record an image, whose blobs were imported into [blob_dir] while holding `_lock`.
+///
+/// The image is stored under its reference, its manifest digest and the digest of its image index.
+/// A tag is also recorded as resolved, so it can be used in offline mode.
+pub(crate) fn add_imported_image(
+	cache_dir: impl AsRef<Path>,
+	_lock: &IndexLock,
+	image: &Reference,
+	platform: &Platform,
+	manifest_digest: &str,
+	index_digest: Option<String>,
+) -> Result<()> {
+	let mut index = get_index(&cache_dir)?;
+	let indexed = IndexImage {
+		manifest_digest: manifest_digest.to_owned(),
+		index_digest: index_digest.clone(),
+	};
+	let by_digest = image.clone_with_digest(manifest_digest.to_owned());
+	(index.images).insert(cache_key(&by_digest, platform), indexed.clone());
+	if let Some(index_digest) = &index_digest {
+		// Like pulls by the digest of an image index, which are stored under that digest.
+		let by_index_digest = image.clone_with_digest(index_digest.clone());
+		(index.images).insert(cache_key(&by_index_digest, platform), indexed.clone());
+	}
+	if image.digest().is_none() {
+		index.images.insert(cache_key(image, platform), indexed);
+		index.tags.insert(
+			cache_key(image, platform),
+			IndexTag {
+				manifest_digest: manifest_digest.to_owned(),
+				index_digest,
+				resolved_at: unix_time(),
+			},
+		);
+	}
+	write_index(cache_dir, &index)
+}
+
+/// Look up the manifest digest of a cached image, by its reference or the last resolution of its tag.
+/// Also returns the digest of the image index the manifest was selected from, if any.
+pub(crate) fn find_manifest_digest(
 	cache_dir: impl AsRef<Path>,
 	image: &Reference,
-) -> Result<Option<ImageData>> {
+	platform: &Platform,
+) -> Result<Option<(String, Option<String>)>> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(None);
+	}
+	let index = get_index(&cache_dir)?;
+	let key = cache_key(image, platform);
+	let found = (index.images.get(&key))
+		.map(|image| (&image.manifest_digest, &image.index_digest))
+		.or_else(|| (index.tags.get(&key)).map(|tag| (&tag.manifest_digest, &tag.index_digest)));
+	Ok(
+		found
+			.map(|(manifest_digest, index_digest)| (manifest_digest.clone(), index_digest.clone())),
+	)
+}
+
+/// Key of an image in the cache index.
+/// References to image indexes resolve to different images per platform.
+fn cache_key(image: &Reference, platform: &Platform) -> String {
+	format!("{} {}", image.whole(), platform)
+}
+
+fn unix_time() -> u64 {
+	(SystemTime::now().duration_since(UNIX_EPOCH)).map_or(0, |duration| duration.as_secs())
+}
+
+/// Attach `lock` to a cached image, so it is held until the image was unpacked.
+fn with_lock(image: Result<Option<ImageData>>, lock: IndexLock) -> Result<Option<ImageData>> {
+	image.map(|image| {
+		image.map(|image| ImageData {
+			_cache_lock: Some(lock),
+			..image
+		})
+	})
+}
+
+fn image_from_reference(cache_dir: impl AsRef<Path>, key: &str) -> Result<Option<ImageData>> {
 	let mut index = get_index(&cache_dir)?;
-	if let map::Entry::Occupied(entry) = index.images.entry(image.whole()) {
-		return Ok(Some(image_from_blobs(
-			cache_dir,
-			&entry.get().manifest_digest,
-		)?));
+	if let map::Entry::Occupied(entry) = index.images.entry(key.to_owned()) {
+		let image = entry.get();
+		let image_data = image_from_blobs(cache_dir, image)?;
+		return Ok(Some(image_data));
 	}
 	Ok(None)
 }
 
-fn image_from_blobs(cache_dir: impl AsRef<Path>, manifest_digest: &str) -> Result<ImageData> {
+fn image_from_blobs(cache_dir: impl AsRef<Path>, image: &IndexImage) -> Result<ImageData> {
 	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
 
-	let manifest_data = read_blob(&blob_dir, manifest_digest)?;
+	let manifest_data = read_blob(&blob_dir, &image.manifest_digest)?;
 	let manifest: OciImageManifest =
 		serde_json::from_slice(&manifest_data[..]).map_err(Error::ParseManifest)?;
 
-	let config_digest = &manifest.config.digest;
-	let config_data = read_blob(&blob_dir, config_digest)?;
-	let config = oci_client::client::Config {
-		data: config_data,
-		media_type: manifest.config.media_type.to_owned(),
-		annotations: manifest.config.annotations.clone(),
-	};
+	let config_data = read_blob(&blob_dir, &manifest.config.digest)?;
+	let config = serde_json::from_slice(&config_data).map_err(Error::ParseImageConfiguration)?;
 
+	// Layers are only opened (and verified) when unpacking, to not read them into memory.
 	let mut layers = Vec::new();
 	for layer in &manifest.layers {
-		let data = read_blob(&blob_dir, &layer.digest)?;
-		layers.push(ImageLayer {
-			data,
+		let path = blob_path(&blob_dir, &layer.digest)?;
+		if !path.is_file() {
+			let digest = layer.digest.clone();
+			return Err(Error::CorruptCacheBlob { digest });
+		}
+		layers.push(LayerBlob {
 			media_type: layer.media_type.clone(),
-			annotations: layer.annotations.clone(),
+			digest: layer.digest.clone(),
+			path,
 		});
 	}
 
 	Ok(ImageData {
-		manifest: Some(manifest),
-		digest: Some(manifest_digest.to_owned()),
-		config,
+		manifest,
+		manifest_digest: image.manifest_digest.clone(),
+		index_digest: image.index_digest.clone(),
 		layers,
+		config,
+		_temp_blob_dir: None,
+		_cache_lock: None,
 	})
 }
 
 fn add_image_to_cache(
 	cache_dir: impl AsRef<Path>,
-	image: &Reference,
-	image_data: &ImageData,
+	key: &str,
+	manifest_digest: &str,
 	manifest_raw: &[u8],
+	index_digest: Option<String>,
 ) -> Result<()> {
 	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
+	write_blob(&blob_dir, manifest_digest, manifest_raw)?;
 
-	let digest = image_data.digest.as_ref().unwrap();
-	add_blob_to_cache(&blob_dir, digest, manifest_raw)?;
-
-	let config_digest = &image_data.manifest.as_ref().unwrap().config.digest;
-	add_blob_to_cache(&blob_dir, config_digest, &image_data.config.data[..])?;
-
-	let manifest_layers = &image_data.manifest.as_ref().unwrap().layers;
-	assert_eq!(manifest_layers.len(), image_data.layers.len());
-	for (i, layer) in image_data.layers.iter().enumerate() {
-		add_blob_to_cache(&blob_dir, &manifest_layers[i].digest, &layer.data[..])?;
-	}
-
-	let index_path = cache_dir.as_ref().join(INDEX_FILE);
 	let mut index = get_index(&cache_dir)?;
 	index.images.insert(
-		image.whole(),
+		key.to_owned(),
 		IndexImage {
-			manifest_digest: digest.to_owned(),
+			manifest_digest: manifest_digest.to_owned(),
+			index_digest,
 		},
 	);
-	let index_bytes = serde_json::to_vec(&index).unwrap();
-	fs::write(index_path, &index_bytes[..])?;
+	write_index(cache_dir, &index)
+}
 
-	Ok(())
+/// Replace the index atomically, so readers never see a partially written index.
+/// Callers must hold the exclusive lock.
+fn write_index(cache_dir: impl AsRef<Path>, index: &Index) -> Result<()> {
+	let index_path = cache_dir.as_ref().join(INDEX_FILE);
+	let index_bytes = serde_json::to_vec(index).map_err(Error::ParseCacheIndex)?;
+	let mut file = temp_file_for(&index_path)?;
+	file.write_all(&index_bytes[..])?;
+	persist(file, &index_path)
 }
 
-fn add_blob_to_cache(
-	blob_dir: impl AsRef<Path>,
-	digest: &str,
-	data: impl AsRef<[u8]>,
-) -> Result<()> {
-	if !digest.starts_with("sha256:") {
-		return Err(Error::DigestNotSupported {
-			digest: digest.to_owned(),
-		});
+/// An image stored in a cache directory.
+#[derive(Clone, Debug)]
+pub struct CachedImage {
+	/// The reference the image was pulled by.
+	pub reference: String,
+	/// Platform the image was selected for, e.g. "linux/amd64".
+	pub platform: String,
+	pub manifest_digest: String,
+	pub index_digest: Option<String>,
+	/// Size of all blobs of the image in bytes.
+	/// Blobs shared with other images are counted for each image.
+	pub size: u64,
+}
+
+/// Result of a garbage collection run, see [gc].
+#[derive(Clone, Debug, Default)]
+pub struct GcResult {
+	pub removed_blobs: usize,
+	/// Temporary files of index updates, left behind by crashed processes.
+	pub removed_temp_files: usize,
+	pub freed_bytes: u64,
+}
+
+/// List all images stored in the cache.
+pub fn list(cache_dir: impl AsRef<Path>) -> Result<Vec<CachedImage>> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(Vec::with_capacity(0));
 	}
+	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
 
-	let colon = digest.find(':').unwrap();
-	let blob_path = blob_path(blob_dir, digest, colon);
-	fs::create_dir_all(&blob_path)?;
-	Ok(fs::write(blob_path.join(&digest[colon + 1..]), data)?)
+	let index = get_index(&cache_dir)?;
+	let images = (index.images.into_iter())
+		.map(|(key, image)| {
+			let (reference, platform) = key.rsplit_once(' ').unwrap_or((&key, ""));
+			let size = (image_digests(&blob_dir, &image).iter())
+				.filter_map(|digest| blob_path(&blob_dir, digest).ok())
+				.filter_map(|path| path.metadata().ok())
+				.map(|meta| meta.len())
+				.sum();
+			CachedImage {
+				reference: reference.to_owned(),
+				platform: platform.to_owned(),
+				manifest_digest: image.manifest_digest,
+				index_digest: image.index_digest,
+				size,
+			}
+		})
+		.collect();
+	Ok(images)
 }
 
-fn read_blob(blob_dir: impl AsRef<Path>, digest: &str) -> Result<Vec<u8>> {
-	let colon = digest.find(':').unwrap();
-	let data = fs::read(blob_path(blob_dir, digest, colon).join(&digest[colon + 1..]))?;
+/// Look up the manifest `image` resolved to for `platform`, when its tag was last resolved by a registry.
+pub fn resolved_tag(
+	cache_dir: impl AsRef<Path>,
+	image: &Reference,
+	platform: &Platform,
+) -> Result<Option<ResolvedTag>> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(None);
+	}
+	let index = get_index(&cache_dir)?;
+	let tag = index.tags.get(&cache_key(image, platform));
+	Ok(tag.map(|tag| ResolvedTag {
+		manifest_digest: tag.manifest_digest.clone(),
+		index_digest: tag.index_digest.clone(),
+		resolved_at: UNIX_EPOCH + Duration::from_secs(tag.resolved_at),
+	}))
+}
 
-	let actual = format!("sha256:{:x}", Sha256::digest(&data));
-	if actual != digest {
-		return Err(Error::CorruptCacheBlob {
-			digest: digest.to_owned(),
-		});
+/// Remove an image from the cache index (for all platforms), including the resolutions of its tag.
+/// Its blobs are only removed by the next [gc].
+///
+/// Returns the number of removed images.
+pub fn remove(cache_dir: impl AsRef<Path>, image: &Reference) -> Result<usize> {
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(0);
+	}
+	let _lock = lock_index(&cache_dir)?;
+
+	let mut index = get_index(&cache_dir)?;
+	let reference = image.whole();
+	let is_other =
+		|key: &String| key.rsplit_once(' ').map_or(key.as_str(), |(r, _)| r) != reference;
+	let (images, tags) = (index.images.len(), index.tags.len());
+	index.images.retain(|key, _| is_other(key));
+	index.tags.retain(|key, _| is_other(key));
+	let removed = images - index.images.len();
+
+	if removed > 0 || tags != index.tags.len() {
+		write_index(&cache_dir, &index)?;
+	}
+	Ok(removed)
+}
+
+/// Check all blobs in the cache against their digests.
+///
+/// Returns a [Error::CorruptCacheBlob] for every blob, which does not match its digest.
+pub fn verify(cache_dir: impl AsRef<Path>) -> Result<Vec<Error>> {
+	let mut corrupt = Vec::new();
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(corrupt);
 	}
+	let _lock = lock_blobs_shared(&cache_dir)?;
 
-	Ok(data)
+	for blob in list_blobs(cache_dir.as_ref().join(BLOBS_DIR))? {
+		let Some(digest) = blob.digest else {
+			continue;
+		};
+		let matches = match digest.parse::<Digest>() {
+			Ok(parsed) => digest_file(&blob.path, parsed.algorithm())? == digest,
+			Err(_) => false,
+		};
+		if !matches {
+			corrupt.push(Error::CorruptCacheBlob { digest });
+		}
+	}
+	Ok(corrupt)
 }
 
-fn blob_path(blob_dir: impl AsRef<Path>, digest: &str, colon: usize) -> PathBuf {
-	// TODO: check that `rest.len() >= 4`
-	let rest = &digest[colon + 1..];
-	blob_dir
-		.as_ref()
-		.join(&digest[..colon])
-		.join(&rest[..2])
-		.join(&rest[2..4])
+/// Remove all blobs not referenced by any image in the cache index,
+/// as well as temporary files left behind by interrupted downloads.
+pub fn gc(cache_dir: impl AsRef<Path>) -> Result<GcResult> {
+	let mut result = GcResult::default();
+	if !cache_dir.as_ref().join(INDEX_FILE).is_file() {
+		return Ok(result);
+	}
+	// Downloads only happen while holding the lock, so no blob is about to be referenced.
+	let _lock = lock_index(&cache_dir)?;
+	// Wait for images being unpacked from the cache.
+	let _blobs_lock = lock_blobs(&cache_dir)?;
+	let blob_dir = cache_dir.as_ref().join(BLOBS_DIR);
+
+	let index = get_index(&cache_dir)?;
+	let referenced: HashSet<_> = (index.images.values())
+		.flat_map(|image| image_digests(&blob_dir, image))
+		.collect();
+
+	for blob in list_blobs(&blob_dir)? {
+		let is_referenced = (blob.digest.as_ref()).is_some_and(|d| referenced.contains(d));
+		if !is_referenced {
+			fs::remove_file(&blob.path)?;
+			result.removed_blobs += 1;
+			result.freed_bytes += blob.size;
+		}
+	}
+
+	// The index is only written while holding the lock, so no temporary file is in use.
+	for entry in fs::read_dir(&cache_dir)? {
+		let entry = entry?;
+		let is_temp_file = entry
+			.file_name()
+			.to_string_lossy()
+			.starts_with(TEMP_FILE_PREFIX);
+		if is_temp_file && entry.file_type()?.is_file() {
+			result.freed_bytes += entry.metadata()?.len();
+			fs::remove_file(entry.path())?;
+			result.removed_temp_files += 1;
+		}
+	}
+	Ok(result)
+}
+
+/// Digests of all blobs belonging to an image: manifest, config and layers.
+/// If the manifest can't be read, only its own digest is returned.
+fn image_digests(blob_dir: impl AsRef<Path>, image: &IndexImage) -> Vec<String> {
+	let mut digests = vec![image.manifest_digest.clone()];
+	let manifest = read_blob(&blob_dir, &image.manifest_digest)
+		.ok()
+		.and_then(|data| serde_json::from_slice::<OciImageManifest>(&data).ok());
+	if let Some(manifest) = manifest {
+		digests.push(manifest.config.digest);
+		digests.extend(manifest.layers.into_iter().map(|layer| layer.digest));
+	}
+	digests
 }
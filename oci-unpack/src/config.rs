@@ -1,6 +1,11 @@
-use std::path::PathBuf;
+use std::{fmt, path::PathBuf, str::FromStr};
 
-use oci_client::secrets::RegistryAuth;
+use oci_client::{secrets::RegistryAuth, Reference};
+
+use crate::{
+	auth::{normalize_registry, DockerConfig},
+	Result,
+};
 
 #[derive(Clone, Debug)]
 pub struct PullConfig {
@@ -13,7 +18,26 @@ pub struct PullConfig {
 	/// If no cache is specified, the images are always fetched from the registry.
 	pub cache: Option<PathBuf>,
 
+	/// Credentials used for all registries, unless [Self::credentials] are given.
 	pub auth: RegistryAuth,
+
+	/// Credentials discovered from a docker config, selected by the registry of each image.
+	pub credentials: Option<DockerConfig>,
+
+	/// Platform to select, if a reference resolves to an image index (a multi-arch image).
+	///
+	/// Defaults to linux on the architecture we are currently running on.
+	pub platform: Platform,
+
+	/// Never contact a registry: images and tags must be found in the [Self::cache].
+	///
+	/// On a cache miss, [pull_image] and [pull_image_manifest] fail with [Error::Offline].
+	pub offline: bool,
+
+	/// Mirrors to pull images through, instead of contacting their registry directly.
+	///
+	/// Images are still identified by their original reference, e.g. in the [Self::cache].
+	pub mirrors: Vec<Mirror>,
 }
 
 impl Default for PullConfig {
@@ -21,6 +45,170 @@ impl Default for PullConfig {
 		Self {
 			cache: None,
 			auth: RegistryAuth::Anonymous,
+			credentials: None,
+			platform: Platform::current(),
+			offline: false,
+			mirrors: Vec::new(),
+		}
+	}
+}
+
+/// Configuration for unpacking images into bundles.
+#[derive(Clone, Debug)]
+pub struct UnpackConfig {
+	/// Unpack without root privileges, see [crate::rootless].
+	///
+	/// Defaults to rootless, unless we are running as root.
+	pub rootless: bool,
+}
+
+impl Default for UnpackConfig {
+	fn default() -> Self {
+		Self {
+			rootless: !crate::rootless::is_root(),
+		}
+	}
+}
+
+impl PullConfig {
+	pub(crate) fn auth_for(&self, image: &Reference) -> Result<RegistryAuth> {
+		match &self.credentials {
+			Some(credentials) => credentials.auth_for(image),
+			None => Ok(self.auth.clone()),
+		}
+	}
+
+	/// References to try pulling `image` from, in order.
+	pub(crate) fn sources(&self, image: &Reference) -> Vec<Reference> {
+		let registry = normalize_registry(image.registry());
+		let Some(rule) =
+			(self.mirrors.iter()).find(|rule| normalize_registry(&rule.registry) == registry)
+		else {
+			return vec![image.clone()];
+		};
+
+		let mut sources: Vec<_> = (rule.mirrors.iter())
+			.map(|mirror| {
+				let mut source = image.clone();
+				source.set_mirror_registry(mirror.clone());
+				source
+			})
+			.collect();
+		if rule.fallback || sources.is_empty() {
+			sources.push(image.clone());
+		}
+		sources
+	}
+}
+
+/// Mirrors of a registry.
+///
+/// Parsed from "registry=mirror[,mirror...][!]", e.g. "docker.io=mirror.example.com".
+/// A trailing "!" disables the fallback to the registry, so only the mirrors are ever contacted.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mirror {
+	/// Registry whose images are mirrored, e.g. "docker.io".
+	pub registry: String,
+	/// Hosts of the mirror registries, tried in order.
+	pub mirrors: Vec<String>,
+	/// Pull from the registry itself, if the image can't be pulled from any mirror.
+	pub fallback: bool,
+}
+
+impl FromStr for Mirror {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		let Some((registry, mirrors)) = s.split_once('=') else {
+			return Err(format!("expected 'registry=mirror', got '{s}'"));
+		};
+		let (mirrors, fallback) = match mirrors.trim_end().strip_suffix('!') {
+			Some(mirrors) => (mirrors, false),
+			None => (mirrors, true),
+		};
+		let mirrors: Vec<_> = (mirrors.split(','))
+			.map(str::trim)
+			.filter(|mirror| !mirror.is_empty())
+			.map(ToOwned::to_owned)
+			.collect();
+		if registry.is_empty() || mirrors.is_empty() {
+			return Err(format!("expected 'registry=mirror', got '{s}'"));
+		}
+		Ok(Mirror {
+			registry: registry.trim().to_owned(),
+			mirrors,
+			fallback,
+		})
+	}
+}
+
+/// Platform as described in the [image index spec], using the values of Go's `GOOS` and `GOARCH`.
+///
+/// [image index spec]: https://github.com/opencontainers/image-spec/blob/main/image-index.md
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+	pub os: String,
+	pub architecture: String,
+	/// If no variant is set, any variant of the architecture matches.
+	pub variant: Option<String>,
+}
+
+impl Platform {
+	/// Linux on the architecture we are currently running on.
+	pub fn current() -> Self {
+		let architecture = match std::env::consts::ARCH {
+			"x86_64" => "amd64",
+			"x86" => "386",
+			"aarch64" => "arm64",
+			"powerpc64" if cfg!(target_endian = "little") => "ppc64le",
+			"powerpc64" => "ppc64",
+			other => other,
+		};
+		Self {
+			os: "linux".into(),
+			architecture: architecture.into(),
+			variant: None,
+		}
+	}
+
+	pub(crate) fn matches(&self, platform: &oci_client::manifest::Platform) -> bool {
+		let variant_matches = match &self.variant {
+			Some(variant) => platform.variant.as_ref() == Some(variant),
+			None => true,
+		};
+		platform.os == self.os && platform.architecture == self.architecture && variant_matches
+	}
+}
+
+/// Parsed from "os/architecture[/variant]", e.g. "linux/arm64" or "linux/arm/v7".
+impl FromStr for Platform {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		let invalid = || format!("expected 'os/architecture[/variant]', got '{s}'");
+		let mut parts = s.split('/');
+		let (Some(os), Some(architecture)) = (parts.next(), parts.next()) else {
+			return Err(invalid());
+		};
+		let variant = parts.next();
+		if os.is_empty() || architecture.is_empty() || variant == Some("") || parts.next().is_some()
+		{
+			return Err(invalid());
+		}
+		Ok(Platform {
+			os: os.to_owned(),
+			architecture: architecture.to_owned(),
+			variant: variant.map(ToOwned::to_owned),
+		})
+	}
+}
+
+impl fmt::Display for Platform {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}/{}", self.os, self.architecture)?;
+		if let Some(variant) = &self.variant {
+			write!(f, "/{variant}")?;
 		}
+		Ok(())
 	}
 }
@@ -0,0 +1,141 @@
+//! Content digests of the form `<algorithm>:<encoded>`, as described by the [image spec].
+//!
+//! [image spec]: https://github.com/opencontainers/image-spec/blob/main/descriptor.md#digests
+
+use std::{fmt, io, str::FromStr};
+
+use sha2::{Digest as _, Sha256, Sha512};
+
+use crate::{Error, Result};
+
+/// The registered digest algorithms of the image spec.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Algorithm {
+	Sha256,
+	Sha512,
+}
+
+impl Algorithm {
+	/// All supported algorithms.  Blob directories have a subdirectory for each of them.
+	pub const ALL: [Algorithm; 2] = [Algorithm::Sha256, Algorithm::Sha512];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Algorithm::Sha256 => "sha256",
+			Algorithm::Sha512 => "sha512",
+		}
+	}
+
+	/// Length of the hex encoded hash.
+	pub fn encoded_len(self) -> usize {
+		match self {
+			Algorithm::Sha256 => 64,
+			Algorithm::Sha512 => 128,
+		}
+	}
+
+	pub fn digester(self) -> Digester {
+		match self {
+			Algorithm::Sha256 => Digester::Sha256(Sha256::new()),
+			Algorithm::Sha512 => Digester::Sha512(Sha512::new()),
+		}
+	}
+
+	/// Digest of data held in memory.
+	pub fn digest(self, data: &[u8]) -> Digest {
+		let mut digester = self.digester();
+		digester.update(data);
+		digester.finalize()
+	}
+}
+
+impl fmt::Display for Algorithm {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub struct Digest {
+	algorithm: Algorithm,
+	/// Lowercase hex encoded hash.
+	encoded: String,
+}
+
+impl Digest {
+	pub fn algorithm(&self) -> Algorithm {
+		self.algorithm
+	}
+
+	pub fn encoded(&self) -> &str {
+		&self.encoded
+	}
+
+	/// Whether `data` has this digest.
+	pub fn matches(&self, data: &[u8]) -> bool {
+		self.algorithm.digest(data) == *self
+	}
+}
+
+impl FromStr for Digest {
+	type Err = Error;
+
+	fn from_str(digest: &str) -> Result<Self> {
+		let not_supported = || Error::DigestNotSupported {
+			digest: digest.to_owned(),
+		};
+		let (algorithm, encoded) = digest.split_once(':').ok_or_else(not_supported)?;
+		let algorithm = (Algorithm::ALL.into_iter())
+			.find(|candidate| candidate.name() == algorithm)
+			.ok_or_else(not_supported)?;
+		// Encoded digests are used in paths, so they have to be strictly checked.
+		let is_hex = |b: u8| b.is_ascii_digit() || (b'a'..=b'f').contains(&b);
+		if encoded.len() != algorithm.encoded_len() || !encoded.bytes().all(is_hex) {
+			return Err(not_supported());
+		}
+		Ok(Digest {
+			algorithm,
+			encoded: encoded.to_owned(),
+		})
+	}
+}
+
+impl fmt::Display for Digest {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.algorithm, self.encoded)
+	}
+}
+
+/// Incrementally computes a [Digest], e.g. while data is written to disk.
+pub enum Digester {
+	Sha256(Sha256),
+	Sha512(Sha512),
+}
+
+impl Digester {
+	pub fn update(&mut self, data: &[u8]) {
+		match self {
+			Digester::Sha256(hasher) => hasher.update(data),
+			Digester::Sha512(hasher) => hasher.update(data),
+		}
+	}
+
+	pub fn finalize(self) -> Digest {
+		let (algorithm, encoded) = match self {
+			Digester::Sha256(hasher) => (Algorithm::Sha256, format!("{:x}", hasher.finalize())),
+			Digester::Sha512(hasher) => (Algorithm::Sha512, format!("{:x}", hasher.finalize())),
+		};
+		Digest { algorithm, encoded }
+	}
+}
+
+impl io::Write for Digester {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.update(buf);
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
@@ -1,9 +1,16 @@
-use std::path::PathBuf;
-
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
+	#[error("image {reference} is not in the cache, and pulling is disabled in offline mode")]
+	Offline { reference: String },
+
+	#[error("image {reference} is not in the cache")]
+	ImageNotCached { reference: String },
+
+	#[error("failed to get registry credentials: {0}")]
+	Credentials(String),
+
 	#[error("target directory was not empty")]
 	TargetNotEmpty,
 
@@ -16,6 +23,24 @@ pub enum Error {
 	#[error("feature not supported: {0}")]
 	UnsupportedFeature(String),
 
+	#[error("no image named {reference}, available images: {available}")]
+	ImageNotFound {
+		reference: String,
+		available: String,
+	},
+
+	#[error("image reference {reference} is ambiguous, candidates: {candidates}")]
+	AmbiguousImage {
+		reference: String,
+		candidates: String,
+	},
+
+	#[error("image index contains no manifest for platform {platform}")]
+	PlatformNotFound { platform: String },
+
+	#[error("unsafe layer entry '{path}': {reason}")]
+	UnsafeLayerEntry { path: String, reason: String },
+
 	#[error("layer tar diff_id mismatch")]
 	LayerDiffIdMismatch,
 
@@ -37,12 +62,12 @@ pub enum Error {
 	#[error("config: unsupported rootfs.type: {typ}")]
 	UnsupportedRootFSType { typ: String },
 
-	#[error("failed to obtain image cache lock: '{0}'")]
-	CacheLockTimeout(PathBuf),
-
 	#[error("the following digest is not (yet) supported: {digest}")]
 	DigestNotSupported { digest: String },
 
 	#[error("cache blob data did not match digest: {digest}")]
 	CorruptCacheBlob { digest: String },
+
+	#[error("downloaded blob data did not match digest: {digest}")]
+	DownloadDigestMismatch { digest: String },
 }
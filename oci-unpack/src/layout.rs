@@ -0,0 +1,549 @@
+//! Moving images without a registry: import from and export to the local filesystem.
+//!
+//! Images are imported into a cache directory (see [crate::cache]) from [OCI image layouts]
+//! or from tarballs written by `docker save`.  Cached images can be exported into an OCI image layout.
+//! Once imported, images are pulled from the cache like any other cached image, even in offline mode.
+//!
+//! [OCI image layouts]: https://github.com/opencontainers/image-spec/blob/main/image-layout.md
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::Write,
+	path::{Component, Path, PathBuf},
+};
+
+use oci_client::{
+	manifest::{
+		ImageIndexEntry, OciDescriptor, OciImageIndex, OciImageManifest,
+		IMAGE_DOCKER_CONFIG_MEDIA_TYPE, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+		IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
+		IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+	},
+	Reference,
+};
+use oci_spec::image::ImageConfiguration;
+use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+
+use crate::{
+	blobs::{blob_path, digest_file, import_blob, persist, read_blob, temp_file_for, write_blob},
+	cache, check_layer_media_types,
+	digest::{Algorithm, Digest},
+	select_platform, Error, Platform, Result, MANIFEST_MEDIA_TYPES,
+};
+
+const OCI_LAYOUT_FILE: &str = "oci-layout";
+const OCI_LAYOUT_VERSION: &str = "1.0.0";
+const INDEX_FILE: &str = "index.json";
+const BLOBS_DIR: &str = "blobs";
+/// Manifest of tarballs written by `docker save`.
+const DOCKER_MANIFEST_FILE: &str = "manifest.json";
+/// Annotation naming the reference of an image in an OCI image layout.
+const ANNOTATION_REF_NAME: &str = "org.opencontainers.image.ref.name";
+/// Annotation naming the full reference of an image, written by containerd and `docker save`,
+/// which only put the tag into [ANNOTATION_REF_NAME].
+const ANNOTATION_IMAGE_NAME: &str = "io.containerd.image.name";
+/// Annotation recording the digest of the image index an exported manifest was selected from.
+/// Only the manifest is exported, so the image index itself is not part of the layout.
+const ANNOTATION_INDEX_DIGEST: &str = "io.warpforge.image.index.digest";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct OciLayout {
+	#[serde(rename = "imageLayoutVersion")]
+	image_layout_version: String,
+}
+
+/// An image in the `manifest.json` of a `docker save` tarball.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct DockerArchiveImage {
+	config: String,
+	#[serde(default)]
+	repo_tags: Option<Vec<String>>,
+	layers: Vec<String>,
+}
+
+/// Import the image `image` for `platform` from an OCI image layout into the cache.
+///
+/// The image is selected by the name it is annotated with, preferring full references over tags.
+/// Only a layout holding a single unnamed image can be imported under any name.
+/// Returns the digest of the imported manifest.
+pub fn import_oci_layout(
+	layout_dir: impl AsRef<Path>,
+	cache_dir: impl AsRef<Path>,
+	image: &Reference,
+	platform: &Platform,
+) -> Result<String> {
+	let layout_dir = layout_dir.as_ref();
+	let layout: OciLayout = serde_json::from_slice(&fs::read(layout_dir.join(OCI_LAYOUT_FILE))?)
+		.map_err(|err| Error::ImageInvalid(format!("invalid '{OCI_LAYOUT_FILE}' file: {err}")))?;
+	if !layout.image_layout_version.starts_with("1.") {
+		let version = layout.image_layout_version;
+		let reason = format!("OCI image layout version {version}");
+		return Err(Error::UnsupportedFeature(reason));
+	}
+	let index = read_layout_index(layout_dir.join(INDEX_FILE))?;
+
+	let candidates = layout_candidates(&index, image)?;
+
+	// Images can have entries per platform, but must not resolve to different manifests.
+	let mut found: Vec<(String, Option<String>)> = Vec::new();
+	for entry in candidates {
+		let Some(resolved) = resolve_layout_entry(layout_dir, entry, platform)? else {
+			continue;
+		};
+		if !found.iter().any(|(digest, _)| *digest == resolved.0) {
+			found.push(resolved);
+		}
+	}
+	if found.len() > 1 {
+		let candidates = (found.iter().map(|(digest, _)| digest.as_str()))
+			.collect::<Vec<_>>()
+			.join(", ");
+		let reference = image.whole();
+		return Err(Error::AmbiguousImage {
+			reference,
+			candidates,
+		});
+	}
+	let Some((manifest_digest, index_digest)) = found.pop() else {
+		let platform = platform.to_string();
+		return Err(Error::PlatformNotFound { platform });
+	};
+
+	cache::init_cache(&cache_dir)?;
+	let lock = cache::lock_index(&cache_dir)?;
+	let blob_dir = cache::blob_dir(&cache_dir);
+
+	let manifest_raw = read_verified(
+		layout_blob_path(layout_dir, &manifest_digest)?,
+		&manifest_digest,
+	)?;
+	let manifest: OciImageManifest =
+		serde_json::from_slice(&manifest_raw).map_err(Error::ParseManifest)?;
+	check_layer_media_types(&manifest)?;
+
+	for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+		let source = layout_blob_path(layout_dir, &descriptor.digest)?;
+		import_blob(&blob_dir, &descriptor.digest, source)?;
+	}
+	write_blob(&blob_dir, &manifest_digest, &manifest_raw)?;
+
+	cache::add_imported_image(
+		&cache_dir,
+		&lock,
+		image,
+		platform,
+		&manifest_digest,
+		index_digest,
+	)?;
+	Ok(manifest_digest)
+}
+
+/// Import an image from a tarball written by `docker save` into the cache.
+///
+/// The image is selected by the tags recorded in the tarball.  If no `image` reference is given,
+/// the tarball must hold a single image, which is named by its first tag.
+/// Tarballs of older docker versions don't contain an image manifest.  For them, a manifest
+/// is created during import, so its digest differs from the digest in the registry.
+///
+/// Returns the reference the image was imported as.
+pub fn import_docker_archive(
+	archive: impl AsRef<Path>,
+	cache_dir: impl AsRef<Path>,
+	image: Option<&Reference>,
+	platform: &Platform,
+) -> Result<Reference> {
+	let temp_dir = TempDir::new()?;
+	let dir = temp_dir.path();
+	tar::Archive::new(fs::File::open(archive)?).unpack(dir)?;
+	let dir = &dir.canonicalize()?;
+	check_archive_symlinks(dir, dir)?;
+
+	let docker_manifest: Vec<DockerArchiveImage> = serde_json::from_slice(&fs::read(
+		archive_path(dir, DOCKER_MANIFEST_FILE)?,
+	)?)
+	.map_err(|err| Error::ImageInvalid(format!("invalid '{DOCKER_MANIFEST_FILE}' file: {err}")))?;
+	let (archived, image) = select_archived_image(docker_manifest, image)?;
+
+	// Docker 25 and newer write an OCI image layout into the archive as well.
+	if dir.join(OCI_LAYOUT_FILE).is_file() {
+		import_oci_layout(dir, &cache_dir, &image, platform)?;
+		return Ok(image);
+	}
+
+	let config_path = archive_path(dir, &archived.config)?;
+	let config_raw = fs::read(&config_path)?;
+	let config: ImageConfiguration =
+		serde_json::from_slice(&config_raw).map_err(Error::ParseImageConfiguration)?;
+	if config.os().to_string() != platform.os
+		|| config.architecture().to_string() != platform.architecture
+	{
+		let platform = platform.to_string();
+		return Err(Error::PlatformNotFound { platform });
+	}
+
+	cache::init_cache(&cache_dir)?;
+	let lock = cache::lock_index(&cache_dir)?;
+	let blob_dir = cache::blob_dir(&cache_dir);
+
+	let config_digest = Algorithm::Sha256.digest(&config_raw).to_string();
+	write_blob(&blob_dir, &config_digest, &config_raw)?;
+
+	let mut layers = Vec::new();
+	for layer in &archived.layers {
+		let path = archive_path(dir, layer)?;
+		let digest = digest_file(&path, Algorithm::Sha256)?;
+		let media_type = match is_gzip_file(&path)? {
+			true => IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
+			false => IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
+		};
+		let size = fs::metadata(&path)?.len() as i64;
+		import_blob(&blob_dir, &digest, &path)?;
+		layers.push(OciDescriptor {
+			media_type: media_type.into(),
+			digest,
+			size,
+			..OciDescriptor::default()
+		});
+	}
+
+	let manifest = OciImageManifest {
+		schema_version: 2,
+		media_type: Some(IMAGE_MANIFEST_MEDIA_TYPE.into()),
+		config: OciDescriptor {
+			media_type: IMAGE_DOCKER_CONFIG_MEDIA_TYPE.into(),
+			digest: config_digest,
+			size: config_raw.len() as i64,
+			..OciDescriptor::default()
+		},
+		layers,
+		..OciImageManifest::default()
+	};
+	let manifest_raw = serde_json::to_vec(&manifest).map_err(Error::ParseManifest)?;
+	let manifest_digest = Algorithm::Sha256.digest(&manifest_raw).to_string();
+	write_blob(&blob_dir, &manifest_digest, &manifest_raw)?;
+
+	cache::add_imported_image(&cache_dir, &lock, &image, platform, &manifest_digest, None)?;
+	Ok(image)
+}
+
+/// Export a cached image into an OCI image layout.
+///
+/// The layout is created if it does not exist yet.  Otherwise the image is added to it,
+/// replacing an image of the same name and platform.  Only the manifest for `platform` is exported:
+/// the digest of the image index it was selected from is kept in an annotation, and restored on import.
+pub fn export_oci_layout(
+	cache_dir: impl AsRef<Path>,
+	image: &Reference,
+	platform: &Platform,
+	layout_dir: impl AsRef<Path>,
+) -> Result<()> {
+	let not_cached = || Error::ImageNotCached {
+		reference: image.whole(),
+	};
+	if !cache_dir.as_ref().is_dir() {
+		return Err(not_cached());
+	}
+	// Blobs must not be removed between looking up the image and copying them.
+	let _lock = cache::lock_blobs_shared(&cache_dir)?;
+	let Some((manifest_digest, index_digest)) =
+		cache::find_manifest_digest(&cache_dir, image, platform)?
+	else {
+		return Err(not_cached());
+	};
+	let blob_dir = cache::blob_dir(&cache_dir);
+
+	let manifest_raw = read_blob(&blob_dir, &manifest_digest)?;
+	let manifest: OciImageManifest =
+		serde_json::from_slice(&manifest_raw).map_err(Error::ParseManifest)?;
+
+	let layout_dir = layout_dir.as_ref();
+	fs::create_dir_all(layout_dir.join(BLOBS_DIR))?;
+	let layout = OciLayout {
+		image_layout_version: OCI_LAYOUT_VERSION.into(),
+	};
+	fs::write(
+		layout_dir.join(OCI_LAYOUT_FILE),
+		serde_json::to_vec(&layout).unwrap(),
+	)?;
+
+	for descriptor in std::iter::once(&manifest.config).chain(&manifest.layers) {
+		let target = layout_blob_path(layout_dir, &descriptor.digest)?;
+		if !target.exists() {
+			fs::create_dir_all(target.parent().expect("blob paths have a parent"))?;
+			fs::copy(blob_path(&blob_dir, &descriptor.digest)?, target)?;
+		}
+	}
+	let target = layout_blob_path(layout_dir, &manifest_digest)?;
+	fs::create_dir_all(target.parent().expect("blob paths have a parent"))?;
+	fs::write(target, &manifest_raw)?;
+
+	let index_path = layout_dir.join(INDEX_FILE);
+	let mut index = match index_path.is_file() {
+		true => read_layout_index(&index_path)?,
+		false => OciImageIndex {
+			schema_version: 2,
+			media_type: Some(OCI_IMAGE_INDEX_MEDIA_TYPE.into()),
+			manifests: Vec::new(),
+			annotations: None,
+		},
+	};
+	let name = image.whole();
+	let entry_platform = oci_client::manifest::Platform {
+		architecture: platform.architecture.clone(),
+		os: platform.os.clone(),
+		os_version: None,
+		os_features: None,
+		variant: platform.variant.clone(),
+		features: None,
+	};
+	index.manifests.retain(|entry| {
+		let same_platform = (entry.platform.as_ref()).is_none_or(|p| platform.matches(p));
+		ref_name(entry) != Some(&name) || !same_platform
+	});
+	let mut annotations = BTreeMap::from([(ANNOTATION_REF_NAME.into(), name)]);
+	if let Some(index_digest) = index_digest {
+		annotations.insert(ANNOTATION_INDEX_DIGEST.into(), index_digest);
+	}
+	index.manifests.push(ImageIndexEntry {
+		media_type: (manifest.media_type.clone()).unwrap_or_else(|| OCI_IMAGE_MEDIA_TYPE.into()),
+		digest: manifest_digest,
+		size: manifest_raw.len() as i64,
+		platform: Some(entry_platform),
+		annotations: Some(annotations),
+	});
+	let mut file = temp_file_for(&index_path)?;
+	file.write_all(&serde_json::to_vec(&index).unwrap())?;
+	persist(file, &index_path)?;
+
+	Ok(())
+}
+
+fn read_layout_index(path: impl AsRef<Path>) -> Result<OciImageIndex> {
+	serde_json::from_slice(&fs::read(path)?)
+		.map_err(|err| Error::ImageInvalid(format!("invalid '{INDEX_FILE}' file: {err}")))
+}
+
+/// Resolve an entry of a layout's index to the digests of the manifest for `platform`
+/// and of the image index it was selected from.
+fn resolve_layout_entry(
+	layout_dir: &Path,
+	entry: &ImageIndexEntry,
+	platform: &Platform,
+) -> Result<Option<(String, Option<String>)>> {
+	let media_type = entry.media_type.as_str();
+	if MANIFEST_MEDIA_TYPES.contains(&media_type) {
+		// Layouts often hold a single image, without recording its platform.
+		let matches = (entry.platform.as_ref()).is_none_or(|p| platform.matches(p));
+		let index_digest = annotation(entry, ANNOTATION_INDEX_DIGEST).cloned();
+		return Ok(matches.then(|| (entry.digest.clone(), index_digest)));
+	}
+	if media_type == OCI_IMAGE_INDEX_MEDIA_TYPE || media_type == IMAGE_MANIFEST_LIST_MEDIA_TYPE {
+		let path = layout_blob_path(layout_dir, &entry.digest)?;
+		let nested: OciImageIndex = serde_json::from_slice(&read_verified(path, &entry.digest)?)
+			.map_err(Error::ParseManifest)?;
+		let selected = select_platform(&nested.manifests, platform);
+		return Ok(selected.map(|digest| (digest, Some(entry.digest.clone()))));
+	}
+	Ok(None)
+}
+
+fn ref_name(entry: &ImageIndexEntry) -> Option<&String> {
+	annotation(entry, ANNOTATION_REF_NAME)
+}
+
+fn annotation<'a>(entry: &'a ImageIndexEntry, key: &str) -> Option<&'a String> {
+	(entry.annotations.as_ref()).and_then(|annotations| annotations.get(key))
+}
+
+/// Entries of a layout's index naming `image`.
+///
+/// Layouts name images by their full reference or by their tag only.
+/// Full references are preferred, so images sharing a tag can be told apart.
+fn layout_candidates<'a>(
+	index: &'a OciImageIndex,
+	image: &Reference,
+) -> Result<Vec<&'a ImageIndexEntry>> {
+	let full_name = image.whole();
+	let is_full_name = |name: &String| {
+		*name == full_name
+			|| name
+				.parse::<Reference>()
+				.is_ok_and(|r| r.whole() == full_name)
+	};
+	let by_full_name: Vec<_> = (index.manifests.iter())
+		.filter(|entry| {
+			ref_name(entry).is_some_and(is_full_name)
+				|| annotation(entry, ANNOTATION_IMAGE_NAME).is_some_and(is_full_name)
+		})
+		.collect();
+	if !by_full_name.is_empty() {
+		return Ok(by_full_name);
+	}
+	let by_tag: Vec<_> = (index.manifests.iter())
+		.filter(|entry| ref_name(entry).is_some_and(|name| image.tag() == Some(name)))
+		.collect();
+	if !by_tag.is_empty() {
+		return Ok(by_tag);
+	}
+
+	if let [entry] = &index.manifests[..] {
+		if ref_name(entry).is_none() && annotation(entry, ANNOTATION_IMAGE_NAME).is_none() {
+			return Ok(vec![entry]);
+		}
+	}
+	let names: Vec<_> = (index.manifests.iter())
+		.filter_map(|entry| annotation(entry, ANNOTATION_IMAGE_NAME).or(ref_name(entry)))
+		.map(String::as_str)
+		.collect();
+	Err(Error::ImageNotFound {
+		reference: full_name,
+		available: available(names),
+	})
+}
+
+/// Select the image of a `docker save` tarball named `image` by its tags,
+/// and the reference to import it as.
+fn select_archived_image(
+	archived: Vec<DockerArchiveImage>,
+	image: Option<&Reference>,
+) -> Result<(DockerArchiveImage, Reference)> {
+	let tags = |archived: &DockerArchiveImage| -> Vec<String> {
+		archived.repo_tags.iter().flatten().cloned().collect()
+	};
+	let all_tags: Vec<_> = archived.iter().flat_map(tags).collect();
+
+	let Some(image) = image else {
+		let mut archived = archived;
+		if archived.len() > 1 {
+			let tags = available(all_tags.iter().map(String::as_str));
+			let reason = format!("archive contains several images, select one of: {tags}");
+			return Err(Error::ImageInvalid(reason));
+		}
+		let Some(archived) = archived.pop() else {
+			return Err(Error::ImageInvalid("archive contains no image".into()));
+		};
+		let tag = (tags(&archived).into_iter().next()).ok_or_else(|| {
+			Error::ImageInvalid("archive contains no tag to name the image by".into())
+		})?;
+		let image =
+			(tag.parse()).map_err(|_| Error::ImageInvalid(format!("invalid tag: {tag}")))?;
+		return Ok((archived, image));
+	};
+
+	// Tags are usually recorded in short form, e.g. "busybox:latest".
+	let names_image = |tag: &String| {
+		tag.parse::<Reference>()
+			.is_ok_and(|tagged| tagged.whole() == image.whole())
+	};
+	let untagged_single_image = archived.len() == 1 && all_tags.is_empty();
+	let mut matching: Vec<_> = (archived.into_iter())
+		.filter(|archived| untagged_single_image || tags(archived).iter().any(names_image))
+		.collect();
+	match matching.len() {
+		0 => Err(Error::ImageNotFound {
+			reference: image.whole(),
+			available: available(all_tags.iter().map(String::as_str)),
+		}),
+		1 => Ok((matching.pop().unwrap(), image.clone())),
+		_ => Err(Error::AmbiguousImage {
+			reference: image.whole(),
+			candidates: available(matching.iter().map(|archived| archived.config.as_str())),
+		}),
+	}
+}
+
+/// List of image names for errors.
+fn available<'a>(names: impl IntoIterator<Item = &'a str>) -> String {
+	let names: Vec<_> = names.into_iter().collect();
+	match names.is_empty() {
+		true => "none".into(),
+		false => names.join(", "),
+	}
+}
+
+/// Path of a blob in an OCI image layout: `blobs/<algorithm>/<encoded>`.
+fn layout_blob_path(layout_dir: &Path, digest: &str) -> Result<PathBuf> {
+	let Some((algorithm, encoded)) = digest.split_once(':') else {
+		let digest = digest.to_owned();
+		return Err(Error::DigestNotSupported { digest });
+	};
+	let is_safe = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_alphanumeric());
+	if !is_safe(algorithm) || !is_safe(encoded) {
+		let digest = digest.to_owned();
+		return Err(Error::DigestNotSupported { digest });
+	}
+	Ok(layout_dir.join(BLOBS_DIR).join(algorithm).join(encoded))
+}
+
+/// Read a small blob from outside of a blob directory and verify its digest.
+fn read_verified(path: impl AsRef<Path>, digest: &str) -> Result<Vec<u8>> {
+	let data = fs::read(path)?;
+	if !digest.parse::<Digest>()?.matches(&data) {
+		return Err(Error::ImageInvalid(format!(
+			"blob data did not match digest: {digest}"
+		)));
+	}
+	Ok(data)
+}
+
+/// Resolve a path named in a docker archive, which must stay inside of the archive.
+fn archive_path(dir: &Path, name: &str) -> Result<PathBuf> {
+	let name = Path::new(name);
+	if !(name.components()).all(|component| matches!(component, Component::Normal(_))) {
+		let reason = format!("path outside of archive: {}", name.display());
+		return Err(Error::ImageInvalid(reason));
+	}
+	let path = dir.join(name);
+	if !path.is_file() {
+		let reason = format!("missing file in archive: {}", name.display());
+		return Err(Error::ImageInvalid(reason));
+	}
+	Ok(path)
+}
+
+/// Reject symlinks of an unpacked archive, which point outside of it (e.g. to `/etc/shadow`).
+/// The files of the archive are read through them.  Symlinks inside of the archive are fine:
+/// `docker save` links layers, which several images share.
+fn check_archive_symlinks(root: &Path, dir: &Path) -> Result<()> {
+	for entry in fs::read_dir(dir)? {
+		let entry = entry?;
+		let file_type = entry.file_type()?;
+		if file_type.is_dir() {
+			check_archive_symlinks(root, &entry.path())?;
+		} else if file_type.is_symlink() {
+			let target = entry.path().canonicalize();
+			if !target.is_ok_and(|target| target.starts_with(root)) {
+				let name = entry
+					.path()
+					.strip_prefix(root)
+					.unwrap()
+					.display()
+					.to_string();
+				let reason = format!("symlink outside of archive: {name}");
+				return Err(Error::ImageInvalid(reason));
+			}
+		} else if !file_type.is_file() {
+			let name = entry
+				.path()
+				.strip_prefix(root)
+				.unwrap()
+				.display()
+				.to_string();
+			return Err(Error::ImageInvalid(format!(
+				"special file in archive: {name}"
+			)));
+		}
+	}
+	Ok(())
+}
+
+fn is_gzip_file(path: &Path) -> Result<bool> {
+	use std::io::Read;
+
+	let mut magic = [0; 2];
+	let read = fs::File::open(path)?.read(&mut magic)?;
+	Ok(read == 2 && magic == [0x1f, 0x8b])
+}
@@ -10,60 +10,95 @@
 //! [oci-client]: https://github.com/oras-project/rust-oci-client
 //! [umoci]: https://github.com/opencontainers/umoci/blob/8e665b719d0aff18dbf97a287f78faa6d0ef4f18/unpack.go
 
-mod cache;
+pub mod auth;
+mod blobs;
+pub mod cache;
 mod config;
+pub mod digest;
 mod error;
+pub mod layout;
+pub mod rootless;
+pub mod snapshot;
 pub mod tee;
 
+#[cfg(test)]
+mod tests;
+
 use std::{
-	fs::{self},
-	io::Read,
-	path::Path,
+	collections::HashSet,
+	ffi::CString,
+	fs::{self, File},
+	io::{self, BufReader, Read, Seek, SeekFrom},
+	os::unix::{ffi::OsStrExt, fs::PermissionsExt},
+	path::{Component, Path, PathBuf},
 	time::UNIX_EPOCH,
 };
 
+use crate::digest::Digest;
+use blobs::{download_blob, LayerBlob};
 use cache::Cache;
 use file_mode::ModePath;
 use filetime::set_file_times;
 use flate2::read::GzDecoder;
 use oci_client::{
-	client::{ClientConfig, ImageLayer},
+	client::ClientConfig,
 	manifest::{
-		OciImageManifest, IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
-		IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_LIST_MEDIA_TYPE,
-		IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_INDEX_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE,
+		ImageIndexEntry, OciDescriptor, OciImageManifest, OciManifest,
+		IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE, IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
+		IMAGE_LAYER_GZIP_MEDIA_TYPE, IMAGE_LAYER_MEDIA_TYPE, IMAGE_MANIFEST_MEDIA_TYPE,
+		OCI_IMAGE_MEDIA_TYPE,
 	},
+	secrets::RegistryAuth,
 	Client, Reference,
 };
-use oci_spec::image::ImageConfiguration;
-use sha2::{Digest, Sha256};
+use rootless::{Device, DeviceKind, FileAttributes, RootlessFiles};
+use tempfile::TempDir;
+use tokio::task::JoinSet;
 
-pub use crate::config::PullConfig;
+pub use crate::config::{Mirror, Platform, PullConfig, UnpackConfig};
 pub use crate::error::{Error, Result};
 use crate::tee::ReadExt;
+pub use oci_spec::image::ImageConfiguration;
 
-const MANIFEST_MEDIA_TYPES: &[&str] = &[
-	IMAGE_MANIFEST_MEDIA_TYPE,
-	IMAGE_MANIFEST_LIST_MEDIA_TYPE,
-	OCI_IMAGE_MEDIA_TYPE,
-	OCI_IMAGE_INDEX_MEDIA_TYPE,
-];
+/// Media types of image manifests, which can be selected from an image index.
+const MANIFEST_MEDIA_TYPES: &[&str] = &[IMAGE_MANIFEST_MEDIA_TYPE, OCI_IMAGE_MEDIA_TYPE];
+
+/// Media type of zstd compressed layers, which oci-client does not (yet) provide a constant for.
+const IMAGE_LAYER_ZSTD_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar+zstd";
 
-// TODO: Consider adding ZSTD support in addition to TAR and GZIP.
 const LAYER_MEDIA_TYPES: &[&str] = &[
 	IMAGE_LAYER_MEDIA_TYPE,
 	IMAGE_LAYER_GZIP_MEDIA_TYPE,
+	IMAGE_LAYER_ZSTD_MEDIA_TYPE,
 	IMAGE_DOCKER_LAYER_TAR_MEDIA_TYPE,
 	IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE,
 ];
 
+/// Prefix of whiteout files, which mark a file of a lower layer as deleted.
+const WHITEOUT_PREFIX: &str = ".wh.";
+/// Opaque whiteouts mark all contents of their directory in lower layers as deleted.
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+/// Maximum number of symlinks followed when resolving a path inside the rootfs, like Linux' `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+
 fn is_gzip(media_type: &str) -> bool {
 	media_type == IMAGE_LAYER_GZIP_MEDIA_TYPE || media_type == IMAGE_DOCKER_LAYER_GZIP_MEDIA_TYPE
 }
 
+fn is_zstd(media_type: &str) -> bool {
+	media_type == IMAGE_LAYER_ZSTD_MEDIA_TYPE
+}
+
 pub struct BundleInfo {
 	pub manifest: OciImageManifest,
+	/// Digest of the image manifest which was unpacked.
 	pub manifest_digest: String,
+	/// Digest of the image index the manifest was selected from,
+	/// if the image reference resolved to an image index (a multi-arch image).
+	pub index_digest: Option<String>,
+	/// Configuration of the image, describing how containers should run it (e.g. `Env`, `WorkingDir`, `User`).
+	pub config: ImageConfiguration,
 }
 
 pub fn pull_and_unpack(
@@ -72,25 +107,21 @@ pub fn pull_and_unpack(
 	config: &PullConfig,
 ) -> Result<BundleInfo> {
 	let image_data = pull_image(image, config)?;
-
-	let manifest = image_data.manifest.unwrap();
-	let manifest_digest = image_data.digest.unwrap();
-	let config: ImageConfiguration =
-		serde_json::from_slice(&image_data.config.data).map_err(Error::ParseImageConfiguration)?;
-
-	let image_data = ImageData {
-		manifest,
-		manifest_digest,
-		layers: image_data.layers,
-		config,
-	};
-
 	unpack(target, image_data)
 }
 
+/// Unpack an image with the default [UnpackConfig]: rootless, unless running as root.
 pub fn unpack(
 	target: impl AsRef<Path>,
 	image_data: ImageData,
+) -> std::result::Result<BundleInfo, Error> {
+	unpack_with(target, image_data, &UnpackConfig::default())
+}
+
+pub fn unpack_with(
+	target: impl AsRef<Path>,
+	image_data: ImageData,
+	config: &UnpackConfig,
 ) -> std::result::Result<BundleInfo, Error> {
 	fs::create_dir_all(&target)?;
 	let is_empty = target.as_ref().read_dir()?.next().is_none();
@@ -107,7 +138,6 @@ pub fn unpack(
 
 	let rootfs_dir = target.as_ref().join("rootfs");
 	fs::create_dir(&rootfs_dir)?;
-	// TODO: root UID and root GID mapping.
 
 	// From opencontainers/umoci:
 	// "Currently, many different images in the wild don't specify what the
@@ -129,8 +159,12 @@ pub fn unpack(
 		return Err(Error::ImageInvalid(reason));
 	}
 
+	let mut rootless_files = config.rootless.then(RootlessFiles::new);
 	for (layer, diff_id) in image_data.layers.iter().zip(diff_ids) {
-		unpack_layer(layer, diff_id, &rootfs_dir)?;
+		unpack_layer(layer, diff_id, &rootfs_dir, rootless_files.as_mut())?;
+	}
+	if let Some(files) = rootless_files {
+		rootless::write(target.as_ref(), &rootfs_dir, files)?;
 	}
 
 	// TODO: Should we unpack a config.json here or do we create
@@ -139,75 +173,307 @@ pub fn unpack(
 	Ok(BundleInfo {
 		manifest: image_data.manifest,
 		manifest_digest: image_data.manifest_digest,
+		index_digest: image_data.index_digest,
+		config: image_data.config,
 	})
 }
 
 pub struct ImageData {
 	manifest: OciImageManifest,
 	manifest_digest: String,
-	layers: Vec<ImageLayer>,
+	index_digest: Option<String>,
+	layers: Vec<LayerBlob>,
 	config: ImageConfiguration,
+	/// Blobs are downloaded into a temporary directory, if no cache is used.
+	/// It is removed when the image data is dropped.
+	_temp_blob_dir: Option<TempDir>,
+	/// Lock keeping the blobs in the cache from being removed, until the image data is dropped.
+	_cache_lock: Option<cache::IndexLock>,
+}
+
+/// Image manifest resolved for the platform requested in the [PullConfig].
+pub(crate) struct ResolvedManifest {
+	manifest_digest: String,
+	index_digest: Option<String>,
 }
 
 #[tokio::main]
-pub async fn pull_image(
-	image: &Reference,
-	config: &PullConfig,
-) -> Result<oci_client::client::ImageData> {
+pub async fn pull_image(image: &Reference, config: &PullConfig) -> Result<ImageData> {
 	let mut cache = Cache::new(config);
 	if let Some(image_data) = cache.before_pull(image)? {
 		return Ok(image_data);
 	}
+	if config.offline {
+		let reference = image.whole();
+		return Err(Error::Offline { reference });
+	}
+
+	let (blob_dir, temp_blob_dir) = match cache.blob_dir() {
+		Some(blob_dir) => (blob_dir, None),
+		None => {
+			let temp_dir = TempDir::new()?;
+			(temp_dir.path().to_owned(), Some(temp_dir))
+		}
+	};
 
 	let client = Client::new(ClientConfig::default());
-	let media_types = LAYER_MEDIA_TYPES.to_vec();
+	let mut pulled = None;
+	for source in config.sources(image) {
+		// Try the next mirror on failure, but report the error of the last source tried.
+		pulled = Some(pull_from(&client, &source, config, &blob_dir).await);
+		if let Some(Ok(_)) = pulled {
+			break;
+		}
+	}
+	let pulled = pulled.expect("images have at least one source")?;
+
+	// The cache records the image by its original reference, regardless of the mirror used.
+	cache.after_resolve(image, &pulled.resolved)?;
+	let index_digest = pulled.resolved.index_digest;
+	let manifest_digest = pulled.resolved.manifest_digest;
+	cache.after_pull(
+		image,
+		&manifest_digest,
+		&pulled.manifest_raw,
+		index_digest.clone(),
+	)?;
+
+	Ok(ImageData {
+		manifest: pulled.manifest,
+		manifest_digest,
+		index_digest,
+		layers: pulled.layers,
+		config: pulled.config,
+		_temp_blob_dir: temp_blob_dir,
+		_cache_lock: cache.lock_blobs()?,
+	})
+}
 
-	let image_data = client.pull(image, &config.auth, media_types).await?;
+/// An image downloaded into a blob directory.
+struct PulledImage {
+	resolved: ResolvedManifest,
+	manifest_raw: Vec<u8>,
+	manifest: OciImageManifest,
+	config: ImageConfiguration,
+	layers: Vec<LayerBlob>,
+}
 
-	cache
-		.after_pull(image, &image_data, &client, config)
+/// Pull an image from a single registry (or mirror).
+async fn pull_from(
+	client: &Client,
+	source: &Reference,
+	config: &PullConfig,
+	blob_dir: &Path,
+) -> Result<PulledImage> {
+	let auth = config.auth_for(source)?;
+
+	// Pull by manifest digest, so the client never has to choose a platform itself.
+	let resolved = resolve_manifest(client, source, &auth, config).await?;
+	let reference = source.clone_with_digest(resolved.manifest_digest.clone());
+	let (manifest_raw, _) = client
+		.pull_manifest_raw(&reference, &auth, MANIFEST_MEDIA_TYPES)
 		.await?;
+	// Mirrors are not trusted to serve the manifest they resolved the reference to.
+	let expected: Digest = resolved.manifest_digest.parse()?;
+	if !expected.matches(&manifest_raw) {
+		let digest = resolved.manifest_digest;
+		return Err(Error::DownloadDigestMismatch { digest });
+	}
+	let manifest: OciImageManifest =
+		serde_json::from_slice(&manifest_raw).map_err(Error::ParseManifest)?;
+
+	check_layer_media_types(&manifest)?;
+
+	let config_path = download_blob(client, &reference, &manifest.config, blob_dir).await?;
+	let image_config =
+		serde_json::from_slice(&fs::read(config_path)?).map_err(Error::ParseImageConfiguration)?;
+	let layers = download_layers(client, &reference, &manifest.layers, blob_dir).await?;
+
+	Ok(PulledImage {
+		resolved,
+		manifest_raw,
+		manifest,
+		config: image_config,
+		layers,
+	})
+}
+
+fn check_layer_media_types(manifest: &OciImageManifest) -> Result<()> {
+	for layer in &manifest.layers {
+		if !LAYER_MEDIA_TYPES.contains(&layer.media_type.as_str()) {
+			let reason = format!("layer media type: {}", layer.media_type);
+			return Err(Error::UnsupportedFeature(reason));
+		}
+	}
+	Ok(())
+}
+
+/// Download all layers in parallel, streaming them into `blob_dir`.
+async fn download_layers(
+	client: &Client,
+	image: &Reference,
+	layers: &[OciDescriptor],
+	blob_dir: &Path,
+) -> Result<Vec<LayerBlob>> {
+	let mut downloads = JoinSet::new();
+	for (i, layer) in layers.iter().enumerate() {
+		let (client, image, blob_dir) = (client.clone(), image.clone(), blob_dir.to_owned());
+		let layer = layer.clone();
+		downloads.spawn(async move {
+			let path = download_blob(&client, &image, &layer, &blob_dir).await?;
+			Ok::<_, Error>((i, path))
+		});
+	}
+
+	let mut paths = vec![PathBuf::new(); layers.len()];
+	while let Some(download) = downloads.join_next().await {
+		let (i, path) = download.map_err(io::Error::from)??;
+		paths[i] = path;
+	}
 
-	Ok(image_data)
+	Ok((layers.iter().zip(paths))
+		.map(|(layer, path)| LayerBlob {
+			media_type: layer.media_type.clone(),
+			digest: layer.digest.clone(),
+			path,
+		})
+		.collect())
 }
 
+/// Resolve the digest of the image manifest, which [pull_image] would unpack.
+/// If `image` references an image index, this is the digest of the manifest selected for
+/// the platform in `config`, not the digest of the index.
 #[tokio::main]
 pub async fn pull_image_manifest(image: &Reference, config: &PullConfig) -> Result<String> {
 	let mut cache = Cache::new(config);
 	if let Some(image_data) = cache.before_pull(image)? {
-		return Ok(image_data.digest.unwrap());
+		return Ok(image_data.manifest_digest);
+	}
+	if config.offline {
+		// Even if the image itself is not cached, the tag might have been resolved before.
+		return match cache.resolved_tag(image)? {
+			Some(tag) => Ok(tag.manifest_digest),
+			None => {
+				let reference = image.whole();
+				Err(Error::Offline { reference })
+			}
+		};
 	}
 
 	let client = Client::new(ClientConfig::default());
-	let manifest = client
-		.pull_manifest_raw(image, &config.auth, MANIFEST_MEDIA_TYPES)
-		.await?;
-	Ok(manifest.1)
+	let mut resolved = None;
+	for source in config.sources(image) {
+		let auth = config.auth_for(&source)?;
+		resolved = Some(resolve_manifest(&client, &source, &auth, config).await);
+		if let Some(Ok(_)) = resolved {
+			break;
+		}
+	}
+	let resolved = resolved.expect("images have at least one source")?;
+	cache.after_resolve(image, &resolved)?;
+	Ok(resolved.manifest_digest)
 }
 
-fn unpack_layer(layer: &ImageLayer, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
+async fn resolve_manifest(
+	client: &Client,
+	image: &Reference,
+	auth: &RegistryAuth,
+	config: &PullConfig,
+) -> Result<ResolvedManifest> {
+	let (manifest, digest) = client.pull_manifest(image, auth).await?;
+	let OciManifest::ImageIndex(index) = manifest else {
+		return Ok(ResolvedManifest {
+			manifest_digest: digest,
+			index_digest: None,
+		});
+	};
+
+	let Some(manifest_digest) = select_platform(&index.manifests, &config.platform) else {
+		let platform = config.platform.to_string();
+		return Err(Error::PlatformNotFound { platform });
+	};
+	Ok(ResolvedManifest {
+		manifest_digest,
+		index_digest: Some(digest),
+	})
+}
+
+/// Select the manifest for `platform` from the entries of an image index.
+fn select_platform(manifests: &[ImageIndexEntry], platform: &Platform) -> Option<String> {
+	(manifests.iter())
+		.filter(|entry| MANIFEST_MEDIA_TYPES.contains(&entry.media_type.as_str()))
+		.find(|entry| (entry.platform.as_ref()).is_some_and(|p| platform.matches(p)))
+		.map(|entry| entry.digest.clone())
+}
+
+/// Unpack a layer straight from its blob file.
+///
+/// While unpacking, the blob is hashed as well, so corrupted blobs are detected.
+/// For rootless unpacking, the attributes which can't be applied are recorded in `rootless`.
+fn unpack_layer(
+	layer: &LayerBlob,
+	diff_id: &str,
+	target: impl AsRef<Path>,
+	rootless: Option<&mut RootlessFiles>,
+) -> Result<()> {
+	// Verify the blob before unpacking anything of it, so a corrupt blob never reaches the rootfs.
+	// The same file is unpacked, even if the blob is replaced in the meantime.
+	let expected: Digest = layer.digest.parse()?;
+	let mut digester = expected.algorithm().digester();
+	let mut file = File::open(&layer.path)?;
+	io::copy(&mut file, &mut digester)?;
+	if digester.finalize() != expected {
+		let digest = layer.digest.clone();
+		return Err(Error::CorruptCacheBlob { digest });
+	}
+	file.seek(SeekFrom::Start(0))?;
+	let read = BufReader::new(file);
+
 	if is_gzip(&layer.media_type) {
-		unpack_layer_gzip(&layer.data[..], diff_id, target)
+		unpack_layer_gzip(read, diff_id, target, rootless)
+	} else if is_zstd(&layer.media_type) {
+		unpack_layer_zstd(read, diff_id, target, rootless)
 	} else {
-		unpack_layer_tar(&layer.data[..], diff_id, target)
+		unpack_layer_tar(read, diff_id, target, rootless)
 	}
 }
 
-fn unpack_layer_gzip(data: impl Read, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
+fn unpack_layer_gzip(
+	data: impl Read,
+	diff_id: &str,
+	target: impl AsRef<Path>,
+	rootless: Option<&mut RootlessFiles>,
+) -> Result<()> {
 	let decoder = GzDecoder::new(data);
-	unpack_layer_tar(decoder, diff_id, target)
+	unpack_layer_tar(decoder, diff_id, target, rootless)
 }
 
-fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) -> Result<()> {
-	if !diff_id.starts_with("sha256:") {
+fn unpack_layer_zstd(
+	data: impl Read,
+	diff_id: &str,
+	target: impl AsRef<Path>,
+	rootless: Option<&mut RootlessFiles>,
+) -> Result<()> {
+	// The diff_id is the digest of the uncompressed tar, which [unpack_layer_tar] computes.
+	let decoder = zstd::Decoder::new(data)?;
+	unpack_layer_tar(decoder, diff_id, target, rootless)
+}
+
+fn unpack_layer_tar(
+	data: impl Read,
+	diff_id: &str,
+	target: impl AsRef<Path>,
+	rootless: Option<&mut RootlessFiles>,
+) -> Result<()> {
+	let Ok(diff_id) = diff_id.parse::<Digest>() else {
 		let algorithm = diff_id.split(':').next().unwrap_or("none");
 		let reason = format!("unsupported digest algorithm: {0}", algorithm);
 		return Err(Error::UnsupportedFeature(reason));
-	}
-	let mut digester = Sha256::new();
+	};
+	let mut digester = diff_id.algorithm().digester();
 
 	let mut read = data.tee(&mut digester);
-	tar::Archive::new(&mut read).unpack(&target)?;
+	unpack_entries(tar::Archive::new(&mut read), target.as_ref(), rootless)?;
 
 	// From opencontainers/umoci:
 	// "Different tar implementations can have different levels of redundant
@@ -217,9 +483,7 @@ fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) ->
 	let mut buffer = Vec::new();
 	let length = read.read_to_end(&mut buffer)?;
 
-	let diff_id = &diff_id["sha256:".len()..];
-	let computed = format!("{:x}", digester.finalize());
-	if diff_id != computed {
+	if digester.finalize() != diff_id {
 		if length > 0 {
 			eprintln!("oci-unpack: ignored {length} trailing 'junk' bytes in the tar stream -- probably from GNU tar")
 		}
@@ -228,3 +492,292 @@ fn unpack_layer_tar(data: impl Read, diff_id: &str, target: impl AsRef<Path>) ->
 
 	Ok(())
 }
+
+/// Unpack the entries of a layer on top of the layers already unpacked into `target`.
+///
+/// Whiteouts are applied and not unpacked themselves, as described in the [image spec].
+/// Similar to [tar::Archive::unpack], directory entries are delayed until the end,
+/// so that their permissions do not interfere with unpacking their descendants.
+///
+/// With root privileges, ownership, setuid bits and device nodes are unpacked as they are.
+/// Otherwise, they are recorded in `rootless` instead.
+///
+/// Layers are untrusted: entries with absolute or ".." paths are rejected, and symlinks
+/// (also those of lower layers) are resolved as if the rootfs was the root directory,
+/// so neither entries, hardlinks nor whiteouts can reach outside of the rootfs.
+///
+/// [image spec]: https://github.com/opencontainers/image-spec/blob/main/layer.md#whiteouts
+fn unpack_entries<R: Read>(
+	mut archive: tar::Archive<R>,
+	target: &Path,
+	mut rootless: Option<&mut RootlessFiles>,
+) -> Result<()> {
+	let target = &target.canonicalize()?;
+	archive.set_preserve_permissions(rootless.is_none());
+	archive.set_preserve_ownerships(rootless.is_none());
+
+	// Paths unpacked from this layer.  Whiteouts only apply to lower layers.
+	let mut upper_paths = HashSet::new();
+	let mut directories = Vec::new();
+	for entry in archive.entries()? {
+		let mut entry = entry?;
+		let path = rootfs_path(&entry.path()?)?;
+		let name = path.file_name().map(|name| name.to_string_lossy());
+		// The parent directory may be a symlink, but the entry itself replaces symlinks.
+		let parent = resolve_in_rootfs(target, path.parent().unwrap_or(Path::new("")))?;
+
+		if name.as_deref() == Some(WHITEOUT_OPAQUE) {
+			remove_lower_children(&parent, &upper_paths)?;
+			continue;
+		}
+		if let Some(whiteout) = name
+			.as_deref()
+			.and_then(|n| n.strip_prefix(WHITEOUT_PREFIX))
+		{
+			if matches!(whiteout, "" | "." | "..") {
+				return Err(unsafe_entry(&path, "invalid whiteout".into()));
+			}
+			// Like opaque whiteouts, these only apply to lower layers: entries of this layer are kept.
+			let whiteout_path = parent.join(whiteout);
+			if !upper_paths.contains(&whiteout_path) {
+				remove_path(&whiteout_path)?;
+			} else if (whiteout_path.symlink_metadata()).is_ok_and(|meta| meta.is_dir()) {
+				remove_lower_children(&whiteout_path, &upper_paths)?;
+			}
+			continue;
+		}
+
+		// Like opencontainers/umoci, replace what lower layers left behind,
+		// unless both are directories: those are merged.
+		let host_path = match path.file_name() {
+			Some(name) => parent.join(name),
+			None => target.clone(),
+		};
+		let is_dir = entry.header().entry_type() == tar::EntryType::Directory;
+		if let Ok(meta) = host_path.symlink_metadata() {
+			if !(is_dir && meta.is_dir()) && host_path != *target {
+				remove_path(&host_path)?;
+			}
+		}
+		upper_paths.insert(host_path.clone());
+
+		let attributes = file_attributes(entry.header())?;
+		if let Some(files) = rootless.as_deref_mut() {
+			let rootfs_path = Path::new("/").join(host_path.strip_prefix(target).unwrap());
+			files.remove(&rootfs_path);
+			if attributes.needs_record() {
+				files.insert(rootfs_path, attributes.clone());
+			}
+		}
+
+		fs::create_dir_all(&parent)?;
+		let entry_type = entry.header().entry_type();
+		let is_device = attributes.device.is_some();
+		if is_dir {
+			directories.push((path, host_path, entry));
+		} else if entry_type == tar::EntryType::Link {
+			unpack_hardlink(target, &path, &host_path, &entry)?;
+		} else if entry_type == tar::EntryType::Fifo || (is_device && rootless.is_none()) {
+			unpack_node(&host_path, &attributes, rootless.is_none())?;
+		} else {
+			// Without privileges, device nodes are unpacked as empty files.
+			entry.unpack(&host_path)?;
+		}
+	}
+
+	directories.sort_by(|(_, a, _), (_, b, _)| b.cmp(a));
+	for (path, host_path, mut dir) in directories {
+		// Later entries may have replaced the directory or one of its parents by a symlink,
+		// which tar would follow when creating the directory and applying its attributes.
+		let replaced = (host_path.symlink_metadata()).is_ok_and(|meta| !meta.is_dir());
+		if replaced || resolve_in_rootfs(target, &path)? != host_path {
+			let reason = "directory replaced by a later entry".into();
+			return Err(unsafe_entry(&path, reason));
+		}
+		dir.unpack(&host_path)?;
+	}
+
+	Ok(())
+}
+
+fn file_attributes(header: &tar::Header) -> Result<FileAttributes> {
+	let kind = match header.entry_type() {
+		tar::EntryType::Char => Some(DeviceKind::Char),
+		tar::EntryType::Block => Some(DeviceKind::Block),
+		_ => None,
+	};
+	let device = match kind {
+		Some(kind) => Some(Device {
+			kind,
+			major: header.device_major()?.unwrap_or(0),
+			minor: header.device_minor()?.unwrap_or(0),
+		}),
+		None => None,
+	};
+	Ok(FileAttributes {
+		uid: header.uid()?,
+		gid: header.gid()?,
+		mode: header.mode()? & 0o7777,
+		device,
+	})
+}
+
+/// Link to a file inside the rootfs.  [tar::Entry::unpack] would take the link name as host path.
+fn unpack_hardlink<R: Read>(
+	target: &Path,
+	path: &Path,
+	host_path: &Path,
+	entry: &tar::Entry<R>,
+) -> Result<()> {
+	let Some(link_name) = entry.link_name()? else {
+		let reason = "hardlink without link name".into();
+		return Err(unsafe_entry(path, reason));
+	};
+	let link_name = rootfs_path(&link_name)?;
+	let Some(name) = link_name.file_name() else {
+		let reason = "hardlink to the rootfs directory".into();
+		return Err(unsafe_entry(path, reason));
+	};
+	// Like the entry itself, the link target is not followed if it is a symlink.
+	let source = resolve_in_rootfs(target, link_name.parent().unwrap_or(Path::new("")))?;
+	fs::hard_link(source.join(name), host_path)?;
+	Ok(())
+}
+
+/// Create fifos and device nodes, which [tar::Entry::unpack] would unpack as regular files.
+fn unpack_node(host_path: &Path, attributes: &FileAttributes, privileged: bool) -> Result<()> {
+	let (kind, device) = match attributes.device {
+		Some(Device { kind, major, minor }) => {
+			let kind = match kind {
+				DeviceKind::Char => libc::S_IFCHR,
+				DeviceKind::Block => libc::S_IFBLK,
+			};
+			(kind, libc::makedev(major, minor))
+		}
+		None => (libc::S_IFIFO, 0),
+	};
+	let c_path = CString::new(host_path.as_os_str().as_bytes())
+		.map_err(|_| Error::ImageInvalid("layer entry path contains NUL".into()))?;
+	// SAFETY: c_path is a valid NUL terminated string.
+	if unsafe { libc::mknod(c_path.as_ptr(), kind | 0o600, device) } != 0 {
+		return Err(io::Error::last_os_error().into());
+	}
+
+	if privileged {
+		std::os::unix::fs::lchown(
+			host_path,
+			Some(attributes.uid as u32),
+			Some(attributes.gid as u32),
+		)?;
+	}
+	let mode = if privileged {
+		attributes.mode
+	} else {
+		attributes.mode & 0o777
+	};
+	fs::set_permissions(host_path, fs::Permissions::from_mode(mode))?;
+	Ok(())
+}
+
+/// Path of a layer entry relative to the rootfs.
+/// Entries must be relative paths, which don't point outside of the rootfs using "..".
+fn rootfs_path(path: &Path) -> Result<PathBuf> {
+	let mut result = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::Normal(part) => result.push(part),
+			Component::CurDir => {}
+			Component::RootDir | Component::Prefix(_) => {
+				return Err(unsafe_entry(path, "absolute path".into()));
+			}
+			Component::ParentDir => {
+				return Err(unsafe_entry(path, "path contains '..'".into()));
+			}
+		}
+	}
+	Ok(result)
+}
+
+/// Resolve `path` inside of `rootfs`, as if `rootfs` was the root directory:
+/// symlinks are followed, but absolute symlinks and ".." never leave `rootfs`.
+/// Components which don't exist (yet) are taken as they are.
+///
+/// Use this to read files of an unpacked image, which could otherwise point outside of it.
+pub fn resolve_in_rootfs(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+	let mut resolved = PathBuf::new();
+	let mut remaining: Vec<_> = path
+		.components()
+		.rev()
+		.map(|c| c.as_os_str().to_owned())
+		.collect();
+	let mut symlinks = 0;
+
+	while let Some(component) = remaining.pop() {
+		match Path::new(&component).components().next() {
+			Some(Component::Normal(name)) => {
+				let candidate = resolved.join(name);
+				let host_path = rootfs.join(&candidate);
+				let is_symlink = (host_path.symlink_metadata())
+					.map(|meta| meta.file_type().is_symlink())
+					.unwrap_or(false);
+				if !is_symlink {
+					resolved = candidate;
+					continue;
+				}
+
+				symlinks += 1;
+				if symlinks > MAX_SYMLINKS {
+					return Err(unsafe_entry(path, "too many levels of symlinks".into()));
+				}
+				let link = fs::read_link(&host_path)?;
+				if link.is_absolute() {
+					resolved = PathBuf::new();
+				}
+				remaining.extend(link.components().rev().map(|c| c.as_os_str().to_owned()));
+			}
+			Some(Component::ParentDir) => {
+				resolved.pop();
+			}
+			Some(Component::RootDir) => resolved = PathBuf::new(),
+			Some(Component::CurDir | Component::Prefix(_)) | None => {}
+		}
+	}
+
+	Ok(rootfs.join(resolved))
+}
+
+fn unsafe_entry(path: &Path, reason: String) -> Error {
+	let path = path.display().to_string();
+	Error::UnsafeLayerEntry { path, reason }
+}
+
+/// Remove everything inside `dir` that was not unpacked from the current layer.
+fn remove_lower_children(dir: &Path, upper_paths: &HashSet<PathBuf>) -> Result<()> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+		Err(err) => return Err(err.into()),
+	};
+	for entry in entries {
+		let entry = entry?;
+		let path = entry.path();
+		if !upper_paths.contains(&path) {
+			remove_path(&path)?;
+		} else if entry.file_type()?.is_dir() {
+			remove_lower_children(&path, upper_paths)?;
+		}
+	}
+	Ok(())
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+	let result = match path.symlink_metadata() {
+		Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+		Ok(_) => fs::remove_file(path),
+		Err(err) => Err(err),
+	};
+	match result {
+		Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+		_ => Ok(()),
+	}
+}
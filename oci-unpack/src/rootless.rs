@@ -0,0 +1,90 @@
+//! Unpacking without root privileges, similar to umoci's rootless mode.
+//!
+//! Without privileges, files can't be owned by other users, setuid and setgid bits are unsafe,
+//! and device nodes can't be created.  Instead, all files are owned by the unpacking user,
+//! device nodes are replaced by empty files, and what a privileged unpack would have applied
+//! is recorded in [ROOTLESS_FILE], next to the rootfs of the bundle.
+//!
+//! Applying the records needs ids beyond the unpacking user's own, like the subordinate ids of `newuidmap`.
+//! The warpforge executors apply them in a user namespace with those ids.  Without such ids,
+//! a rootless container sees all files as owned by a single user.
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+/// Name of the file in the bundle directory, which records the attributes a rootless unpack couldn't apply.
+pub const ROOTLESS_FILE: &str = "rootless.json";
+
+/// Attributes of a file, as they would be after unpacking with root privileges.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct FileAttributes {
+	pub uid: u64,
+	pub gid: u64,
+	/// Permission bits, including setuid, setgid and sticky bits.
+	pub mode: u32,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub device: Option<Device>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Device {
+	pub kind: DeviceKind,
+	pub major: u32,
+	pub minor: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+	Char,
+	Block,
+}
+
+impl FileAttributes {
+	/// Whether a rootless unpack loses any of these attributes.
+	pub(crate) fn needs_record(&self) -> bool {
+		self.uid != 0 || self.gid != 0 || self.mode & 0o7000 != 0 || self.device.is_some()
+	}
+}
+
+/// Attributes of files in the rootfs, by their absolute path inside the rootfs.
+pub type RootlessFiles = BTreeMap<PathBuf, FileAttributes>;
+
+/// Read the attributes recorded by a rootless unpack of the bundle at `bundle_dir`.
+/// Bundles which were unpacked with root privileges have no records.
+pub fn read(bundle_dir: impl AsRef<Path>) -> Result<RootlessFiles> {
+	let path = bundle_dir.as_ref().join(ROOTLESS_FILE);
+	match fs::read(&path) {
+		Ok(data) => serde_json::from_slice(&data).map_err(|err| {
+			let reason = format!("invalid {ROOTLESS_FILE}: {err}");
+			Error::ImageInvalid(reason)
+		}),
+		Err(err) if err.kind() == ErrorKind::NotFound => Ok(RootlessFiles::new()),
+		Err(err) => Err(err.into()),
+	}
+}
+
+/// Write the records of a rootless unpack, leaving out files which upper layers removed.
+pub(crate) fn write(bundle_dir: &Path, rootfs: &Path, mut files: RootlessFiles) -> Result<()> {
+	files.retain(|path, _| {
+		let path = path.strip_prefix("/").unwrap_or(path);
+		rootfs.join(path).symlink_metadata().is_ok()
+	});
+	let data = serde_json::to_vec_pretty(&files).map_err(|err| Error::IO(err.into()))?;
+	fs::write(bundle_dir.join(ROOTLESS_FILE), data)?;
+	Ok(())
+}
+
+/// Whether this process can unpack with root privileges.
+pub fn is_root() -> bool {
+	// SAFETY: geteuid has no preconditions and can't fail.
+	unsafe { libc::geteuid() == 0 }
+}
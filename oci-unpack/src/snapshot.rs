@@ -0,0 +1,199 @@
+//! Unpacked images, shared by all containers running the same image.
+//!
+//! A snapshot is the unpacked root filesystem of an image, stored by its manifest digest.
+//! Snapshots are never modified after unpacking: containers use them as the read-only lower
+//! directory of an overlayfs, so repeated runs of an image don't have to unpack it again.
+//!
+//! Snapshots in use hold a shared lock on `snapshots.lock`, removing them takes an exclusive lock.
+//! Snapshots are removed by [remove], or by [gc] once their image was removed from the image cache.
+
+use std::{
+	collections::HashSet,
+	fs::{self, File},
+	io::ErrorKind,
+	path::{Path, PathBuf},
+};
+
+use oci_client::Reference;
+
+use crate::{
+	cache::{self, IndexLock},
+	digest::{Algorithm, Digest},
+	pull_image, unpack, BundleInfo, ImageData, PullConfig, Result,
+};
+
+const LOCK_FILE: &str = "snapshots.lock";
+/// Prefix of the directories images are unpacked in, before they are moved into place.
+const UNPACK_DIR_PREFIX: &str = ".unpack-";
+
+pub struct Snapshot {
+	/// Root filesystem of the image.  Must not be modified.
+	pub rootfs: PathBuf,
+	pub info: BundleInfo,
+	/// Keeps the snapshot from being removed, until dropped.
+	pub lock: SnapshotLock,
+}
+
+/// Shared lock on the snapshots, released when dropped.
+pub struct SnapshotLock {
+	_lock: IndexLock,
+}
+
+/// Pull an image and unpack it into `snapshot_dir`, unless it was unpacked before.
+pub fn pull_and_snapshot(
+	image: &Reference,
+	snapshot_dir: impl AsRef<Path>,
+	config: &PullConfig,
+) -> Result<Snapshot> {
+	let image_data = pull_image(image, config)?;
+	snapshot(snapshot_dir, image_data)
+}
+
+/// Unpack an image into `snapshot_dir`, unless it was unpacked before.
+pub fn snapshot(snapshot_dir: impl AsRef<Path>, image_data: ImageData) -> Result<Snapshot> {
+	let snapshot_dir = snapshot_dir.as_ref();
+	let path = snapshot_path(snapshot_dir, &image_data.manifest_digest)?;
+	let info = BundleInfo {
+		manifest: image_data.manifest.clone(),
+		manifest_digest: image_data.manifest_digest.clone(),
+		index_digest: image_data.index_digest.clone(),
+		config: image_data.config.clone(),
+	};
+
+	fs::create_dir_all(snapshot_dir)?;
+	let lock = SnapshotLock {
+		_lock: cache::lock_file(snapshot_dir.join(LOCK_FILE), File::lock_shared)?,
+	};
+
+	if !path.is_dir() {
+		// Unpack next to the snapshot and move it into place once complete,
+		// so a snapshot, which exists, is always complete.
+		fs::create_dir_all(path.parent().expect("snapshot paths have a parent"))?;
+		let temp_dir = tempfile::Builder::new()
+			.prefix(UNPACK_DIR_PREFIX)
+			.tempdir_in(snapshot_dir)?;
+		let bundle = temp_dir.path().join("bundle");
+		unpack(&bundle, image_data)?;
+
+		match fs::rename(&bundle, &path) {
+			Ok(()) => {}
+			// Another process unpacked the same image in the meantime.
+			Err(err)
+				if path.is_dir()
+					&& matches!(
+						err.kind(),
+						ErrorKind::AlreadyExists | ErrorKind::DirectoryNotEmpty
+					) => {}
+			Err(err) => return Err(err.into()),
+		}
+	}
+
+	Ok(Snapshot {
+		rootfs: path.join("rootfs"),
+		info,
+		lock,
+	})
+}
+
+/// Manifest digests of all images unpacked into `snapshot_dir`.
+pub fn list(snapshot_dir: impl AsRef<Path>) -> Result<Vec<String>> {
+	let snapshot_dir = snapshot_dir.as_ref();
+	let mut digests = Vec::new();
+	for algorithm in Algorithm::ALL {
+		let entries = match fs::read_dir(snapshot_dir.join(algorithm.name())) {
+			Ok(entries) => entries,
+			Err(err) if err.kind() == ErrorKind::NotFound => continue,
+			Err(err) => return Err(err.into()),
+		};
+		for entry in entries {
+			let digest = format!("{algorithm}:{}", entry?.file_name().to_string_lossy());
+			if digest.parse::<Digest>().is_ok() {
+				digests.push(digest);
+			}
+		}
+	}
+	digests.sort();
+	Ok(digests)
+}
+
+/// Remove the snapshot of the image with `manifest_digest`.
+/// Waits until no container uses a snapshot anymore.
+///
+/// Returns whether there was a snapshot to remove.
+pub fn remove(snapshot_dir: impl AsRef<Path>, manifest_digest: &str) -> Result<bool> {
+	let snapshot_dir = snapshot_dir.as_ref();
+	let path = snapshot_path(snapshot_dir, manifest_digest)?;
+	if !path.is_dir() {
+		return Ok(false);
+	}
+	let _lock = cache::lock_file(snapshot_dir.join(LOCK_FILE), File::lock)?;
+	remove_snapshot_dir(&path)?;
+	Ok(true)
+}
+
+/// Remove all snapshots of images, which are not in the image cache at `cache_dir` anymore,
+/// as well as directories left behind by interrupted unpacks.
+/// Waits until no container uses a snapshot anymore.
+///
+/// Returns the number of removed snapshots.
+pub fn gc(snapshot_dir: impl AsRef<Path>, cache_dir: impl AsRef<Path>) -> Result<usize> {
+	let snapshot_dir = snapshot_dir.as_ref();
+	if !snapshot_dir.is_dir() {
+		return Ok(0);
+	}
+	let _lock = cache::lock_file(snapshot_dir.join(LOCK_FILE), File::lock)?;
+
+	let cached: HashSet<_> = (cache::list(cache_dir)?.into_iter())
+		.map(|image| image.manifest_digest)
+		.collect();
+	let mut removed = 0;
+	for digest in list(snapshot_dir)? {
+		if !cached.contains(&digest) {
+			remove_snapshot_dir(&snapshot_path(snapshot_dir, &digest)?)?;
+			removed += 1;
+		}
+	}
+
+	// Unpacks only happen while holding a shared lock, so none of them is in progress.
+	for entry in fs::read_dir(snapshot_dir)? {
+		let entry = entry?;
+		let is_unpack_dir = (entry.file_name().to_string_lossy()).starts_with(UNPACK_DIR_PREFIX);
+		if is_unpack_dir && entry.file_type()?.is_dir() {
+			remove_snapshot_dir(&entry.path())?;
+		}
+	}
+	Ok(removed)
+}
+
+/// Remove a directory, whose contents were unpacked from an image.
+/// Images may contain directories without write permission, which have to be made writable first.
+fn remove_snapshot_dir(path: &Path) -> Result<()> {
+	if fs::remove_dir_all(path).is_ok() {
+		return Ok(());
+	}
+	make_dirs_writable(path)?;
+	fs::remove_dir_all(path)?;
+	Ok(())
+}
+
+fn make_dirs_writable(path: &Path) -> Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+
+	let metadata = fs::symlink_metadata(path)?;
+	if !metadata.is_dir() {
+		return Ok(());
+	}
+	let mut permissions = metadata.permissions();
+	permissions.set_mode(permissions.mode() | 0o700);
+	fs::set_permissions(path, permissions)?;
+	for entry in fs::read_dir(path)? {
+		make_dirs_writable(&entry?.path())?;
+	}
+	Ok(())
+}
+
+/// Snapshots are stored like blobs: `<algorithm>/<encoded>`.
+fn snapshot_path(snapshot_dir: &Path, manifest_digest: &str) -> Result<PathBuf> {
+	let digest: Digest = manifest_digest.parse()?;
+	Ok((snapshot_dir.join(digest.algorithm().name())).join(digest.encoded()))
+}
@@ -0,0 +1,1600 @@
+use std::{
+	env, fs,
+	io::{BufRead, BufReader},
+	path::Path,
+	process::{Command, Stdio},
+	time::{Duration, Instant},
+};
+
+use oci_client::{client::ImageLayer, manifest::OciImageManifest, Reference};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::{
+	auth::DockerConfig,
+	blobs::{blob_path, write_blob, LayerBlob},
+	blobs::{list_blobs, read_blob},
+	cache,
+	digest::Algorithm,
+	layout, pull_image, pull_image_manifest,
+	rootless::{self, Device, DeviceKind, FileAttributes},
+	snapshot, unpack, unpack_with, Error, ImageData, Mirror, Platform, PullConfig, UnpackConfig,
+};
+
+enum Entry {
+	Dir(&'static str),
+	File(&'static str, &'static str),
+}
+
+fn layer(entries: &[Entry]) -> Vec<u8> {
+	let mut builder = tar::Builder::new(Vec::new());
+	for entry in entries {
+		let mut header = tar::Header::new_gnu();
+		header.set_uid(0);
+		header.set_gid(0);
+		match entry {
+			Entry::Dir(path) => {
+				header.set_entry_type(tar::EntryType::Directory);
+				header.set_mode(0o755);
+				header.set_size(0);
+				builder.append_data(&mut header, path, &[][..]).unwrap();
+			}
+			Entry::File(path, content) => {
+				header.set_entry_type(tar::EntryType::Regular);
+				header.set_mode(0o644);
+				header.set_size(content.len() as u64);
+				builder
+					.append_data(&mut header, path, content.as_bytes())
+					.unwrap();
+			}
+		}
+	}
+	builder.into_inner().unwrap()
+}
+
+/// Build an image from hand-built layers, lowest layer first.
+fn image(layers: Vec<Vec<u8>>) -> ImageData {
+	let layers = (layers.into_iter())
+		.map(|layer| ImageLayer::oci_v1(layer, None))
+		.collect();
+	image_from_layers(layers, None)
+}
+
+/// Manifest digest of the images built by [image_from_layers].
+const FAKE_DIGEST: &str = "sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Build an image from layers, which might be compressed.
+/// Diff ids are computed from `uncompressed` if given, otherwise from the layer data.
+fn image_from_layers(layers: Vec<ImageLayer>, uncompressed: Option<Vec<Vec<u8>>>) -> ImageData {
+	let uncompressed =
+		uncompressed.unwrap_or_else(|| layers.iter().map(|layer| layer.data.clone()).collect());
+	let diff_ids: Vec<_> = (uncompressed.iter())
+		.map(|layer| format!("sha256:{:x}", Sha256::digest(layer)))
+		.collect();
+	let config = serde_json::from_value(json!({
+		"architecture": "amd64",
+		"os": "linux",
+		"rootfs": { "type": "layers", "diff_ids": diff_ids },
+		"history": [],
+	}))
+	.unwrap();
+
+	// Layers are unpacked from blob files, like they are after pulling.
+	let blob_dir = TempDir::new().unwrap();
+	let layers = (layers.into_iter())
+		.map(|layer| {
+			let digest = format!("sha256:{:x}", Sha256::digest(&layer.data));
+			write_blob(blob_dir.path(), &digest, &layer.data).unwrap();
+			LayerBlob {
+				media_type: layer.media_type,
+				path: blob_path(blob_dir.path(), &digest).unwrap(),
+				digest,
+			}
+		})
+		.collect();
+
+	ImageData {
+		manifest: OciImageManifest::default(),
+		manifest_digest: FAKE_DIGEST.into(),
+		index_digest: None,
+		layers,
+		config,
+		_temp_blob_dir: Some(blob_dir),
+		_cache_lock: None,
+	}
+}
+
+fn unpack_image(layers: Vec<Vec<u8>>) -> (TempDir, crate::Result<()>) {
+	let temp_dir = TempDir::new().unwrap();
+	let result = unpack(temp_dir.path().join("bundle"), image(layers)).map(|_| ());
+	(temp_dir, result)
+}
+
+fn rootfs(temp_dir: &TempDir) -> std::path::PathBuf {
+	temp_dir.path().join("bundle/rootfs")
+}
+
+fn list(dir: &Path) -> Vec<String> {
+	let mut names: Vec<_> = (fs::read_dir(dir).unwrap())
+		.map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+		.collect();
+	names.sort();
+	names
+}
+
+#[test]
+fn whiteout_removes_lower_file() {
+	let lower = layer(&[
+		Entry::Dir("a/"),
+		Entry::File("a/keep", "keep"),
+		Entry::File("a/deleted", "deleted"),
+		Entry::Dir("gone/"),
+		Entry::File("gone/file", "file"),
+	]);
+	let upper = layer(&[
+		Entry::File("a/.wh.deleted", ""),
+		Entry::File(".wh.gone", ""),
+	]);
+
+	let (temp_dir, result) = unpack_image(vec![lower, upper]);
+	result.unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	assert_eq!(list(&rootfs), vec!["a"]);
+	assert_eq!(list(&rootfs.join("a")), vec!["keep"]);
+}
+
+#[test]
+fn whiteout_keeps_entries_of_its_own_layer() {
+	let lower = layer(&[
+		Entry::File("file", "lower"),
+		Entry::Dir("dir/"),
+		Entry::File("dir/lower", "lower"),
+	]);
+	let upper = layer(&[
+		Entry::File("file", "upper"),
+		Entry::Dir("dir/"),
+		Entry::File("dir/upper", "upper"),
+		Entry::File(".wh.file", ""),
+		Entry::File(".wh.dir", ""),
+	]);
+
+	let (temp_dir, result) = unpack_image(vec![lower, upper]);
+	result.unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	assert_eq!(fs::read_to_string(rootfs.join("file")).unwrap(), "upper");
+	assert_eq!(list(&rootfs.join("dir")), vec!["upper"]);
+}
+
+#[test]
+fn opaque_whiteout_hides_lower_directory_contents() {
+	let lower = layer(&[
+		Entry::Dir("dir/"),
+		Entry::File("dir/lower", "lower"),
+		Entry::Dir("dir/sub/"),
+		Entry::File("dir/sub/lower", "lower"),
+		Entry::File("other", "other"),
+	]);
+	let upper = layer(&[
+		Entry::Dir("dir/"),
+		Entry::Dir("dir/sub/"),
+		Entry::File("dir/sub/upper", "upper"),
+		Entry::File("dir/.wh..wh..opq", ""),
+		Entry::File("dir/after", "after"),
+	]);
+
+	let (temp_dir, result) = unpack_image(vec![lower, upper]);
+	result.unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	assert_eq!(list(&rootfs), vec!["dir", "other"]);
+	assert_eq!(list(&rootfs.join("dir")), vec!["after", "sub"]);
+	assert_eq!(list(&rootfs.join("dir/sub")), vec!["upper"]);
+}
+
+#[test]
+fn changed_file_type_replaces_lower_file() {
+	let lower = layer(&[Entry::File("path", "file"), Entry::Dir("dir/")]);
+	let upper = layer(&[
+		Entry::Dir("path/"),
+		Entry::File("path/child", "child"),
+		Entry::File("dir", "now a file"),
+	]);
+
+	let (temp_dir, result) = unpack_image(vec![lower, upper]);
+	result.unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	assert_eq!(list(&rootfs.join("path")), vec!["child"]);
+	assert_eq!(
+		fs::read_to_string(rootfs.join("dir")).unwrap(),
+		"now a file"
+	);
+}
+
+#[test]
+fn whiteout_outside_rootfs_is_rejected() {
+	let mut builder = tar::Builder::new(Vec::new());
+	let mut header = tar::Header::new_gnu();
+	header.set_entry_type(tar::EntryType::Regular);
+	header.set_size(0);
+	// `append_data` refuses ".." in paths, so write the name directly.
+	header.as_gnu_mut().unwrap().name[..11].copy_from_slice(b"../.wh.file");
+	header.set_cksum();
+	builder.append(&header, &[][..]).unwrap();
+	let layer = builder.into_inner().unwrap();
+
+	let (_temp_dir, result) = unpack_image(vec![layer]);
+	assert!(matches!(result, Err(Error::UnsafeLayerEntry { .. })));
+}
+
+#[test]
+fn whiteout_through_symlink_stays_inside_rootfs() {
+	let outside = TempDir::new().unwrap();
+	fs::write(outside.path().join("victim"), "host file").unwrap();
+
+	// A lower layer points a directory to the host, upper layers delete through it.
+	let mut builder = tar::Builder::new(Vec::new());
+	let mut header = tar::Header::new_gnu();
+	header.set_entry_type(tar::EntryType::Symlink);
+	header.set_mode(0o777);
+	header.set_uid(0);
+	header.set_gid(0);
+	header.set_size(0);
+	builder
+		.append_link(&mut header, "escape", outside.path())
+		.unwrap();
+	let symlink_layer = builder.into_inner().unwrap();
+	let whiteout_layer = layer(&[Entry::File("escape/.wh.victim", "")]);
+	let opaque_layer = layer(&[Entry::File("escape/.wh..wh..opq", "")]);
+
+	let (_temp_dir, _result) = unpack_image(vec![symlink_layer, whiteout_layer, opaque_layer]);
+	assert!(outside.path().join("victim").exists());
+}
+
+#[test]
+fn zstd_layer() {
+	let tar = layer(&[Entry::Dir("dir/"), Entry::File("dir/file", "zstd")]);
+	let compressed = zstd::encode_all(&tar[..], 0).unwrap();
+	let media_type = crate::IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_owned();
+	let layers = vec![ImageLayer::new(compressed, media_type, None)];
+
+	let temp_dir = TempDir::new().unwrap();
+	let image = image_from_layers(layers, Some(vec![tar]));
+	unpack(temp_dir.path().join("bundle"), image).unwrap();
+
+	let content = fs::read_to_string(rootfs(&temp_dir).join("dir/file")).unwrap();
+	assert_eq!(content, "zstd");
+}
+
+#[test]
+fn zstd_layer_diff_id_mismatch() {
+	let tar = layer(&[Entry::File("file", "zstd")]);
+	let compressed = zstd::encode_all(&tar[..], 0).unwrap();
+	let media_type = crate::IMAGE_LAYER_ZSTD_MEDIA_TYPE.to_owned();
+	// The diff_id must be computed from the uncompressed tar, not the compressed blob.
+	let layers = vec![ImageLayer::new(compressed.clone(), media_type, None)];
+
+	let temp_dir = TempDir::new().unwrap();
+	let image = image_from_layers(layers, Some(vec![compressed]));
+	let result = unpack(temp_dir.path().join("bundle"), image);
+	assert!(matches!(result, Err(Error::LayerDiffIdMismatch)));
+}
+
+#[test]
+fn select_platform_from_index() {
+	let manifests: Vec<oci_client::manifest::ImageIndexEntry> = serde_json::from_value(json!([
+		{
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"digest": "sha256:amd64",
+			"size": 1,
+			"platform": { "os": "linux", "architecture": "amd64" },
+		},
+		{
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"digest": "sha256:armv6",
+			"size": 1,
+			"platform": { "os": "linux", "architecture": "arm", "variant": "v6" },
+		},
+		{
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"digest": "sha256:armv7",
+			"size": 1,
+			"platform": { "os": "linux", "architecture": "arm", "variant": "v7" },
+		},
+	]))
+	.unwrap();
+
+	let platform = |architecture: &str, variant: Option<&str>| crate::Platform {
+		os: "linux".into(),
+		architecture: architecture.into(),
+		variant: variant.map(Into::into),
+	};
+	let select = |platform| crate::select_platform(&manifests, &platform);
+
+	assert_eq!(select(platform("amd64", None)).unwrap(), "sha256:amd64");
+	assert_eq!(select(platform("arm", Some("v7"))).unwrap(), "sha256:armv7");
+	assert_eq!(select(platform("arm", None)).unwrap(), "sha256:armv6");
+	assert_eq!(select(platform("riscv64", None)), None);
+}
+
+#[test]
+fn parse_platform() {
+	let platform: Platform = "linux/arm/v7".parse().unwrap();
+	assert_eq!(platform.architecture, "arm");
+	assert_eq!(platform.variant.as_deref(), Some("v7"));
+	assert_eq!(platform.to_string(), "linux/arm/v7");
+	assert_eq!("linux/amd64".parse::<Platform>().unwrap().variant, None);
+	for invalid in ["linux", "linux/", "/amd64", "linux/arm/", "linux/arm/v7/x"] {
+		assert!(invalid.parse::<Platform>().is_err(), "{invalid}");
+	}
+}
+
+#[test]
+fn corrupt_layer_blob_is_detected() {
+	let tar = layer(&[Entry::File("file", "original")]);
+	let image = image(vec![tar.clone()]);
+
+	let corrupted = layer(&[Entry::File("file", "modified")]);
+	fs::write(&image.layers[0].path, corrupted).unwrap();
+
+	let temp_dir = TempDir::new().unwrap();
+	let result = unpack(temp_dir.path().join("bundle"), image);
+	assert!(matches!(result, Err(Error::CorruptCacheBlob { .. })));
+	// Nothing of the corrupt blob was unpacked.
+	assert!(list(&rootfs(&temp_dir)).is_empty());
+}
+
+/// Write a blob into a cache directory and return its digest.
+fn cache_blob(cache_dir: &Path, data: &[u8]) -> String {
+	let digest = format!("sha256:{:x}", Sha256::digest(data));
+	write_blob(cache_dir.join("blobs"), &digest, data).unwrap();
+	digest
+}
+
+/// Create a cache holding a single image, as if it had been pulled as `reference`.
+/// Returns the digests of the image's layer blob and of an unreferenced blob.
+fn cache_with_image(cache_dir: &Path, reference: &str) -> (String, String) {
+	let layer_digest = cache_blob(cache_dir, &layer(&[Entry::File("file", "content")]));
+	let config = json!({
+		"architecture": "amd64",
+		"os": "linux",
+		"rootfs": { "type": "layers", "diff_ids": [layer_digest] },
+		"history": [],
+	});
+	let config_digest = cache_blob(cache_dir, config.to_string().as_bytes());
+	let manifest = json!({
+		"schemaVersion": 2,
+		"config": {
+			"mediaType": "application/vnd.oci.image.config.v1+json",
+			"digest": config_digest,
+			"size": 0,
+		},
+		"layers": [{
+			"mediaType": "application/vnd.oci.image.layer.v1.tar",
+			"digest": layer_digest,
+			"size": 0,
+		}],
+	});
+	let manifest_digest = cache_blob(cache_dir, &serde_json::to_vec(&manifest).unwrap());
+	let unreferenced = cache_blob(cache_dir, b"unreferenced");
+
+	let index = json!({
+		"images": { format!("{reference} linux/amd64"): { "manifest_digest": manifest_digest } },
+	});
+	fs::write(cache_dir.join("index.json"), index.to_string()).unwrap();
+	(layer_digest, unreferenced)
+}
+
+#[test]
+fn cache_list_and_remove() {
+	let cache_dir = TempDir::new().unwrap();
+	let reference = "docker.io/library/busybox:latest";
+	cache_with_image(cache_dir.path(), reference);
+
+	let images = cache::list(cache_dir.path()).unwrap();
+	assert_eq!(images.len(), 1);
+	assert_eq!(images[0].reference, reference);
+	assert_eq!(images[0].platform, "linux/amd64");
+	assert!(images[0].size > 0);
+
+	let other = "docker.io/library/alpine:latest".parse().unwrap();
+	assert_eq!(cache::remove(cache_dir.path(), &other).unwrap(), 0);
+	let removed = cache::remove(cache_dir.path(), &reference.parse().unwrap()).unwrap();
+	assert_eq!(removed, 1);
+	assert!(cache::list(cache_dir.path()).unwrap().is_empty());
+}
+
+#[test]
+fn cache_verify_reports_corrupt_blobs() {
+	let cache_dir = TempDir::new().unwrap();
+	let (layer_digest, _) = cache_with_image(cache_dir.path(), "docker.io/library/busybox:latest");
+	assert!(cache::verify(cache_dir.path()).unwrap().is_empty());
+
+	// Verifying doesn't wait for pulls holding the index lock.
+	let index_lock = cache::lock_index(cache_dir.path()).unwrap();
+	assert!(cache::verify(cache_dir.path()).unwrap().is_empty());
+	drop(index_lock);
+
+	let blob_dir = cache_dir.path().join("blobs");
+	fs::write(blob_path(&blob_dir, &layer_digest).unwrap(), "corrupted").unwrap();
+	let corrupt = cache::verify(cache_dir.path()).unwrap();
+	assert!(matches!(
+		&corrupt[..],
+		[Error::CorruptCacheBlob { digest }] if *digest == layer_digest
+	));
+}
+
+#[test]
+fn cache_gc_removes_unreferenced_blobs() {
+	let cache_dir = TempDir::new().unwrap();
+	let reference = "docker.io/library/busybox:latest";
+	let (layer_digest, unreferenced) = cache_with_image(cache_dir.path(), reference);
+	let blob_dir = cache_dir.path().join("blobs");
+
+	// Left behind by a crash while writing the index.
+	fs::write(cache_dir.path().join(".tmpAbC123"), "{}").unwrap();
+
+	let result = cache::gc(cache_dir.path()).unwrap();
+	assert_eq!(result.removed_blobs, 1);
+	assert_eq!(result.removed_temp_files, 1);
+	assert_eq!(result.freed_bytes, ("unreferenced".len() + 2) as u64);
+	assert!(!cache_dir.path().join(".tmpAbC123").exists());
+	assert!(!blob_path(&blob_dir, &unreferenced).unwrap().exists());
+	assert!(blob_path(&blob_dir, &layer_digest).unwrap().exists());
+
+	cache::remove(cache_dir.path(), &reference.parse().unwrap()).unwrap();
+	let result = cache::gc(cache_dir.path()).unwrap();
+	assert_eq!(result.removed_blobs, 3);
+	assert!(!blob_path(&blob_dir, &layer_digest).unwrap().exists());
+}
+
+#[test]
+fn cache_index_without_platform_is_migrated() {
+	let cache_dir = TempDir::new().unwrap();
+	let reference = "docker.io/library/busybox:latest";
+	let (layer_digest, _) = cache_with_image(cache_dir.path(), reference);
+
+	// Version 0 keyed images by reference only.
+	let index_path = cache_dir.path().join("index.json");
+	let index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+	let image = &index["images"][format!("{reference} linux/amd64")];
+	let old_index = json!({
+		"images": {
+			reference: image,
+			"docker.io/library/missing:latest": { "manifest_digest": FAKE_DIGEST },
+		},
+	});
+	fs::write(&index_path, old_index.to_string()).unwrap();
+
+	let images = cache::list(cache_dir.path()).unwrap();
+	assert_eq!(images.len(), 1);
+	assert_eq!(images[0].reference, reference);
+	assert_eq!(images[0].platform, "linux/amd64");
+
+	// Writes store the migrated index, and gc keeps the blobs of migrated images.
+	cache::gc(cache_dir.path()).unwrap();
+	let blob_dir = cache_dir.path().join("blobs");
+	assert!(blob_path(&blob_dir, &layer_digest).unwrap().exists());
+	let removed = cache::remove(cache_dir.path(), &reference.parse().unwrap()).unwrap();
+	assert_eq!(removed, 1);
+	let index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+	assert_eq!(index["version"], 1);
+}
+
+/// Environment variable telling a re-executed test binary which role to play in [cache_lock_across_processes].
+const CHILD_ROLE_VAR: &str = "OCI_UNPACK_TEST_CHILD_ROLE";
+const CHILD_CACHE_VAR: &str = "OCI_UNPACK_TEST_CHILD_CACHE";
+
+/// Re-execute this test binary running only `test`, with the given role.
+fn spawn_child(test: &str, role: &str, cache_dir: &Path) -> std::process::Child {
+	Command::new(env::current_exe().unwrap())
+		.args(["--exact", test, "--nocapture", "--test-threads=1"])
+		.env(CHILD_ROLE_VAR, role)
+		.env(CHILD_CACHE_VAR, cache_dir)
+		.stdout(Stdio::piped())
+		.spawn()
+		.unwrap()
+}
+
+#[test]
+fn cache_lock_across_processes() {
+	const TEST: &str = "tests::cache_lock_across_processes";
+	if let Ok(role) = env::var(CHILD_ROLE_VAR) {
+		let cache_dir = env::var(CHILD_CACHE_VAR).unwrap();
+		match role.split_once(':') {
+			Some(("remove", reference)) => {
+				let removed = cache::remove(&cache_dir, &reference.parse().unwrap()).unwrap();
+				assert_eq!(removed, 1);
+			}
+			_ => {
+				// Hold the lock until killed.  libtest prints the test name on the same line.
+				let _lock = cache::lock_index(&cache_dir).unwrap();
+				println!("locked");
+				std::thread::sleep(Duration::from_secs(60));
+			}
+		}
+		return;
+	}
+
+	let cache_dir = TempDir::new().unwrap();
+	cache_with_image(cache_dir.path(), "docker.io/library/busybox:latest");
+
+	// A lock held by a crashed process is released by the kernel.
+	let mut holder = spawn_child(TEST, "hold", cache_dir.path());
+	let stdout = BufReader::new(holder.stdout.take().unwrap());
+	let locked = (stdout.lines()).any(|line| line.unwrap().ends_with("locked"));
+	assert!(locked);
+	let lock_file = fs::File::open(cache_dir.path().join("index.lock")).unwrap();
+	assert!(lock_file.try_lock_shared().is_err());
+	holder.kill().unwrap();
+	holder.wait().unwrap();
+	let start = Instant::now();
+	drop(cache::lock_index(cache_dir.path()).unwrap());
+	assert!(start.elapsed() < Duration::from_secs(5));
+
+	// Concurrent writers don't lose each other's updates, and readers never see a partial index.
+	let references: Vec<_> = (0..8)
+		.map(|i| format!("docker.io/library/image-{i}:latest"))
+		.collect();
+	let images: serde_json::Map<_, _> = (references.iter())
+		.map(|reference| {
+			let key = format!("{reference} linux/amd64");
+			(key, json!({ "manifest_digest": FAKE_DIGEST }))
+		})
+		.collect();
+	let index = json!({ "images": images });
+	fs::write(cache_dir.path().join("index.json"), index.to_string()).unwrap();
+
+	let children: Vec<_> = (references.iter())
+		.map(|reference| spawn_child(TEST, &format!("remove:{reference}"), cache_dir.path()))
+		.collect();
+	for _ in 0..20 {
+		cache::list(cache_dir.path()).unwrap();
+	}
+	for child in children {
+		let output = child.wait_with_output().unwrap();
+		assert!(output.status.success());
+	}
+	assert!(cache::list(cache_dir.path()).unwrap().is_empty());
+}
+
+fn offline_config(cache_dir: &Path) -> PullConfig {
+	PullConfig {
+		cache: Some(cache_dir.to_owned()),
+		platform: Platform {
+			os: "linux".into(),
+			architecture: "amd64".into(),
+			variant: None,
+		},
+		offline: true,
+		..PullConfig::default()
+	}
+}
+
+#[test]
+fn offline_pull_fails_on_cache_miss() {
+	let cache_dir = TempDir::new().unwrap();
+	cache_with_image(cache_dir.path(), "docker.io/library/busybox:latest");
+	let config = offline_config(cache_dir.path());
+
+	let image = "docker.io/library/alpine:latest".parse().unwrap();
+	let result = pull_image(&image, &config);
+	assert!(matches!(result, Err(Error::Offline { .. })));
+	let result = pull_image_manifest(&image, &config);
+	assert!(matches!(result, Err(Error::Offline { .. })));
+
+	let image = "docker.io/library/busybox:latest".parse().unwrap();
+	pull_image(&image, &config).unwrap();
+}
+
+#[test]
+fn cached_image_keeps_blobs_locked_until_dropped() {
+	let cache_dir = TempDir::new().unwrap();
+	let reference = "docker.io/library/busybox:latest";
+	cache_with_image(cache_dir.path(), reference);
+
+	let image = pull_image(
+		&reference.parse().unwrap(),
+		&offline_config(cache_dir.path()),
+	)
+	.unwrap();
+	let blobs_lock = fs::File::open(cache_dir.path().join("blobs.lock")).unwrap();
+	assert!(matches!(
+		blobs_lock.try_lock(),
+		Err(fs::TryLockError::WouldBlock)
+	));
+
+	drop(image);
+	blobs_lock.try_lock().unwrap();
+}
+
+#[test]
+fn offline_pull_uses_resolved_tag() {
+	let cache_dir = TempDir::new().unwrap();
+	let tag = "docker.io/library/busybox:latest";
+	cache_with_image(cache_dir.path(), tag);
+
+	// Re-key the image by digest, as if it was pulled by a resolved reference (which drops the tag).
+	let index_path = cache_dir.path().join("index.json");
+	let index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+	let manifest_digest = index["images"][format!("{tag} linux/amd64")]["manifest_digest"].clone();
+	let index = json!({
+		"images": {
+			format!("docker.io/library/busybox@{} linux/amd64", manifest_digest.as_str().unwrap()): {
+				"manifest_digest": manifest_digest,
+			},
+		},
+		"tags": {
+			format!("{tag} linux/amd64"): {
+				"manifest_digest": manifest_digest,
+				"resolved_at": 1700000000,
+			},
+		},
+	});
+	fs::write(&index_path, index.to_string()).unwrap();
+
+	let config = offline_config(cache_dir.path());
+	let image = tag.parse().unwrap();
+	let resolved = cache::resolved_tag(cache_dir.path(), &image, &config.platform).unwrap();
+	let resolved = resolved.unwrap();
+	assert_eq!(resolved.manifest_digest, manifest_digest);
+	assert_eq!(
+		resolved.resolved_at,
+		std::time::UNIX_EPOCH + Duration::from_secs(1700000000)
+	);
+
+	assert_eq!(
+		pull_image_manifest(&image, &config).unwrap(),
+		resolved.manifest_digest
+	);
+	let image_data = pull_image(&image, &config).unwrap();
+	assert_eq!(image_data.manifest_digest, resolved.manifest_digest);
+}
+
+/// Write an image with a single layer into an OCI image layout, naming it `ref_name`.
+fn oci_layout(layout_dir: &Path, ref_name: &str, layer_data: &[u8]) {
+	oci_layout_with_images(layout_dir, &[(ref_name, layer_data)]);
+}
+
+/// Write an OCI image layout holding an image per ref name.
+fn oci_layout_with_images(layout_dir: &Path, images: &[(&str, &[u8])]) {
+	let write = |data: &[u8]| {
+		let hex = format!("{:x}", Sha256::digest(data));
+		fs::create_dir_all(layout_dir.join("blobs/sha256")).unwrap();
+		fs::write(layout_dir.join("blobs/sha256").join(&hex), data).unwrap();
+		(format!("sha256:{hex}"), data.len())
+	};
+
+	let mut manifests = Vec::new();
+	for (ref_name, layer_data) in images {
+		let (layer_digest, layer_size) = write(layer_data);
+		let config = json!({
+			"architecture": "amd64",
+			"os": "linux",
+			"rootfs": { "type": "layers", "diff_ids": [layer_digest] },
+			"history": [],
+		});
+		let (config_digest, config_size) = write(config.to_string().as_bytes());
+		let manifest = json!({
+			"schemaVersion": 2,
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"config": {
+				"mediaType": "application/vnd.oci.image.config.v1+json",
+				"digest": config_digest,
+				"size": config_size,
+			},
+			"layers": [{
+				"mediaType": "application/vnd.oci.image.layer.v1.tar",
+				"digest": layer_digest,
+				"size": layer_size,
+			}],
+		});
+		let (manifest_digest, manifest_size) = write(manifest.to_string().as_bytes());
+		manifests.push(json!({
+			"mediaType": "application/vnd.oci.image.manifest.v1+json",
+			"digest": manifest_digest,
+			"size": manifest_size,
+			"annotations": { "org.opencontainers.image.ref.name": ref_name },
+		}));
+	}
+	let index = json!({ "schemaVersion": 2, "manifests": manifests });
+	fs::write(layout_dir.join("index.json"), index.to_string()).unwrap();
+	fs::write(
+		layout_dir.join("oci-layout"),
+		r#"{"imageLayoutVersion":"1.0.0"}"#,
+	)
+	.unwrap();
+}
+
+#[test]
+fn import_and_export_oci_layout() {
+	let temp_dir = TempDir::new().unwrap();
+	let layout_dir = temp_dir.path().join("layout");
+	let cache_dir = temp_dir.path().join("cache");
+	let image = "docker.io/library/fixture:latest".parse().unwrap();
+	let layer_data = layer(&[Entry::File("file", "from layout")]);
+	oci_layout(&layout_dir, "latest", &layer_data);
+
+	let config = offline_config(&cache_dir);
+	let manifest_digest =
+		layout::import_oci_layout(&layout_dir, &cache_dir, &image, &config.platform).unwrap();
+	assert_eq!(
+		pull_image_manifest(&image, &config).unwrap(),
+		manifest_digest
+	);
+	let bundle_dir = temp_dir.path().join("bundle");
+	unpack(&bundle_dir, pull_image(&image, &config).unwrap()).unwrap();
+	let content = fs::read_to_string(bundle_dir.join("rootfs/file")).unwrap();
+	assert_eq!(content, "from layout");
+
+	// Exporting and importing again results in the same image.
+	let exported_dir = temp_dir.path().join("exported");
+	layout::export_oci_layout(&cache_dir, &image, &config.platform, &exported_dir).unwrap();
+	let other_cache_dir = temp_dir.path().join("other-cache");
+	let reimported =
+		layout::import_oci_layout(&exported_dir, &other_cache_dir, &image, &config.platform)
+			.unwrap();
+	assert_eq!(reimported, manifest_digest);
+
+	let missing = "docker.io/library/missing:latest".parse().unwrap();
+	let result = layout::export_oci_layout(&cache_dir, &missing, &config.platform, &exported_dir);
+	assert!(matches!(result, Err(Error::ImageNotCached { .. })));
+}
+
+#[test]
+fn export_oci_layout_keeps_index_digest() {
+	let temp_dir = TempDir::new().unwrap();
+	let layout_dir = temp_dir.path().join("layout");
+	oci_layout(
+		&layout_dir,
+		"latest",
+		&layer(&[Entry::File("file", "multi-arch")]),
+	);
+
+	// Move the image into a nested image index, as written for multi-arch images.
+	let index_path = layout_dir.join("index.json");
+	let mut index: serde_json::Value =
+		serde_json::from_slice(&fs::read(&index_path).unwrap()).unwrap();
+	let entry = &mut index["manifests"][0];
+	entry["platform"] = json!({ "architecture": "amd64", "os": "linux" });
+	entry.as_object_mut().unwrap().remove("annotations");
+	let nested = json!({
+		"schemaVersion": 2,
+		"mediaType": "application/vnd.oci.image.index.v1+json",
+		"manifests": [entry],
+	})
+	.to_string();
+	let nested_hex = format!("{:x}", Sha256::digest(&nested));
+	fs::write(layout_dir.join("blobs/sha256").join(&nested_hex), &nested).unwrap();
+	let index_digest = format!("sha256:{nested_hex}");
+	let index = json!({ "schemaVersion": 2, "manifests": [{
+		"mediaType": "application/vnd.oci.image.index.v1+json",
+		"digest": index_digest,
+		"size": nested.len(),
+		"annotations": { "org.opencontainers.image.ref.name": "latest" },
+	}]});
+	fs::write(&index_path, index.to_string()).unwrap();
+
+	let image: Reference = "docker.io/library/fixture:latest".parse().unwrap();
+	let cache_dir = temp_dir.path().join("cache");
+	let platform = offline_config(&cache_dir).platform;
+	let manifest_digest =
+		layout::import_oci_layout(&layout_dir, &cache_dir, &image, &platform).unwrap();
+
+	let exported_dir = temp_dir.path().join("exported");
+	layout::export_oci_layout(&cache_dir, &image, &platform, &exported_dir).unwrap();
+	let other_cache_dir = temp_dir.path().join("other-cache");
+	layout::import_oci_layout(&exported_dir, &other_cache_dir, &image, &platform).unwrap();
+
+	// The image can still be pulled by the digest of its image index.
+	let by_index_digest = image.clone_with_digest(index_digest.clone());
+	let config = offline_config(&other_cache_dir);
+	assert_eq!(
+		pull_image_manifest(&by_index_digest, &config).unwrap(),
+		manifest_digest
+	);
+	let cached = cache::list(&other_cache_dir).unwrap();
+	assert!(cached
+		.iter()
+		.all(|image| image.index_digest.as_ref() == Some(&index_digest)));
+	// No temporary files are left behind.
+	let mut files: Vec<_> = (fs::read_dir(&exported_dir).unwrap())
+		.map(|entry| entry.unwrap().file_name())
+		.collect();
+	files.sort();
+	assert_eq!(files, ["blobs", "index.json", "oci-layout"]);
+}
+
+#[test]
+fn import_oci_layout_with_corrupt_blob() {
+	let temp_dir = TempDir::new().unwrap();
+	let layout_dir = temp_dir.path().join("layout");
+	let layer_data = layer(&[Entry::File("file", "from layout")]);
+	oci_layout(&layout_dir, "latest", &layer_data);
+	let layer_path =
+		(layout_dir.join("blobs/sha256")).join(format!("{:x}", Sha256::digest(&layer_data)));
+	fs::write(layer_path, "corrupted").unwrap();
+
+	let image = "docker.io/library/fixture:latest".parse().unwrap();
+	let cache_dir = temp_dir.path().join("cache");
+	let platform = offline_config(&cache_dir).platform;
+	let result = layout::import_oci_layout(&layout_dir, &cache_dir, &image, &platform);
+	assert!(matches!(result, Err(Error::ImageInvalid(_))));
+}
+
+#[test]
+fn import_oci_layout_with_several_images() {
+	let temp_dir = TempDir::new().unwrap();
+	let layout_dir = temp_dir.path().join("layout");
+	let (busybox, alpine) = (
+		layer(&[Entry::File("file", "busybox")]),
+		layer(&[Entry::File("file", "alpine")]),
+	);
+	oci_layout_with_images(
+		&layout_dir,
+		&[
+			("docker.io/library/busybox:latest", &busybox),
+			("docker.io/library/alpine:latest", &alpine),
+			("stable", &busybox),
+		],
+	);
+	let cache_dir = temp_dir.path().join("cache");
+	let config = offline_config(&cache_dir);
+	let import = |reference: &str| {
+		let image: Reference = reference.parse().unwrap();
+		layout::import_oci_layout(&layout_dir, &cache_dir, &image, &config.platform).map(|_| image)
+	};
+
+	// Full references are preferred over tags.
+	let image = import("docker.io/library/alpine:latest").unwrap();
+	let bundle_dir = temp_dir.path().join("bundle");
+	unpack(&bundle_dir, pull_image(&image, &config).unwrap()).unwrap();
+	let content = fs::read_to_string(bundle_dir.join("rootfs/file")).unwrap();
+	assert_eq!(content, "alpine");
+
+	import("docker.io/library/debian:stable").unwrap();
+
+	let result = import("docker.io/library/debian:bookworm");
+	let Err(Error::ImageNotFound { available, .. }) = result else {
+		panic!("expected ImageNotFound, got {result:?}");
+	};
+	assert_eq!(
+		available,
+		"docker.io/library/busybox:latest, docker.io/library/alpine:latest, stable"
+	);
+}
+
+#[test]
+fn import_oci_layout_with_ambiguous_tag() {
+	let temp_dir = TempDir::new().unwrap();
+	let layout_dir = temp_dir.path().join("layout");
+	let (first, second) = (
+		layer(&[Entry::File("file", "first")]),
+		layer(&[Entry::File("file", "second")]),
+	);
+	oci_layout_with_images(&layout_dir, &[("latest", &first), ("latest", &second)]);
+
+	let cache_dir = temp_dir.path().join("cache");
+	let image = "docker.io/library/busybox:latest".parse().unwrap();
+	let platform = offline_config(&cache_dir).platform;
+	let result = layout::import_oci_layout(&layout_dir, &cache_dir, &image, &platform);
+	assert!(
+		matches!(result, Err(Error::AmbiguousImage { .. })),
+		"{result:?}"
+	);
+}
+
+/// Write a `docker save` tarball holding an image with a single file "file" per entry.
+fn docker_archive(path: &Path, images: &[(&[&str], &'static str)]) {
+	let mut files = vec![];
+	let mut manifest = vec![];
+	for (i, (tags, content)) in images.iter().enumerate() {
+		let layer_data = layer(&[Entry::File("file", content)]);
+		let config = json!({
+			"architecture": "amd64",
+			"os": "linux",
+			"rootfs": {
+				"type": "layers",
+				"diff_ids": [format!("sha256:{:x}", Sha256::digest(&layer_data))],
+			},
+			"history": [],
+		})
+		.to_string();
+		manifest.push(json!({
+			"Config": format!("config{i}.json"),
+			"RepoTags": tags,
+			"Layers": [format!("{i}/layer.tar")],
+		}));
+		files.push((format!("config{i}.json"), config.into_bytes()));
+		files.push((format!("{i}/layer.tar"), layer_data));
+	}
+	files.push((
+		"manifest.json".into(),
+		json!(manifest).to_string().into_bytes(),
+	));
+
+	let mut builder = tar::Builder::new(Vec::new());
+	for (path, data) in files {
+		let mut header = tar::Header::new_gnu();
+		header.set_mode(0o644);
+		header.set_size(data.len() as u64);
+		builder.append_data(&mut header, path, &data[..]).unwrap();
+	}
+	fs::write(path, builder.into_inner().unwrap()).unwrap();
+}
+
+#[test]
+fn import_docker_archive() {
+	let temp_dir = TempDir::new().unwrap();
+	let archive_path = temp_dir.path().join("image.tar");
+	docker_archive(&archive_path, &[(&["fixture:1.0"], "from docker")]);
+
+	let cache_dir = temp_dir.path().join("cache");
+	let config = offline_config(&cache_dir);
+	let image =
+		layout::import_docker_archive(&archive_path, &cache_dir, None, &config.platform).unwrap();
+	assert_eq!(image.whole(), "docker.io/library/fixture:1.0");
+
+	let bundle_dir = temp_dir.path().join("bundle");
+	unpack(&bundle_dir, pull_image(&image, &config).unwrap()).unwrap();
+	let content = fs::read_to_string(bundle_dir.join("rootfs/file")).unwrap();
+	assert_eq!(content, "from docker");
+}
+
+#[test]
+fn import_docker_archive_with_several_images() {
+	let temp_dir = TempDir::new().unwrap();
+	let archive_path = temp_dir.path().join("images.tar");
+	docker_archive(
+		&archive_path,
+		&[
+			(&["fixture:1.0", "fixture:latest"], "first"),
+			(&["other:2.0"], "second"),
+		],
+	);
+	let cache_dir = temp_dir.path().join("cache");
+	let config = offline_config(&cache_dir);
+	let import = |reference: Option<&str>| {
+		let reference: Option<Reference> = reference.map(|r| r.parse().unwrap());
+		let platform = &config.platform;
+		layout::import_docker_archive(&archive_path, &cache_dir, reference.as_ref(), platform)
+	};
+
+	let image = import(Some("docker.io/library/other:2.0")).unwrap();
+	let bundle_dir = temp_dir.path().join("bundle");
+	unpack(&bundle_dir, pull_image(&image, &config).unwrap()).unwrap();
+	let content = fs::read_to_string(bundle_dir.join("rootfs/file")).unwrap();
+	assert_eq!(content, "second");
+
+	let result = import(Some("fixture:1.1"));
+	let Err(Error::ImageNotFound { available, .. }) = result else {
+		panic!("expected ImageNotFound, got {result:?}");
+	};
+	assert_eq!(available, "fixture:1.0, fixture:latest, other:2.0");
+
+	// Without reference, the image to import can't be chosen.
+	assert!(matches!(import(None), Err(Error::ImageInvalid(_))));
+
+	docker_archive(
+		&archive_path,
+		&[(&["fixture:1.0"], "first"), (&["fixture:1.0"], "second")],
+	);
+	let result = import(Some("fixture:1.0"));
+	assert!(
+		matches!(result, Err(Error::AmbiguousImage { .. })),
+		"{result:?}"
+	);
+}
+
+#[test]
+fn import_docker_archive_rejects_symlinks_outside() {
+	let temp_dir = TempDir::new().unwrap();
+	let archive_path = temp_dir.path().join("image.tar");
+	let cache_dir = temp_dir.path().join("cache");
+	let platform = offline_config(&cache_dir).platform;
+
+	// Replace a file of a valid archive by a symlink to `target`, keeping the file at `moved_to`.
+	let with_symlink = |name: &str, target: &Path, moved_to: Option<&str>| {
+		docker_archive(&archive_path, &[(&["fixture:1.0"], "from docker")]);
+		let mut archive = tar::Archive::new(fs::File::open(&archive_path).unwrap());
+		let mut builder = tar::Builder::new(Vec::new());
+		for entry in archive.entries().unwrap() {
+			let entry = entry.unwrap();
+			let path = entry.path().unwrap().into_owned();
+			let mut header = entry.header().clone();
+			if path != Path::new(name) {
+				builder.append(&header, entry).unwrap();
+				continue;
+			}
+			if let Some(moved_to) = moved_to {
+				builder
+					.append_data(&mut header.clone(), moved_to, entry)
+					.unwrap();
+			}
+			header.set_entry_type(tar::EntryType::Symlink);
+			header.set_size(0);
+			builder.append_link(&mut header, &path, target).unwrap();
+		}
+		fs::write(&archive_path, builder.into_inner().unwrap()).unwrap();
+		layout::import_docker_archive(&archive_path, &cache_dir, None, &platform)
+	};
+
+	let secret = temp_dir.path().join("secret");
+	fs::write(&secret, "secret").unwrap();
+	for name in ["manifest.json", "config0.json", "0/layer.tar"] {
+		let result = with_symlink(name, &secret, None);
+		assert!(
+			matches!(result, Err(Error::ImageInvalid(_))),
+			"{name}: {result:?}"
+		);
+	}
+	assert!(!cache_dir.join("index.json").exists());
+
+	// `docker save` links layers, which several images share, inside of the archive.
+	let shared = Path::new("../shared/layer.tar");
+	let image = with_symlink("0/layer.tar", shared, Some("shared/layer.tar")).unwrap();
+	assert_eq!(image.whole(), "docker.io/library/fixture:1.0");
+}
+
+fn docker_config(config: serde_json::Value, helper_dir: &Path) -> DockerConfig {
+	let mut config: DockerConfig = serde_json::from_value(config).unwrap();
+	config.helper_dir = Some(helper_dir.to_owned());
+	config
+}
+
+#[test]
+fn registry_auth_from_docker_config() {
+	use oci_client::secrets::RegistryAuth;
+	use std::os::unix::fs::PermissionsExt;
+
+	let temp_dir = TempDir::new().unwrap();
+	// A credential helper, which only knows about ghcr.io.
+	let helper = temp_dir.path().join("docker-credential-fake");
+	fs::write(
+		&helper,
+		r#"#!/bin/sh
+[ "$1" = get ] || exit 2
+read server
+if [ "$server" = ghcr.io ]; then
+	echo '{"ServerURL":"ghcr.io","Username":"helper-user","Secret":"helper-secret"}'
+else
+	echo "credentials not found in native keychain"
+	exit 1
+fi
+"#,
+	)
+	.unwrap();
+	fs::set_permissions(&helper, fs::Permissions::from_mode(0o755)).unwrap();
+
+	let config = docker_config(
+		json!({
+			"auths": {
+				// base64 of "hub-user:hub-secret"
+				"https://index.docker.io/v1/": { "auth": "aHViLXVzZXI6aHViLXNlY3JldA==" },
+				"registry.example.com": { "username": "user", "password": "secret" },
+			},
+			"credHelpers": { "ghcr.io": "fake" },
+			"credsStore": "fake",
+		}),
+		temp_dir.path(),
+	);
+	let auth_for = |reference: &str| config.auth_for(&reference.parse().unwrap()).unwrap();
+
+	let basic = |user: &str, secret: &str| RegistryAuth::Basic(user.into(), secret.into());
+	assert_eq!(auth_for("busybox:latest"), basic("hub-user", "hub-secret"));
+	assert_eq!(
+		auth_for("registry.example.com/project/image:1.0"),
+		basic("user", "secret")
+	);
+	assert_eq!(
+		auth_for("ghcr.io/owner/image:latest"),
+		basic("helper-user", "helper-secret")
+	);
+	// Falls back to the credential store, which has no credentials for this registry.
+	assert_eq!(
+		auth_for("quay.io/owner/image:latest"),
+		RegistryAuth::Anonymous
+	);
+
+	let config = docker_config(
+		json!({ "credHelpers": { "ghcr.io": "missing" } }),
+		temp_dir.path(),
+	);
+	let result = config.auth_for(&"ghcr.io/owner/image:latest".parse().unwrap());
+	assert!(matches!(result, Err(Error::Credentials(_))));
+}
+
+#[test]
+fn mirror_sources() {
+	let mirror: Mirror = "docker.io=mirror-1.example.com, mirror-2.example.com"
+		.parse()
+		.unwrap();
+	assert_eq!(
+		mirror.mirrors,
+		["mirror-1.example.com", "mirror-2.example.com"]
+	);
+	assert!(mirror.fallback);
+	assert!("docker.io".parse::<Mirror>().is_err());
+	assert!("docker.io=".parse::<Mirror>().is_err());
+
+	let config = PullConfig {
+		mirrors: vec![mirror],
+		..PullConfig::default()
+	};
+	let image: Reference = "busybox:latest".parse().unwrap();
+	let sources = config.sources(&image);
+	let registries: Vec<_> = sources.iter().map(|s| s.resolve_registry()).collect();
+	assert_eq!(
+		registries,
+		[
+			"mirror-1.example.com",
+			"mirror-2.example.com",
+			"index.docker.io"
+		]
+	);
+	// The identity of the image does not change.
+	assert!(sources.iter().all(|source| source.whole() == image.whole()));
+
+	let image: Reference = "quay.io/owner/image:latest".parse().unwrap();
+	let registries: Vec<_> = (config.sources(&image).iter())
+		.map(|s| s.resolve_registry().to_owned())
+		.collect();
+	assert_eq!(registries, ["quay.io"]);
+}
+
+#[test]
+fn mirror_sources_without_fallback() {
+	let mirror: Mirror = "docker.io=mirror.example.com!".parse().unwrap();
+	assert_eq!(mirror.mirrors, ["mirror.example.com"]);
+	assert!(!mirror.fallback);
+	assert!("docker.io=!".parse::<Mirror>().is_err());
+
+	let config = PullConfig {
+		mirrors: vec![mirror],
+		..PullConfig::default()
+	};
+	let image: Reference = "busybox:latest".parse().unwrap();
+	let registries: Vec<_> = (config.sources(&image).iter())
+		.map(|s| s.resolve_registry().to_owned())
+		.collect();
+	assert_eq!(registries, ["mirror.example.com"]);
+}
+
+#[test]
+fn snapshot_is_unpacked_once() {
+	let snapshot_dir = TempDir::new().unwrap();
+	let first = layer(&[Entry::File("version", "1")]);
+	let snapshot = snapshot::snapshot(snapshot_dir.path(), image(vec![first])).unwrap();
+	assert_eq!(
+		snapshot.rootfs,
+		snapshot_dir
+			.path()
+			.join(FAKE_DIGEST.replace(':', "/"))
+			.join("rootfs")
+	);
+	assert_eq!(
+		fs::read_to_string(snapshot.rootfs.join("version")).unwrap(),
+		"1"
+	);
+
+	// Images are identified by their manifest digest, which both images share.
+	let second = layer(&[Entry::File("version", "2")]);
+	let snapshot = snapshot::snapshot(snapshot_dir.path(), image(vec![second])).unwrap();
+	assert_eq!(
+		fs::read_to_string(snapshot.rootfs.join("version")).unwrap(),
+		"1"
+	);
+	assert_eq!(snapshot.info.manifest_digest, FAKE_DIGEST);
+	// No temporary unpack directories are left behind.
+	assert_eq!(list(snapshot_dir.path()), vec!["sha256", "snapshots.lock"]);
+}
+
+#[test]
+fn snapshots_are_removed_with_their_image() {
+	let temp_dir = TempDir::new().unwrap();
+	let (cache_dir, snapshot_dir) = (
+		temp_dir.path().join("cache"),
+		temp_dir.path().join("snapshots"),
+	);
+	let reference = "docker.io/library/busybox:latest";
+	cache_with_image(&cache_dir, reference);
+	let reference: Reference = reference.parse().unwrap();
+	let config = offline_config(&cache_dir);
+
+	let snapshot = snapshot::pull_and_snapshot(&reference, &snapshot_dir, &config).unwrap();
+	let manifest_digest = snapshot.info.manifest_digest.clone();
+	drop(snapshot);
+	assert_eq!(
+		snapshot::list(&snapshot_dir).unwrap(),
+		vec![manifest_digest.clone()]
+	);
+	fs::create_dir(snapshot_dir.join(".unpack-interrupted")).unwrap();
+
+	// Snapshots of cached images are kept, leftovers of interrupted unpacks are not.
+	assert_eq!(snapshot::gc(&snapshot_dir, &cache_dir).unwrap(), 0);
+	assert_eq!(
+		snapshot::list(&snapshot_dir).unwrap(),
+		vec![manifest_digest.clone()]
+	);
+	assert!(!snapshot_dir.join(".unpack-interrupted").exists());
+
+	cache::remove(&cache_dir, &reference).unwrap();
+	assert_eq!(snapshot::gc(&snapshot_dir, &cache_dir).unwrap(), 1);
+	assert!(snapshot::list(&snapshot_dir).unwrap().is_empty());
+
+	// Snapshots can also be removed directly.
+	let layer = layer(&[Entry::File("file", "content")]);
+	snapshot::snapshot(&snapshot_dir, image(vec![layer])).unwrap();
+	assert!(snapshot::remove(&snapshot_dir, FAKE_DIGEST).unwrap());
+	assert!(!snapshot::remove(&snapshot_dir, FAKE_DIGEST).unwrap());
+	assert!(snapshot::list(&snapshot_dir).unwrap().is_empty());
+}
+
+/// A layer with a setuid binary owned by root, a file owned by a user, and a device node.
+fn layer_with_special_files() -> Vec<u8> {
+	let mut builder = tar::Builder::new(Vec::new());
+	let mut append = |path: &str, entry_type, uid, gid, mode, content: &[u8]| {
+		let mut header = tar::Header::new_gnu();
+		header.set_entry_type(entry_type);
+		header.set_uid(uid);
+		header.set_gid(gid);
+		header.set_mode(mode);
+		header.set_size(content.len() as u64);
+		if entry_type == tar::EntryType::Char {
+			header.set_device_major(1).unwrap();
+			header.set_device_minor(3).unwrap();
+		}
+		builder.append_data(&mut header, path, content).unwrap();
+	};
+	append("bin/su", tar::EntryType::Regular, 0, 0, 0o4755, b"su");
+	append(
+		"home/user/file",
+		tar::EntryType::Regular,
+		1000,
+		100,
+		0o644,
+		b"file",
+	);
+	append("dev/null", tar::EntryType::Char, 0, 0, 0o666, b"");
+	append("run/fifo", tar::EntryType::Fifo, 0, 0, 0o600, b"");
+	builder.into_inner().unwrap()
+}
+
+#[test]
+fn rootless_unpack_records_special_files() {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+	let temp_dir = TempDir::new().unwrap();
+	let bundle = temp_dir.path().join("bundle");
+	let config = UnpackConfig { rootless: true };
+	let lower = layer_with_special_files();
+	let upper = layer(&[Entry::File("home/user/.wh.file", "")]);
+	unpack_with(&bundle, image(vec![lower, upper]), &config).unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	// Nothing which requires privileges was applied.
+	let su = rootfs.join("bin/su").symlink_metadata().unwrap();
+	assert_eq!(su.mode() & 0o7777, 0o755);
+	let null = rootfs.join("dev/null").symlink_metadata().unwrap();
+	assert!(null.is_file());
+	assert_eq!(null.len(), 0);
+	let fifo = rootfs.join("run/fifo").symlink_metadata().unwrap();
+	assert!(fifo.file_type().is_fifo());
+
+	// Instead, it was recorded.  Files removed by upper layers are not.
+	let files = rootless::read(&bundle).unwrap();
+	let recorded: Vec<_> = files
+		.iter()
+		.map(|(path, attrs)| (path.to_str().unwrap(), attrs))
+		.collect();
+	assert_eq!(
+		recorded,
+		vec![
+			(
+				"/bin/su",
+				&FileAttributes {
+					uid: 0,
+					gid: 0,
+					mode: 0o4755,
+					device: None,
+				}
+			),
+			(
+				"/dev/null",
+				&FileAttributes {
+					uid: 0,
+					gid: 0,
+					mode: 0o666,
+					device: Some(Device {
+						kind: DeviceKind::Char,
+						major: 1,
+						minor: 3,
+					}),
+				}
+			),
+		]
+	);
+}
+
+#[test]
+fn privileged_unpack_applies_special_files() {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+	if !rootless::is_root() {
+		eprintln!("skipped: unpacking device nodes requires root");
+		return;
+	}
+	let temp_dir = TempDir::new().unwrap();
+	let bundle = temp_dir.path().join("bundle");
+	let config = UnpackConfig { rootless: false };
+	unpack_with(&bundle, image(vec![layer_with_special_files()]), &config).unwrap();
+
+	let rootfs = rootfs(&temp_dir);
+	let su = rootfs.join("bin/su").symlink_metadata().unwrap();
+	assert_eq!(su.mode() & 0o7777, 0o4755);
+	let file = rootfs.join("home/user/file").symlink_metadata().unwrap();
+	assert_eq!((file.uid(), file.gid()), (1000, 100));
+	let null = rootfs.join("dev/null").symlink_metadata().unwrap();
+	assert!(null.file_type().is_char_device());
+	assert_eq!(null.rdev(), libc::makedev(1, 3));
+	assert!(rootless::read(&bundle).unwrap().is_empty());
+}
+
+/// Entry of a hand-crafted layer.  Names are written as they are, bypassing the checks of [tar::Builder].
+enum RawEntry<'a> {
+	File(&'a str, &'a str),
+	Dir(&'a str),
+	Symlink(&'a str, &'a str),
+	Hardlink(&'a str, &'a str),
+	Char(&'a str),
+}
+
+fn raw_layer(entries: &[RawEntry]) -> Vec<u8> {
+	let mut builder = tar::Builder::new(Vec::new());
+	for entry in entries {
+		let (entry_type, name, link_name, content) = match *entry {
+			RawEntry::File(name, content) => (tar::EntryType::Regular, name, "", content),
+			RawEntry::Dir(name) => (tar::EntryType::Directory, name, "", ""),
+			RawEntry::Symlink(name, target) => (tar::EntryType::Symlink, name, target, ""),
+			RawEntry::Hardlink(name, target) => (tar::EntryType::Link, name, target, ""),
+			RawEntry::Char(name) => (tar::EntryType::Char, name, "", ""),
+		};
+		let mut header = tar::Header::new_gnu();
+		header.set_entry_type(entry_type);
+		header.set_uid(0);
+		header.set_gid(0);
+		header.set_mode(0o755);
+		header.set_size(content.len() as u64);
+		header.set_device_major(1).unwrap();
+		header.set_device_minor(3).unwrap();
+		let gnu = header.as_gnu_mut().unwrap();
+		gnu.name[..name.len()].copy_from_slice(name.as_bytes());
+		gnu.linkname[..link_name.len()].copy_from_slice(link_name.as_bytes());
+		header.set_cksum();
+		builder.append(&header, content.as_bytes()).unwrap();
+	}
+	builder.into_inner().unwrap()
+}
+
+#[derive(Debug)]
+enum Expect {
+	/// The layer is rejected as unsafe.
+	Unsafe,
+	/// The layer fails to unpack for another reason.
+	Fails,
+	/// The layer unpacks, but stays inside the rootfs.
+	Contained,
+}
+
+#[test]
+fn malicious_layers_stay_inside_rootfs() {
+	use std::os::unix::fs::{MetadataExt, PermissionsExt};
+	use RawEntry::*;
+
+	let temp_dir = TempDir::new().unwrap();
+	let outside = temp_dir.path().join("outside");
+	let outside = outside.to_str().unwrap();
+	let victim = format!("{outside}/victim");
+
+	let corpus: Vec<(&str, Vec<Vec<RawEntry>>, Expect)> = vec![
+		(
+			"dotdot file",
+			vec![vec![File("../victim", "pwned")]],
+			Expect::Unsafe,
+		),
+		(
+			"absolute file",
+			vec![vec![File(&victim, "pwned")]],
+			Expect::Unsafe,
+		),
+		(
+			"nested dotdot",
+			vec![vec![Dir("a/"), File("a/../../victim", "pwned")]],
+			Expect::Unsafe,
+		),
+		(
+			"relative symlink parent",
+			vec![vec![
+				Symlink("link", "../../outside"),
+				File("link/victim", "pwned"),
+			]],
+			Expect::Contained,
+		),
+		(
+			"absolute symlink parent",
+			vec![vec![Symlink("link", outside), File("link/victim", "pwned")]],
+			Expect::Contained,
+		),
+		(
+			"symlink parent from lower layer",
+			vec![
+				vec![Symlink("link", outside)],
+				vec![File("link/victim", "pwned")],
+			],
+			Expect::Contained,
+		),
+		(
+			"symlink chain",
+			vec![vec![
+				Symlink("a", "b"),
+				Symlink("b", outside),
+				File("a/victim", "pwned"),
+			]],
+			Expect::Contained,
+		),
+		(
+			"symlink loop",
+			vec![vec![Symlink("a", "b"), Symlink("b", "a"), File("a/x", "x")]],
+			Expect::Unsafe,
+		),
+		(
+			"file replacing symlink",
+			vec![vec![Symlink("link", &victim), File("link", "pwned")]],
+			Expect::Contained,
+		),
+		(
+			"directory through symlink",
+			vec![vec![Symlink("link", outside), Dir("link/dir/")]],
+			Expect::Contained,
+		),
+		(
+			"directory replaced by symlink",
+			vec![vec![Dir("x/"), Symlink("x", outside)]],
+			Expect::Unsafe,
+		),
+		(
+			"parent directory replaced by symlink",
+			vec![vec![Dir("x/"), Dir("x/victim/"), Symlink("x", outside)]],
+			Expect::Unsafe,
+		),
+		(
+			"device through symlink",
+			vec![vec![Symlink("link", outside), Char("link/null")]],
+			Expect::Contained,
+		),
+		(
+			"whiteout through symlink",
+			vec![
+				vec![Symlink("link", outside)],
+				vec![File("link/.wh.victim", "")],
+			],
+			Expect::Contained,
+		),
+		(
+			"opaque whiteout through symlink",
+			vec![
+				vec![Symlink("link", outside)],
+				vec![File("link/.wh..wh..opq", "")],
+			],
+			Expect::Contained,
+		),
+		(
+			"dotdot whiteout",
+			vec![vec![File(".wh...", "")]],
+			Expect::Unsafe,
+		),
+		(
+			"dotdot hardlink",
+			vec![vec![Hardlink("stolen", "../outside/victim")]],
+			Expect::Unsafe,
+		),
+		(
+			"absolute hardlink",
+			vec![vec![Hardlink("stolen", &victim)]],
+			Expect::Unsafe,
+		),
+		(
+			"hardlink through symlink",
+			vec![
+				vec![Symlink("link", outside)],
+				vec![Hardlink("stolen", "link/victim")],
+			],
+			Expect::Fails,
+		),
+	];
+
+	for (name, layers, expect) in corpus {
+		fs::create_dir_all(outside).unwrap();
+		fs::set_permissions(outside, fs::Permissions::from_mode(0o700)).unwrap();
+		fs::write(&victim, "victim").unwrap();
+		let bundle = temp_dir.path().join("bundle");
+
+		let layers = layers.iter().map(|entries| raw_layer(entries)).collect();
+		let result = unpack(&bundle, image(layers)).map(|_| ());
+		let as_expected = match expect {
+			Expect::Unsafe => matches!(result, Err(Error::UnsafeLayerEntry { .. })),
+			Expect::Fails => result.is_err(),
+			Expect::Contained => result.is_ok(),
+		};
+		assert!(as_expected, "{name}: expected {expect:?}, got {result:?}");
+
+		assert_eq!(list(Path::new(outside)), vec!["victim"], "{name}");
+		let meta = fs::metadata(&victim).unwrap();
+		assert_eq!(meta.nlink(), 1, "{name}");
+		assert_eq!(fs::read_to_string(&victim).unwrap(), "victim", "{name}");
+		let mode = fs::metadata(outside).unwrap().permissions().mode() & 0o7777;
+		assert_eq!(mode, 0o700, "{name}");
+
+		fs::remove_dir_all(&bundle).unwrap();
+	}
+}
+
+#[test]
+fn sha512_digests() {
+	let data = layer(&[Entry::File("file", "sha512")]);
+	let digest = Algorithm::Sha512.digest(&data).to_string();
+	assert_eq!(digest.len(), "sha512:".len() + 128);
+
+	let blob_dir = TempDir::new().unwrap();
+	write_blob(blob_dir.path(), &digest, &data).unwrap();
+	let path = blob_path(blob_dir.path(), &digest).unwrap();
+	assert!(path.starts_with(blob_dir.path().join("sha512")));
+	assert_eq!(read_blob(blob_dir.path(), &digest).unwrap(), data);
+	let listed = list_blobs(blob_dir.path()).unwrap();
+	assert_eq!(listed[0].digest.as_deref(), Some(digest.as_str()));
+
+	// Layers and diff ids may use sha512, too.
+	let sha512_image = |diff_id: String| {
+		let mut image_data = image(vec![data.clone()]);
+		image_data.layers[0].digest = digest.clone();
+		image_data.config = serde_json::from_value(json!({
+			"architecture": "amd64",
+			"os": "linux",
+			"rootfs": { "type": "layers", "diff_ids": [diff_id] },
+			"history": [],
+		}))
+		.unwrap();
+		image_data
+	};
+	let temp_dir = TempDir::new().unwrap();
+	unpack(temp_dir.path().join("bundle"), sha512_image(digest.clone())).unwrap();
+	assert_eq!(
+		fs::read_to_string(rootfs(&temp_dir).join("file")).unwrap(),
+		"sha512"
+	);
+
+	let temp_dir = TempDir::new().unwrap();
+	let wrong_diff_id = Algorithm::Sha512.digest(b"other").to_string();
+	let result = unpack(temp_dir.path().join("bundle"), sha512_image(wrong_diff_id));
+	assert!(matches!(result, Err(Error::LayerDiffIdMismatch)));
+
+	let result = blob_path(blob_dir.path(), "md5:d41d8cd98f00b204e9800998ecf8427e");
+	assert!(matches!(result, Err(Error::DigestNotSupported { .. })));
+
+	// Digests end up in paths, so their length is checked too.
+	let sha256 = Algorithm::Sha256.digest(b"").to_string();
+	for digest in [
+		"sha256:0000",
+		&sha256[..sha256.len() - 1],
+		&format!("{sha256}0"),
+	] {
+		let result = blob_path(blob_dir.path(), digest);
+		assert!(matches!(result, Err(Error::DigestNotSupported { .. })));
+	}
+	let sha512 = Algorithm::Sha512.digest(b"").to_string();
+	assert!(sha512.parse::<crate::digest::Digest>().is_ok());
+	assert!(sha512[..71].parse::<crate::digest::Digest>().is_err());
+}
@@ -39,6 +39,40 @@ pub struct CatalogRef {
 	pub item_name: ItemName,
 }
 
+/// Reference to a whole release, rather than a single item in it: "{moduleName}:{releaseName}".
+#[derive(Clone, Debug, SerializeDisplay, DeserializeFromStr, catverters_derive::Stringoid)]
+pub struct CatalogReleaseRef {
+	pub module_name: ModuleName,
+	pub release_name: ReleaseName,
+}
+
+/// Replays are stored next to a catalog module's releases, and referenced by CID from a release's "replay" metadata.
+/// They contain everything needed to rebuild the release, and a record of the run that originally built it.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ReplayCapsule {
+	#[serde(rename = "replay.v1")]
+	V1(Replay),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Replay {
+	pub plot: crate::plot::PlotCapsule,
+	#[serde(rename = "runRecord")]
+	pub run_record: RunRecord,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct RunRecord {
+	/// Unix timestamp (in seconds) of when the run finished.
+	pub time: u64,
+	/// WareIDs of the plot outputs, as produced by the run.
+	pub results: IndexMap<ItemName, crate::content::WareID>,
+	/// JSON Patches applied to the container specs during the run, in order.
+	/// Replays apply them again.
+	#[serde(rename = "specPatches", default, skip_serializing_if = "Vec::is_empty")]
+	pub spec_patches: Vec<serde_json::Value>,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, FromStr, Display)] // Unwrap the newtype.  We'll remove "From" if implementing stricter validation.
 pub struct ModuleName(pub String); // Does not currently accomplish anything other than naming and documentation.  FUTURE: some validation rules would be nice -- see comments below about how, though.
 
@@ -92,4 +126,58 @@ mod tests {
             }"#]];
 		assert_eq_json_roundtrip::<CatalogModuleCapsule>(&expect);
 	}
+
+	#[test]
+	fn test_replay_roundtrip() {
+		let expect = expect![[r#"
+            {
+              "replay.v1": {
+                "plot": {
+                  "plot.v1": {
+                    "inputs": {
+                      "rootfs": "ware:tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9"
+                    },
+                    "steps": {},
+                    "outputs": {
+                      "out": "pipe::rootfs"
+                    }
+                  }
+                },
+                "runRecord": {
+                  "time": 1700000000,
+                  "results": {
+                    "out": "tar:4z9DCTxoKkStqXQRwtf9nimpfQQ36dbndDsAPCQgECfbXt3edanUrsVKCjE9TkX2v9"
+                  }
+                }
+              }
+            }"#]];
+		assert_eq_json_roundtrip::<ReplayCapsule>(&expect);
+	}
+
+	#[test]
+	fn test_run_record_spec_patches_roundtrip() {
+		let expect = expect![[r#"
+            {
+              "time": 1700000000,
+              "results": {},
+              "specPatches": [
+                [
+                  {
+                    "op": "replace",
+                    "path": "/hostname",
+                    "value": "patched"
+                  }
+                ]
+              ]
+            }"#]];
+		assert_eq_json_roundtrip::<RunRecord>(&expect);
+	}
+
+	#[test]
+	fn test_release_ref() {
+		let release_ref: CatalogReleaseRef = "warpsys.org/gawk:v5.1.1".parse().unwrap();
+		assert_eq!(release_ref.module_name.0, "warpsys.org/gawk");
+		assert_eq!(release_ref.release_name.0, "v5.1.1");
+		assert_eq!(release_ref.to_string(), "warpsys.org/gawk:v5.1.1");
+	}
 }
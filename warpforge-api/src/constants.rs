@@ -1,2 +1,6 @@
 pub const MAGIC_FILENAME_MODULE: &str = "module.wf";
 pub const MAGIC_FILENAME_PLOT: &str = "plot.wf";
+pub const MAGIC_FILENAME_CATALOG_MODULE: &str = "_module.json";
+pub const MAGIC_DIRNAME_CATALOG_RELEASES: &str = "_releases";
+pub const MAGIC_DIRNAME_CATALOG_REPLAYS: &str = "_replays";
+pub const MAGIC_METADATA_REPLAY: &str = "replay";
@@ -2,6 +2,7 @@ pub mod catalog;
 pub mod constants;
 pub mod content;
 pub mod formula;
+pub mod module;
 pub mod plot;
 
 #[cfg(test)]
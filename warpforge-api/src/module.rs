@@ -0,0 +1,44 @@
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::catalog::ModuleName;
+
+/// Contents of the `module.wf` file, which marks a directory as module.
+///
+/// A module is built by running the plot in the same directory (`plot.wf`).
+/// The results of that plot are released into the catalog under [Module::name].
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum ModuleCapsule {
+	#[serde(rename = "module.v1")]
+	V1(Module),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Module {
+	/// Name of the catalog module this module's plot results belong to.
+	pub name: ModuleName,
+	#[serde(default, skip_serializing_if = "IndexMap::is_empty")]
+	pub metadata: IndexMap<String, String>,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::test_common::assert_eq_json_roundtrip;
+	use expect_test::expect;
+
+	#[test]
+	fn test_roundtrip() {
+		let expect = expect![[r#"
+            {
+              "module.v1": {
+                "name": "warpsys.org/bash",
+                "metadata": {
+                  "homepage": "https://www.gnu.org/software/bash/"
+                }
+              }
+            }"#]];
+		assert_eq_json_roundtrip::<ModuleCapsule>(&expect);
+	}
+}
@@ -1,4 +1,6 @@
+pub mod cache;
 pub mod catalog;
+pub mod check;
 pub mod run;
 pub mod ware;
 
@@ -17,12 +19,18 @@ pub enum Subcommands {
 	/// subcommands for working with catalogs, warpforge's data labelling system.
 	Catalog(catalog::Cmd),
 
+	/// subcommands for inspecting and maintaining the cache of pulled container images.
+	Cache(cache::Cmd),
+
 	/// subcommands for working with wares and filesystems -- snapshotting, packing, unpacking, mirroring, etc.
 	Ware(ware::Cmd),
 
 	/// Run a module or formula.
 	Run(run::Cmd),
 
+	/// Validate formulas, modules and catalogs without running anything.
+	Check(check::Cmd),
+
 	/// subcommand to graph dependencies of given package. A dot file is emitted to stdout.
 	Graph(GraphCmd),
 }
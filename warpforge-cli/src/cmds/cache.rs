@@ -0,0 +1,160 @@
+use std::{path::PathBuf, str::FromStr};
+
+use oci_client::Reference;
+use oci_unpack::{cache, layout, snapshot, Platform};
+use warpforge_terminal::logln;
+
+use crate::{dab, Error};
+
+#[derive(clap::Args, Debug)]
+pub struct Cmd {
+	#[command(subcommand)]
+	pub subcommand: Subcommands,
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Subcommands {
+	/// list prints every cached image reference, with the platform it was pulled for and the size of its blobs.
+	/// Images which are unpacked into a snapshot are marked as "unpacked".
+	List,
+
+	/// remove drops an image reference from the cache (for all platforms).
+	/// Its blobs are kept until the next 'gc', snapshots of images no longer in the cache are removed.
+	Remove(RemoveCmdArgs),
+
+	/// verify checks every blob in the cache against its digest, and reports corrupt blobs.
+	Verify,
+
+	/// gc deletes all blobs which are not referenced by any cached image,
+	/// as well as the snapshots of images which are not cached anymore.
+	Gc,
+
+	/// import adds an image from an OCI image layout directory or a 'docker save' tarball to the cache,
+	/// so it can be used without access to a registry.
+	Import(ImportCmdArgs),
+
+	/// export writes a cached image into an OCI image layout directory.
+	Export(ExportCmdArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RemoveCmdArgs {
+	/// Image reference to remove, e.g. "docker.io/library/busybox:latest".
+	#[arg(value_parser = Reference::from_str)]
+	pub reference: Reference,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ImportCmdArgs {
+	/// OCI image layout directory or 'docker save' tarball.
+	pub path: PathBuf,
+
+	/// Reference to store the image as.
+	///
+	/// Selects the image, if the layout or tarball holds several.  Required for OCI image layouts;
+	/// tarballs holding a single image default to its first tag.
+	#[arg(long, value_parser = Reference::from_str)]
+	pub reference: Option<Reference>,
+
+	/// Platform the image is recorded for, e.g. "linux/arm64".  Defaults to linux on the current architecture.
+	#[arg(long, value_name = "OS/ARCH[/VARIANT]", value_parser = Platform::from_str)]
+	pub platform: Option<Platform>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ExportCmdArgs {
+	/// Image reference to export, e.g. "docker.io/library/busybox:latest".
+	#[arg(value_parser = Reference::from_str)]
+	pub reference: Reference,
+
+	/// OCI image layout directory to write to.  Created if it does not exist.
+	pub dest: PathBuf,
+
+	/// Platform of the cached image, e.g. "linux/arm64".  Defaults to linux on the current architecture.
+	#[arg(long, value_name = "OS/ARCH[/VARIANT]", value_parser = Platform::from_str)]
+	pub platform: Option<Platform>,
+}
+
+pub fn execute(cmd: &Cmd) -> Result<(), Error> {
+	let cache_dir = dab::image_cache_path()?;
+	let snapshot_dir = dab::snapshot_cache_path()?;
+	let map_err = |e: oci_unpack::Error| Error::ImageCache { cause: Box::new(e) };
+
+	match &cmd.subcommand {
+		Subcommands::List => {
+			let snapshots = snapshot::list(&snapshot_dir).map_err(map_err)?;
+			for image in cache::list(&cache_dir).map_err(map_err)? {
+				let unpacked = match snapshots.contains(&image.manifest_digest) {
+					true => " unpacked",
+					false => "",
+				};
+				logln!(
+					"{} {} {} {}{unpacked}",
+					image.reference,
+					image.platform,
+					image.manifest_digest,
+					image.size,
+				);
+			}
+		}
+		Subcommands::Remove(cmd) => {
+			let removed = cache::remove(&cache_dir, &cmd.reference).map_err(map_err)?;
+			if removed == 0 {
+				return Err(Error::ImageCache {
+					cause: format!("image not in cache: {}", cmd.reference).into(),
+				});
+			}
+			logln!("removed {removed} cache entries for {}", cmd.reference);
+			let removed_snapshots = snapshot::gc(&snapshot_dir, &cache_dir).map_err(map_err)?;
+			if removed_snapshots > 0 {
+				logln!("removed {removed_snapshots} snapshot(s)");
+			}
+		}
+		Subcommands::Verify => {
+			let corrupt = cache::verify(&cache_dir).map_err(map_err)?;
+			for err in &corrupt {
+				logln!("{err}");
+			}
+			if !corrupt.is_empty() {
+				return Err(Error::ImageCache {
+					cause: format!("found {} corrupt blob(s)", corrupt.len()).into(),
+				});
+			}
+		}
+		Subcommands::Gc => {
+			let result = cache::gc(&cache_dir).map_err(map_err)?;
+			let removed_snapshots = snapshot::gc(&snapshot_dir, &cache_dir).map_err(map_err)?;
+			logln!(
+				"removed {} blob(s), {} temporary file(s) and {removed_snapshots} snapshot(s), freed {} bytes",
+				result.removed_blobs,
+				result.removed_temp_files,
+				result.freed_bytes,
+			);
+		}
+		Subcommands::Import(cmd) => {
+			let platform = cmd.platform.clone().unwrap_or_else(Platform::current);
+			let reference = if cmd.path.is_dir() {
+				let Some(reference) = &cmd.reference else {
+					return Err(Error::InvalidArguments {
+						cause: "importing an OCI image layout requires '--reference'".into(),
+					});
+				};
+				layout::import_oci_layout(&cmd.path, &cache_dir, reference, &platform)
+					.map_err(map_err)?;
+				reference.clone()
+			} else {
+				let reference = cmd.reference.as_ref();
+				layout::import_docker_archive(&cmd.path, &cache_dir, reference, &platform)
+					.map_err(map_err)?
+			};
+			logln!("imported {reference}");
+		}
+		Subcommands::Export(cmd) => {
+			let platform = cmd.platform.clone().unwrap_or_else(Platform::current);
+			layout::export_oci_layout(&cache_dir, &cmd.reference, &platform, &cmd.dest)
+				.map_err(map_err)?;
+			logln!("exported {} to {}", cmd.reference, cmd.dest.display());
+		}
+	}
+	Ok(())
+}
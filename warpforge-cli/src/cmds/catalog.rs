@@ -1,3 +1,27 @@
+use std::{fmt, str::FromStr};
+
+use tempfile::TempDir;
+use warpforge_api::{
+	catalog::{CatalogRelease, CatalogReleaseRef, ItemName, ReplayCapsule},
+	constants::MAGIC_METADATA_REPLAY,
+	content::WareID,
+};
+use warpforge_executors::{
+	context::Context,
+	plot::{host_inputs, run_plot},
+	Output,
+};
+use warpforge_terminal::logln;
+
+use crate::{
+	cmds::run::ContainerArgs,
+	dab::{
+		self,
+		catalog::{FsHandle, Handle},
+	},
+	Error,
+};
+
 #[derive(clap::Args, Debug)]
 pub struct Cmd {
 	#[command(subcommand)]
@@ -11,12 +35,227 @@ pub enum Subcommands {
 	///
 	/// Optional flags to the command can cause additonal data to be reported with line-break delimiters, or cause the command to operate in JSON mode.
 	ReadItem(ReadItemCmdArgs),
-}
 
-use std::str::FromStr;
+	/// replay rebuilds a release from the plot recorded when it was published,
+	/// and compares the resulting WareIDs with the items of the release.
+	///
+	/// Each item is reported as matching, mismatching or missing.  Exits with an error if any item doesn't match.
+	Replay(ReplayCmdArgs),
+}
 
 #[derive(clap::Args, Debug)]
 pub struct ReadItemCmdArgs {
 	#[arg(value_parser = warpforge_api::catalog::CatalogRef::from_str)]
 	pub catalog_ref: warpforge_api::catalog::CatalogRef,
 }
+
+#[derive(clap::Args, Debug)]
+pub struct ReplayCmdArgs {
+	/// Release to replay, as "{moduleName}:{releaseName}".
+	#[arg(value_parser = warpforge_api::catalog::CatalogReleaseRef::from_str)]
+	pub release_ref: warpforge_api::catalog::CatalogReleaseRef,
+
+	#[command(flatten)]
+	pub container: ContainerArgs,
+}
+
+pub fn replay(cmd: &ReplayCmdArgs) -> Result<(), Error> {
+	let CatalogReleaseRef {
+		module_name,
+		release_name,
+	} = &cmd.release_ref;
+
+	let catalog_handle = FsHandle::new(dab::catalog_path()?);
+	let release = catalog_handle
+		.load_release(module_name, release_name)
+		.map_err(|e| Error::CatalogAccess { cause: e })?;
+	let Some(replay_cid) = release.metadata.get(MAGIC_METADATA_REPLAY) else {
+		return Err(Error::CatalogAccess {
+			cause: format!("release {} has no recorded replay", cmd.release_ref).into(),
+		});
+	};
+	let ReplayCapsule::V1(replay) = catalog_handle
+		.load_replay(module_name, replay_cid)
+		.map_err(|e| Error::CatalogAccess { cause: e })?;
+
+	// Replays recorded before plots with host inputs were refused.
+	let host_inputs = host_inputs(&replay.plot);
+	if !host_inputs.is_empty() {
+		return Err(Error::CatalogAccess {
+			cause: format!(
+				"replay of {} depends on inputs from the host: {}",
+				cmd.release_ref,
+				host_inputs.join(", ")
+			)
+			.into(),
+		});
+	}
+
+	let spec_patches = (replay.run_record.spec_patches.into_iter())
+		.map(serde_json::from_value)
+		.collect::<Result<_, _>>()
+		.map_err(|e| Error::CatalogAccess {
+			cause: format!(
+				"replay of {} has an invalid spec patch: {e}",
+				cmd.release_ref
+			)
+			.into(),
+		})?;
+
+	// Replays run in an empty directory: they can't depend on the state of the host.
+	let temp_dir = TempDir::new().map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
+	let context = Context {
+		output_path: Some(temp_dir.path().to_owned()),
+		..(cmd.container).context(temp_dir.path().to_owned(), spec_patches)?
+	};
+	let outputs = run_plot(replay.plot, &context)?;
+
+	logln!("replay of {}", cmd.release_ref);
+	let comparisons = compare_items(&release, &outputs);
+	for comparison in &comparisons {
+		logln!("  {comparison}");
+	}
+	check_comparisons(&comparisons)
+}
+
+/// Outcome of comparing a recorded item of a release with the outputs of its replay.
+#[derive(Debug, PartialEq)]
+enum ItemComparison<'a> {
+	Ok {
+		item_name: &'a ItemName,
+		recorded: &'a WareID,
+	},
+	Mismatch {
+		item_name: &'a ItemName,
+		recorded: &'a WareID,
+		replayed: WareID,
+	},
+	Missing {
+		item_name: &'a ItemName,
+		recorded: &'a WareID,
+	},
+}
+
+impl fmt::Display for ItemComparison<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ItemComparison::Ok {
+				item_name,
+				recorded,
+			} => write!(f, "ok       {item_name} {recorded}"),
+			ItemComparison::Mismatch {
+				item_name,
+				recorded,
+				replayed,
+			} => write!(
+				f,
+				"mismatch {item_name} recorded {recorded}, replayed {replayed}"
+			),
+			ItemComparison::Missing {
+				item_name,
+				recorded,
+			} => write!(
+				f,
+				"missing  {item_name} recorded {recorded}, not produced by replay"
+			),
+		}
+	}
+}
+
+fn compare_items<'a>(release: &'a CatalogRelease, outputs: &[Output]) -> Vec<ItemComparison<'a>> {
+	(release.items.iter())
+		.map(|(item_name, recorded)| {
+			let replayed = (outputs.iter())
+				.find(|output| output.name == item_name.to_string())
+				.map(|output| output.ware_id());
+			match replayed {
+				Some(replayed) if replayed == *recorded => ItemComparison::Ok {
+					item_name,
+					recorded,
+				},
+				Some(replayed) => ItemComparison::Mismatch {
+					item_name,
+					recorded,
+					replayed,
+				},
+				None => ItemComparison::Missing {
+					item_name,
+					recorded,
+				},
+			}
+		})
+		.collect()
+}
+
+/// Fail with [Error::ReplayMismatch] if any item didn't match.
+fn check_comparisons(comparisons: &[ItemComparison]) -> Result<(), Error> {
+	let mismatches = (comparisons.iter())
+		.filter(|comparison| !matches!(comparison, ItemComparison::Ok { .. }))
+		.count();
+	if mismatches > 0 {
+		return Err(Error::ReplayMismatch { count: mismatches });
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use warpforge_api::content::Packtype;
+	use warpforge_executors::Digest;
+
+	use super::*;
+
+	fn release(items: &[(&str, &str)]) -> CatalogRelease {
+		CatalogRelease {
+			release_name: "v1".parse().unwrap(),
+			items: (items.iter())
+				.map(|(name, ware_id)| (name.parse().unwrap(), ware_id.parse().unwrap()))
+				.collect(),
+			metadata: Default::default(),
+		}
+	}
+
+	fn output(name: &str, hash: &str) -> Output {
+		Output {
+			name: name.to_owned(),
+			digest: Digest::Sha384(hash.to_owned()),
+			packtype: Packtype("tar".to_owned()),
+		}
+	}
+
+	#[test]
+	fn compare_items_reports_ok_mismatch_and_missing() {
+		let release = release(&[
+			("same", "tar:aaa"),
+			("changed", "tar:bbb"),
+			("gone", "tar:ccc"),
+		]);
+		let outputs = [
+			output("changed", "xxx"),
+			output("same", "aaa"),
+			output("extra", "ddd"),
+		];
+
+		let comparisons = compare_items(&release, &outputs);
+		let lines: Vec<_> = comparisons.iter().map(ToString::to_string).collect();
+		assert_eq!(
+			lines,
+			[
+				"ok       same tar:aaa",
+				"mismatch changed recorded tar:bbb, replayed tar:xxx",
+				"missing  gone recorded tar:ccc, not produced by replay",
+			]
+		);
+
+		let err = check_comparisons(&comparisons).unwrap_err();
+		assert!(matches!(err, Error::ReplayMismatch { count: 2 }));
+		assert_eq!(err.code(), 18);
+	}
+
+	#[test]
+	fn matching_replay_succeeds() {
+		let release = release(&[("out", "tar:aaa")]);
+		let comparisons = compare_items(&release, &[output("out", "aaa")]);
+		assert!(check_comparisons(&comparisons).is_ok());
+	}
+}
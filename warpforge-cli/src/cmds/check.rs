@@ -0,0 +1,253 @@
+use std::{
+	env::current_dir,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use warpforge_api::constants::{
+	MAGIC_DIRNAME_CATALOG_RELEASES, MAGIC_FILENAME_CATALOG_MODULE, MAGIC_FILENAME_MODULE,
+	MAGIC_FILENAME_PLOT,
+};
+use warpforge_terminal::logln;
+use warpforge_validate::{
+	validate_catalog_module, validate_catalog_release, validate_formula, validate_module,
+	validate_plot,
+};
+
+use crate::{
+	cmds::Root,
+	diagnostics::{Diagnostics, DiagnosticsFormat},
+	Error,
+};
+
+#[derive(clap::Args, Debug)]
+pub struct Cmd {
+	/// Path to a file, module folder or catalog folder.
+	///
+	/// Files are checked by their name: 'module.wf', 'plot.wf', '_module.json'
+	/// and release files in '_releases' are recognized, anything else is checked as formula.
+	/// Folders are searched recursively: every module (containing a 'module.wf')
+	/// and every catalog module (containing a '_module.json') found is checked.
+	/// If no target is provided, check targets the current/working directory (cwd).
+	pub target: Option<PathBuf>,
+
+	/// Format used to report validation errors.
+	///
+	/// 'json' and 'sarif' write a single document to stdout after all files are checked.
+	#[arg(long, value_enum, default_value_t = DiagnosticsFormat::Human)]
+	pub diagnostics: DiagnosticsFormat,
+}
+
+pub fn execute(_cli: &Root, cmd: &Cmd) -> Result<(), Error> {
+	let target = match &cmd.target {
+		Some(target) => target.to_owned(),
+		None => current_dir().map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?,
+	};
+
+	let mut checker = Checker {
+		diagnostics: Diagnostics::new(cmd.diagnostics),
+		checked_files: 0,
+	};
+
+	let meta = fs::metadata(&target).map_err(|e| Error::InvalidArguments { cause: Box::new(e) })?;
+	if meta.is_file() {
+		checker.check_single_file(&target);
+	} else if meta.is_dir() {
+		checker.check_dir(&target);
+		if checker.checked_files == 0 {
+			return Err(Error::InvalidArguments {
+				cause: "invalid target: found no module or catalog to check".into(),
+			});
+		}
+	} else {
+		return Err(Error::InvalidArguments {
+			cause: "invalid target: 'check' requires an existing file or directory".into(),
+		});
+	}
+
+	let Checker {
+		diagnostics,
+		checked_files,
+	} = checker;
+	let count = diagnostics.count();
+	if diagnostics.format() == DiagnosticsFormat::Human {
+		logln!("checked {checked_files} file(s), found {count} error(s)");
+	}
+	(diagnostics.finish()).map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
+
+	if count > 0 {
+		return Err(Error::ValidationFailed { count });
+	}
+	Ok(())
+}
+
+struct Checker {
+	diagnostics: Diagnostics,
+	checked_files: usize,
+}
+
+impl Checker {
+	/// Read and validate a single file, reporting all problems found.
+	/// Returns the validated value, if the file is valid.
+	fn check_file<T>(
+		&mut self,
+		path: &Path,
+		validate: impl FnOnce(&str) -> warpforge_validate::Result<T>,
+	) -> Option<T> {
+		self.checked_files += 1;
+
+		let source = match fs::read_to_string(path) {
+			Ok(source) => source,
+			Err(err) => {
+				(self.diagnostics).report_message(path, format!("failed to read file: {err}"));
+				return None;
+			}
+		};
+
+		match validate(&source) {
+			Ok(validated) => Some(validated),
+			Err(err) => {
+				self.diagnostics.report(&err, &source, path);
+				None
+			}
+		}
+	}
+
+	/// Check a file given directly as target, picking the validation by file name.
+	fn check_single_file(&mut self, path: &Path) {
+		let file_name = path.file_name().unwrap_or_default();
+		let in_releases_dir = (path.parent())
+			.and_then(Path::file_name)
+			.is_some_and(|dir| dir == MAGIC_DIRNAME_CATALOG_RELEASES);
+
+		if file_name == MAGIC_FILENAME_MODULE {
+			self.check_file(path, validate_module);
+		} else if file_name == MAGIC_FILENAME_PLOT {
+			self.check_file(path, validate_plot);
+		} else if file_name == MAGIC_FILENAME_CATALOG_MODULE {
+			self.check_file(path, validate_catalog_module);
+		} else if in_releases_dir && path.extension().is_some_and(|ext| ext == "json") {
+			self.check_file(path, validate_catalog_release);
+		} else {
+			self.check_file(path, validate_formula);
+		}
+	}
+
+	fn check_dir(&mut self, dir: &Path) {
+		if dir.join(MAGIC_FILENAME_MODULE).is_file() {
+			self.check_module(dir);
+		}
+		if dir.join(MAGIC_FILENAME_CATALOG_MODULE).is_file() {
+			self.check_catalog_module(dir);
+		}
+
+		let entries = match fs::read_dir(dir) {
+			Ok(entries) => entries,
+			Err(err) => {
+				let message = format!("failed to read directory: {err}");
+				self.diagnostics.report_message(dir, message);
+				return;
+			}
+		};
+
+		// Sorted, so reports are in the same order on every run.
+		let mut subdirs: Vec<_> = (entries.filter_map(|entry| entry.ok()))
+			.filter(|entry| entry.file_type().is_ok_and(|kind| kind.is_dir()))
+			.filter(|entry| {
+				let name = entry.file_name();
+				let name = name.to_string_lossy();
+				// Catalog internals like '_releases' are checked as part of their module.
+				!name.starts_with('.') && !name.starts_with('_')
+			})
+			.map(|entry| entry.path())
+			.collect();
+		subdirs.sort();
+
+		for subdir in subdirs {
+			self.check_dir(&subdir);
+		}
+	}
+
+	fn check_module(&mut self, dir: &Path) {
+		self.check_file(&dir.join(MAGIC_FILENAME_MODULE), validate_module);
+
+		let plot_path = dir.join(MAGIC_FILENAME_PLOT);
+		if !plot_path.is_file() {
+			let message = format!("module is missing file '{MAGIC_FILENAME_PLOT}'");
+			(self.diagnostics).report_message(dir.join(MAGIC_FILENAME_MODULE), message);
+			return;
+		}
+		self.check_file(&plot_path, validate_plot);
+	}
+
+	fn check_catalog_module(&mut self, dir: &Path) {
+		let module_path = dir.join(MAGIC_FILENAME_CATALOG_MODULE);
+		let module = self.check_file(&module_path, validate_catalog_module);
+
+		let releases_dir = dir.join(MAGIC_DIRNAME_CATALOG_RELEASES);
+		let mut release_files: Vec<_> = match fs::read_dir(&releases_dir) {
+			Ok(entries) => (entries.filter_map(|entry| entry.ok()))
+				.map(|entry| entry.path())
+				.filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+				.collect(),
+			Err(_) => Vec::with_capacity(0),
+		};
+		release_files.sort();
+
+		for release_path in &release_files {
+			let Some(validated) = self.check_file(release_path, validate_catalog_release) else {
+				continue;
+			};
+			let release_name = &validated.release.release_name.0;
+			let file_stem = release_path.file_stem().unwrap_or_default();
+			if file_stem.to_string_lossy() != *release_name {
+				let message = format!(
+					"release name '{release_name}' does not match its file name '{}'",
+					file_stem.to_string_lossy(),
+				);
+				self.diagnostics.report_message(release_path, message);
+			}
+		}
+
+		let Some(validated) = module else {
+			return;
+		};
+		let warpforge_api::catalog::CatalogModuleCapsule::V1(module) = validated.module;
+		for release_name in module.releases.keys() {
+			let release_path = releases_dir.join(format!("{release_name}.json"));
+			if !release_files.contains(&release_path) {
+				let message = format!("release '{release_name}' has no release file");
+				self.diagnostics.report_message(&module_path, message);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn single_files_are_checked_by_name() {
+		let dir = tempfile::tempdir().unwrap();
+		let module_path = dir.path().join(MAGIC_FILENAME_MODULE);
+		fs::write(
+			&module_path,
+			r#"{"module.v1": {"name": "warpsys.org/bash"}}"#,
+		)
+		.unwrap();
+		let catalog_module_path = dir.path().join(MAGIC_FILENAME_CATALOG_MODULE);
+		let catalog_module =
+			r#"{"catalogmodule.v1": {"name": "warpsys.org/bash", "releases": {}, "metadata": {}}}"#;
+		fs::write(&catalog_module_path, catalog_module).unwrap();
+
+		let mut checker = Checker {
+			diagnostics: Diagnostics::new(DiagnosticsFormat::Json),
+			checked_files: 0,
+		};
+		checker.check_single_file(&module_path);
+		checker.check_single_file(&catalog_module_path);
+		assert_eq!(checker.checked_files, 2);
+		assert_eq!(checker.diagnostics.count(), 0);
+	}
+}
@@ -1,20 +1,41 @@
 use std::{
 	env::current_dir,
-	ffi::OsStr,
 	fs::{self, File},
 	io::BufReader,
 	path::{Path, PathBuf},
+	str::FromStr,
+	sync::OnceLock,
+	time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use oci_unpack::{auth::DockerConfig, Mirror, Platform};
 use warpforge_api::{
-	constants::{MAGIC_FILENAME_MODULE, MAGIC_FILENAME_PLOT},
+	catalog::{CatalogRelease, ItemName, ReleaseName, Replay, ReplayCapsule, RunRecord},
+	constants::{MAGIC_FILENAME_MODULE, MAGIC_FILENAME_PLOT, MAGIC_METADATA_REPLAY},
+	module::{Module, ModuleCapsule},
 	plot::PlotCapsule,
 };
-use warpforge_executors::{context::Context, formula::run_formula, plot::run_plot, Digest};
-use warpforge_terminal::{log_global, logln, Level};
-use warpforge_validate::validate_formula;
+use warpforge_executors::{
+	cancel::CancellationToken,
+	context::{Context, DebugShell},
+	formula::run_formula,
+	limits::{self, Limits},
+	plot::{host_inputs, pin_plot, run_plot},
+	runtime::Runtime,
+	Digest, Output,
+};
+use warpforge_terminal::{logln, warn};
+use warpforge_validate::{validate_formula, validate_module, validate_plot};
 
-use crate::{cmds::Root, Error};
+use crate::{
+	cmds::Root,
+	dab::{
+		self,
+		catalog::{FsHandle, Handle},
+	},
+	diagnostics::{Destination, Diagnostics, DiagnosticsFormat},
+	Error,
+};
 
 #[derive(clap::Args, Debug)]
 pub struct Cmd {
@@ -24,9 +45,149 @@ pub struct Cmd {
 	/// as a module. An error is reported if no module is found.
 	pub target: Option<PathBuf>,
 
-	/// Container runtime used to run OCI bundles.
-	#[arg(long, default_value = "runc")]
-	pub runtime: PathBuf,
+	/// Format used to report validation errors.
+	///
+	/// 'json' and 'sarif' write a single document after validation,
+	/// to '--diagnostics-file' or else to stderr, as stdout is used by the run.
+	#[arg(long, value_enum, default_value_t = DiagnosticsFormat::Human)]
+	pub diagnostics: DiagnosticsFormat,
+
+	/// File to write the document of '--diagnostics json|sarif' to.
+	#[arg(long, value_name = "FILE")]
+	pub diagnostics_file: Option<PathBuf>,
+
+	/// Publish the outputs of a module as new catalog release with this name.
+	///
+	/// The release is added to the module named in 'module.wf', its wares are
+	/// stored in the local warehouse and the plot is recorded for replaying it.
+	#[arg(long)]
+	pub release: Option<ReleaseName>,
+
+	#[command(flatten)]
+	pub container: ContainerArgs,
+
+	/// JSON Patch file (RFC 6902) applied to the OCI spec of every container.
+	///
+	/// Patches are applied in the order given, after the ones of Warpforge itself.
+	/// Releases record the patches, so replays apply them again.
+	#[arg(long = "spec-patch", value_name = "FILE")]
+	pub spec_patches: Vec<PathBuf>,
+
+	/// Open an interactive shell in the container of a formula after its action ran:
+	/// "on-failure" (the default when given without value), "always" or "never".
+	///
+	/// The shell sees the same mounts, environment and root filesystem as the action,
+	/// including the changes made by it.  Requires the image to provide '/bin/sh'.
+	#[arg(
+		long,
+		value_name = "WHEN",
+		num_args = 0..=1,
+		require_equals = true,
+		default_value = "never",
+		default_missing_value = "on-failure",
+		value_parser = DebugShell::from_str
+	)]
+	pub debug_shell: DebugShell,
+}
+
+/// Options of running containers, shared by `run` and `catalog replay`.
+#[derive(clap::Args, Debug)]
+pub struct ContainerArgs {
+	/// Container runtime used to run OCI bundles: "runc", "crun" or "gvisor".
+	///
+	/// A runtime executable can be given as "PROFILE=PATH", or as path whose file name names the runtime.
+	#[arg(long, default_value = "runc", value_parser = Runtime::from_str)]
+	pub runtime: Runtime,
+
+	/// Never contact a container registry.
+	///
+	/// Images must already be in the image cache, and tags must have been resolved by an earlier run.
+	#[arg(long)]
+	pub offline: bool,
+
+	/// Pull images of a registry through mirrors, e.g. "docker.io=mirror.example.com".
+	///
+	/// Several mirrors can be given separated by commas; they are tried in order before the registry itself.
+	/// A trailing "!" never contacts the registry itself: "docker.io=mirror.example.com!".
+	#[arg(long = "registry-mirror", value_name = "REGISTRY=MIRROR")]
+	pub registry_mirrors: Vec<Mirror>,
+
+	/// Platform to select from multi-arch images, e.g. "linux/arm64" or "linux/arm/v7".
+	///
+	/// Defaults to linux on the architecture Warpforge runs on.
+	#[arg(long, value_name = "OS/ARCH[/VARIANT]", value_parser = Platform::from_str)]
+	pub platform: Option<Platform>,
+
+	/// Unpack images for every container, instead of mounting a cached snapshot of them with overlayfs.
+	///
+	/// Needed if the kernel or the container runtime can't mount overlayfs as root filesystem (e.g. rootless).
+	#[arg(long)]
+	pub no_snapshots: bool,
+
+	#[command(flatten)]
+	pub limits: LimitArgs,
+}
+
+impl ContainerArgs {
+	/// The context to run containers with these options, mounting host inputs relative to `mount_path`.
+	pub fn context(
+		&self,
+		mount_path: PathBuf,
+		spec_patches: Vec<json_patch::Patch>,
+	) -> Result<Context, Error> {
+		Ok(Context {
+			runtime: self.runtime.to_owned(),
+			mount_path: Some(mount_path),
+			image_cache: Some(dab::image_cache_path()?),
+			snapshot_cache: (!self.no_snapshots)
+				.then(dab::snapshot_cache_path)
+				.transpose()?,
+			offline: self.offline,
+			docker_config: DockerConfig::default_path(),
+			registry_mirrors: self.registry_mirrors.clone(),
+			platform: self.platform.clone(),
+			spec_patches,
+			limits: self.limits.limits(),
+			cancellation: cancel_on_ctrl_c()?,
+			..Default::default()
+		})
+	}
+}
+
+/// Resource limits of containers.
+#[derive(clap::Args, Debug)]
+pub struct LimitArgs {
+	/// Kill containers running longer than this, e.g. "90s", "15m" or "1h30m".
+	#[arg(long, value_parser = limits::parse_duration)]
+	pub timeout: Option<Duration>,
+
+	/// Memory limit of containers, e.g. "512M" or "2G".  Requires cgroups.
+	#[arg(long, value_parser = limits::parse_size)]
+	pub memory: Option<u64>,
+
+	/// Number of CPUs containers may use, e.g. "2" or "0.5".  Requires cgroups.
+	#[arg(long, value_parser = limits::parse_cpus)]
+	pub cpus: Option<f64>,
+
+	/// Maximum number of processes and threads in containers.  Requires cgroups.
+	#[arg(long)]
+	pub pids: Option<u64>,
+
+	/// Maximum number of open files in containers.
+	#[arg(long, default_value_t = limits::DEFAULT_OPEN_FILES)]
+	pub open_files: u64,
+}
+
+impl LimitArgs {
+	pub fn limits(&self) -> Limits {
+		Limits {
+			timeout: self.timeout,
+			memory: self.memory,
+			cpus: self.cpus,
+			pids: self.pids,
+			open_files: Some(self.open_files),
+		}
+	}
 }
 
 pub fn execute(_cli: &Root, cmd: &Cmd) -> Result<(), Error> {
@@ -39,6 +200,11 @@ pub fn execute(_cli: &Root, cmd: &Cmd) -> Result<(), Error> {
 	if meta.is_dir() {
 		execute_module(cmd, target)
 	} else if meta.is_file() {
+		if cmd.release.is_some() {
+			return Err(Error::InvalidArguments {
+				cause: "'--release' can only be used when running a module".into(),
+			});
+		}
 		execute_formula(cmd, target)
 	} else {
 		Err(Error::InvalidArguments {
@@ -48,42 +214,214 @@ pub fn execute(_cli: &Root, cmd: &Cmd) -> Result<(), Error> {
 }
 
 fn execute_module(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
-	if !path.as_ref().join(MAGIC_FILENAME_MODULE).is_file() {
-		return Err(Error::InvalidArguments {
-			cause: format!(
-				"invalid target: directory does not contain file '{MAGIC_FILENAME_MODULE}'",
-			)
-			.into(),
-		});
-	}
+	let (module, plot) = load_module(cmd, &path)?;
 
-	let plot_path = path.as_ref().join(MAGIC_FILENAME_PLOT);
-	let file = File::open(plot_path).map_err(|e| Error::InvalidArguments { cause: Box::new(e) })?;
-	let reader = BufReader::new(file);
-	let plot: PlotCapsule =
-		serde_json::from_reader(reader).map_err(|e| Error::InvalidArguments {
-			cause: format!("invalid plot file: {e}").into(),
-		})?;
+	let catalog_handle = FsHandle::new(dab::catalog_path()?);
+	if let Some(release_name) = &cmd.release {
+		// Check before running, so we don't waste a run we couldn't publish.
+		if catalog_handle.has_release(&module.name, release_name) {
+			return Err(Error::CatalogReleaseExists {
+				module_name: module.name,
+				release_name: release_name.clone(),
+			});
+		}
+	}
 
 	let parent = parent(path)?;
+	let warehouse = match cmd.release {
+		Some(_) => Some(dab::warehouse_path()?),
+		None => None,
+	};
 	let context = Context {
-		runtime: cmd.runtime.to_owned(),
-		mount_path: Some(parent),
-		..Default::default()
+		warehouse,
+		debug_shell: cmd.debug_shell,
+		..(cmd.container).context(parent, load_spec_patches(&cmd.spec_patches)?)?
+	};
+	// Releases record the plot for replays, which must use the same images as this run.
+	let plot = match cmd.release {
+		Some(_) => pin_plot(&plot, &context)?,
+		None => plot,
 	};
-	let outputs = run_plot(plot, &context)?;
+	let outputs = run_plot(plot.clone(), &context)?;
 
-	for output in outputs {
-		let warpforge_executors::Output {
+	logln!("module '{}'", module.name);
+	for output in &outputs {
+		let Output {
 			name,
 			digest: Digest::Sha384(digest),
+			..
 		} = output;
-		logln!("sha384:{digest} {name}");
+		logln!("  sha384:{digest} {name}");
+	}
+
+	if let Some(release_name) = &cmd.release {
+		publish_release(
+			&catalog_handle,
+			&module,
+			release_name,
+			&plot,
+			&outputs,
+			&context.spec_patches,
+		)?;
+		logln!("published release '{}:{release_name}'", module.name);
 	}
 
 	Ok(())
 }
 
+/// Add a release of the outputs of a run to the catalog, with a replay of `plot`,
+/// which should have been pinned with [pin_plot] before running it.
+fn publish_release(
+	catalog_handle: &dyn Handle,
+	module: &Module,
+	release_name: &ReleaseName,
+	plot: &PlotCapsule,
+	outputs: &[Output],
+	spec_patches: &[json_patch::Patch],
+) -> Result<(), Error> {
+	let time = (SystemTime::now().duration_since(UNIX_EPOCH))
+		.map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?
+		.as_secs();
+	let run_record = RunRecord {
+		time,
+		results: (outputs.iter())
+			.map(|output| {
+				let item_name: ItemName = output.name.parse().unwrap(); // Infallible.
+				(item_name, output.ware_id())
+			})
+			.collect(),
+		spec_patches: (spec_patches.iter())
+			.map(|patch| serde_json::to_value(patch).unwrap()) // Patches always serialize.
+			.collect(),
+	};
+	let mut release = CatalogRelease {
+		release_name: release_name.clone(),
+		items: run_record.results.clone(),
+		metadata: Default::default(),
+	};
+
+	let host_inputs = host_inputs(plot);
+	if host_inputs.is_empty() {
+		let replay = ReplayCapsule::V1(Replay {
+			plot: plot.clone(),
+			run_record,
+		});
+		let replay_cid = (catalog_handle.write_replay(&module.name, &replay))
+			.map_err(|e| Error::CatalogAccess { cause: e })?;
+		(release.metadata).insert(MAGIC_METADATA_REPLAY.to_owned(), replay_cid);
+	} else {
+		// Replays can't reproduce files of the host they ran on.
+		warn!(
+			"not recording a replay: the plot has inputs from the host: {}",
+			host_inputs.join(", ")
+		);
+	}
+
+	(catalog_handle.write_release(module, &release)).map_err(|e| Error::CatalogAccess { cause: e })
+}
+
+/// Cancel the run when Ctrl-C is pressed, which kills running containers and removes their files.
+/// Pressing Ctrl-C again exits immediately.
+///
+/// The handler is installed once per process; later calls return the same token.
+pub fn cancel_on_ctrl_c() -> Result<CancellationToken, Error> {
+	static CANCELLATION: OnceLock<Result<CancellationToken, String>> = OnceLock::new();
+	let cancellation = CANCELLATION.get_or_init(|| {
+		let cancellation = CancellationToken::default();
+		let token = cancellation.clone();
+		ctrlc::set_handler(move || {
+			if token.is_cancelled() {
+				std::process::exit(130);
+			}
+			logln!("cancelling, press Ctrl-C again to exit immediately");
+			token.cancel();
+		})
+		.map_err(|e| e.to_string())?;
+		Ok(cancellation)
+	});
+	(cancellation.clone()).map_err(|e| Error::BizarreEnvironment { cause: e.into() })
+}
+
+/// Read the JSON Patch files given with '--spec-patch'.
+fn load_spec_patches(paths: &[PathBuf]) -> Result<Vec<json_patch::Patch>, Error> {
+	(paths.iter())
+		.map(|path| {
+			let file = File::open(path).map_err(|e| Error::InvalidArguments {
+				cause: format!("failed to open spec patch {}: {e}", path.display()).into(),
+			})?;
+			serde_json::from_reader(BufReader::new(file)).map_err(|e| Error::InvalidArguments {
+				cause: format!("invalid spec patch {}: {e}", path.display()).into(),
+			})
+		})
+		.collect()
+}
+
+/// Diagnostics of validating the files of a run.
+fn diagnostics(cmd: &Cmd) -> Diagnostics {
+	let destination = match &cmd.diagnostics_file {
+		Some(path) => Destination::File(path.clone()),
+		None => Destination::Stderr,
+	};
+	Diagnostics::new(cmd.diagnostics).with_destination(destination)
+}
+
+fn finish_diagnostics(diagnostics: Diagnostics) -> Result<(), Error> {
+	diagnostics.finish().map_err(|err| Error::InvalidArguments {
+		cause: format!("failed to write diagnostics: {err}").into(),
+	})
+}
+
+/// Read and validate the module and plot files in the module directory at `path`.
+///
+/// Both files are validated before failing, so all their errors end up in one report.
+fn load_module(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(Module, PlotCapsule), Error> {
+	let module_path = path.as_ref().join(MAGIC_FILENAME_MODULE);
+	if !module_path.is_file() {
+		return Err(Error::InvalidArguments {
+			cause: format!(
+				"invalid target: directory does not contain file '{MAGIC_FILENAME_MODULE}'",
+			)
+			.into(),
+		});
+	}
+	let plot_path = path.as_ref().join(MAGIC_FILENAME_PLOT);
+
+	let module_source = fs::read_to_string(&module_path).map_err(|err| {
+		let cause = format!("failed to read module file: {err}").into();
+		Error::InvalidArguments { cause }
+	})?;
+	let plot_source = fs::read_to_string(&plot_path).map_err(|err| {
+		let cause = format!("failed to read plot file: {err}").into();
+		Error::InvalidArguments { cause }
+	})?;
+
+	let module_result = validate_module(&module_source);
+	let plot_result = validate_plot(&plot_source);
+	let mut diagnostics = diagnostics(cmd);
+	if let Err(err) = &module_result {
+		diagnostics.report(err, &module_source, &module_path);
+	}
+	if let Err(err) = &plot_result {
+		diagnostics.report(err, &plot_source, &plot_path);
+	}
+	finish_diagnostics(diagnostics)?;
+
+	let module = match module_result {
+		Ok(validated) => {
+			let ModuleCapsule::V1(module) = validated.module;
+			module
+		}
+		Err(err) => {
+			let cause = format!("invalid module file: {err}").into();
+			return Err(Error::InvalidArguments { cause });
+		}
+	};
+	let plot = plot_result.map_err(|err| Error::InvalidArguments {
+		cause: format!("invalid plot file: {err}").into(),
+	})?;
+	Ok((module, plot.plot))
+}
+
 fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 	let source = fs::read_to_string(&path).map_err(|err| {
 		let cause = format!("failed to read formula file: {err}").into();
@@ -91,10 +429,14 @@ fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 	})?;
 
 	let result = validate_formula(&source);
+	let mut diagnostics = diagnostics(cmd);
+	if let Err(err) = &result {
+		diagnostics.report(err, &source, &path);
+	}
+	finish_diagnostics(diagnostics)?;
 	let validated_formula = match result {
 		Ok(formula) => formula,
 		Err(err) => {
-			display_error(&err, &source, &path);
 			let cause = format!("invalid formula file: {err}").into();
 			return Err(Error::InvalidArguments { cause });
 		}
@@ -102,16 +444,16 @@ fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 
 	let parent = parent(&path)?;
 	let context = Context {
-		runtime: cmd.runtime.to_owned(),
-		mount_path: Some(parent),
-		..Default::default()
+		debug_shell: cmd.debug_shell,
+		..(cmd.container).context(parent, load_spec_patches(&cmd.spec_patches)?)?
 	};
 	let outputs = run_formula(validated_formula.formula, &context)?;
 
 	for output in outputs {
-		let warpforge_executors::Output {
+		let Output {
 			name,
 			digest: Digest::Sha384(digest),
+			..
 		} = output;
 		logln!("sha384:{digest} {name}");
 	}
@@ -119,85 +461,6 @@ fn execute_formula(cmd: &Cmd, path: impl AsRef<Path>) -> Result<(), Error> {
 	Ok(())
 }
 
-fn display_error(err: &warpforge_validate::Error, source: &str, path: impl AsRef<Path>) {
-	use ariadne::{ColorGenerator, IndexType, Label, Report, ReportKind, Source};
-
-	let warpforge_validate::Error::Invalid { errors } = err;
-
-	let file_name = (path.as_ref().file_name())
-		.and_then(OsStr::to_str)
-		.unwrap_or("");
-	let color_primary = ColorGenerator::new().next();
-
-	let trailing_errors: Vec<_> = (errors.iter())
-		.filter(|err| err.is_trailing_comma())
-		.collect();
-	if !trailing_errors.is_empty() {
-		let first_span = (trailing_errors.iter())
-			.filter_map(|err| err.span(source))
-			.next()
-			.unwrap_or_default();
-		let mut report = Report::build(ReportKind::Error, (file_name, first_span))
-			.with_config(ariadne::Config::default().with_index_type(IndexType::Byte))
-			.with_message("found trailing comma(s)");
-		for trailing in trailing_errors {
-			let Some(span) = trailing.span(source) else {
-				continue;
-			};
-			report = report.with_label(
-				Label::new((file_name, span))
-					.with_message("trailing comma")
-					.with_color(color_primary),
-			);
-		}
-
-		print_ariadne_report(report.finish(), (file_name, Source::from(source)));
-	}
-
-	for err in errors.iter().filter(|err| !err.is_trailing_comma()) {
-		let span = err.span(source);
-		let mut report = Report::build(
-			ReportKind::Error,
-			(file_name, span.clone().unwrap_or_default()),
-		)
-		.with_config(ariadne::Config::default().with_index_type(IndexType::Byte))
-		.with_message(format!("{err}"));
-
-		if let Some(span) = span {
-			if span == (0..0) {
-				continue;
-			}
-			let label = err.label().unwrap_or("here");
-			report = report.with_label(
-				Label::new((file_name, span))
-					.with_message(label)
-					.with_color(color_primary),
-			);
-			if let Some(note) = err.note() {
-				report = report.with_note(note);
-			}
-		}
-
-		print_ariadne_report(report.finish(), (file_name, Source::from(source)));
-	}
-}
-
-fn print_ariadne_report<S, C>(report: ariadne::Report<'_, S>, cache: C)
-where
-	S: ariadne::Span,
-	C: ariadne::Cache<S::SourceId>,
-{
-	let mut message = Vec::new();
-	let result = report.write(cache, &mut message);
-	if result.is_err() {
-		return;
-	}
-	message.push(b'\n');
-	if let Ok(message) = String::from_utf8(message) {
-		log_global(Level::Error, message);
-	}
-}
-
 fn parent(path: impl AsRef<Path>) -> Result<PathBuf, Error> {
 	let parent = if path.as_ref().is_absolute() {
 		path.as_ref().parent().map(ToOwned::to_owned)
@@ -213,3 +476,79 @@ fn parent(path: impl AsRef<Path>) -> Result<PathBuf, Error> {
 		cause: "could not get parent after successfully accessing child-path".into(), // has to be race condition
 	})
 }
+
+#[cfg(test)]
+mod tests {
+	use warpforge_api::{content::Packtype, module::Module, plot::Plot};
+
+	use super::*;
+
+	#[test]
+	fn ctrl_c_handler_is_installed_once() {
+		// Replays of catalog releases run plots, which also ask for the token, possibly concurrently.
+		let threads: Vec<_> = (0..8)
+			.map(|_| std::thread::spawn(|| cancel_on_ctrl_c().map_err(|e| e.to_string())))
+			.collect();
+		for thread in threads {
+			assert!(!thread.join().unwrap().unwrap().is_cancelled());
+		}
+	}
+
+	#[test]
+	fn publish_release_records_replay() {
+		let dir = tempfile::tempdir().unwrap();
+		let handle = FsHandle::new(dir.path());
+		let module = Module {
+			name: "warpforge.io/example".parse().unwrap(),
+			metadata: Default::default(),
+		};
+		let release_name: ReleaseName = "v1".parse().unwrap();
+		let plot = PlotCapsule::V1(Plot {
+			inputs: Default::default(),
+			steps: Default::default(),
+			outputs: Default::default(),
+		});
+		let outputs = [Output {
+			name: "out".to_owned(),
+			digest: Digest::Sha384("abc".to_owned()),
+			packtype: Packtype("tar".to_owned()),
+		}];
+
+		publish_release(&handle, &module, &release_name, &plot, &outputs, &[]).unwrap();
+
+		assert!(handle.has_release(&module.name, &release_name));
+		let release = handle.load_release(&module.name, &release_name).unwrap();
+		let item: ItemName = "out".parse().unwrap();
+		assert_eq!(release.items[&item].to_string(), "tar:abc");
+
+		let replay_cid = &release.metadata[MAGIC_METADATA_REPLAY];
+		let ReplayCapsule::V1(replay) = handle.load_replay(&module.name, replay_cid).unwrap();
+		assert_eq!(replay.run_record.results, release.items);
+
+		let err = publish_release(&handle, &module, &release_name, &plot, &outputs, &[]);
+		assert!(matches!(err, Err(Error::CatalogAccess { .. })));
+	}
+
+	#[test]
+	fn publish_release_skips_replay_of_host_inputs() {
+		let dir = tempfile::tempdir().unwrap();
+		let handle = FsHandle::new(dir.path());
+		let module = Module {
+			name: "warpforge.io/example".parse().unwrap(),
+			metadata: Default::default(),
+		};
+		let release_name: ReleaseName = "v1".parse().unwrap();
+		let plot = PlotCapsule::V1(Plot {
+			inputs: [("src".parse().unwrap(), "mount:ro:/src".parse().unwrap())]
+				.into_iter()
+				.collect(),
+			steps: Default::default(),
+			outputs: Default::default(),
+		});
+
+		publish_release(&handle, &module, &release_name, &plot, &[], &[]).unwrap();
+
+		let release = handle.load_release(&module.name, &release_name).unwrap();
+		assert!(!release.metadata.contains_key(MAGIC_METADATA_REPLAY));
+	}
+}
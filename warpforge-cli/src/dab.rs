@@ -1 +1,33 @@
+use std::{env, path::PathBuf};
+
+use crate::Error;
+
 pub mod catalog;
+
+/// Directory holding the user's catalogs and warehouse: `$HOME/.warphome`.
+//TODO: check for a root workspace above $CWD before $HOME/.warphome
+pub fn warphome() -> Result<PathBuf, Error> {
+	let user_home =
+		env::var("HOME").map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
+	Ok(PathBuf::from(user_home).join(".warphome"))
+}
+
+/// Path of the catalog that releases are read from and written to.
+pub fn catalog_path() -> Result<PathBuf, Error> {
+	Ok(warphome()?.join("catalogs/warpsys"))
+}
+
+/// Path of the cache of pulled container images.
+pub fn image_cache_path() -> Result<PathBuf, Error> {
+	Ok(warphome()?.join("images"))
+}
+
+/// Path of the cache of unpacked container images.
+pub fn snapshot_cache_path() -> Result<PathBuf, Error> {
+	Ok(warphome()?.join("snapshots"))
+}
+
+/// Path of the local warehouse, storing wares by their hash.
+pub fn warehouse_path() -> Result<PathBuf, Error> {
+	Ok(warphome()?.join("warehouse"))
+}
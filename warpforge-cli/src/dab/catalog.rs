@@ -1,9 +1,14 @@
-use std::fs::File;
-use std::io::BufReader;
+use std::fs::{self, File};
+use std::io::{BufReader, Write};
 use std::path::{Path, PathBuf};
 
-use warpforge_api::catalog::{CatalogModule, CatalogRelease};
+use sha2::{Digest, Sha384};
+use warpforge_api::catalog::{CatalogModule, CatalogModuleCapsule, CatalogRelease, ReplayCapsule};
 use warpforge_api::catalog::{ModuleName, ReleaseName};
+use warpforge_api::constants::{
+	MAGIC_DIRNAME_CATALOG_RELEASES, MAGIC_DIRNAME_CATALOG_REPLAYS, MAGIC_FILENAME_CATALOG_MODULE,
+};
+use warpforge_api::module::Module;
 
 use std::error::Error as UndertypedError;
 
@@ -17,6 +22,28 @@ pub trait Handle {
 		module_name: &ModuleName,
 		release_name: &ReleaseName,
 	) -> Result<CatalogRelease, Box<dyn UndertypedError>>;
+	fn has_release(&self, module_name: &ModuleName, release_name: &ReleaseName) -> bool;
+
+	/// Add a new release to the catalog module of `module`, creating the catalog module if it doesn't exist yet.
+	fn write_release(
+		&self,
+		module: &Module,
+		release: &CatalogRelease,
+	) -> Result<(), Box<dyn UndertypedError>>;
+
+	fn load_replay(
+		&self,
+		module_name: &ModuleName,
+		cid: &str,
+	) -> Result<ReplayCapsule, Box<dyn UndertypedError>>;
+
+	/// Store the information needed to replay one of a module's releases.
+	/// Returns the CID (v1, base32) under which the replay was stored.
+	fn write_replay(
+		&self,
+		module_name: &ModuleName,
+		replay: &ReplayCapsule,
+	) -> Result<String, Box<dyn UndertypedError>>;
 }
 
 pub struct FsHandle {
@@ -29,6 +56,20 @@ impl FsHandle {
 			root_path: path.as_ref().to_path_buf(),
 		}
 	}
+
+	fn release_path(&self, module_name: &ModuleName, release_name: &ReleaseName) -> PathBuf {
+		self.root_path
+			.join(&module_name.0)
+			.join(MAGIC_DIRNAME_CATALOG_RELEASES)
+			.join(release_name.0.clone() + ".json")
+	}
+
+	fn replay_path(&self, module_name: &ModuleName, cid: &str) -> PathBuf {
+		self.root_path
+			.join(&module_name.0)
+			.join(MAGIC_DIRNAME_CATALOG_REPLAYS)
+			.join(cid.to_owned() + ".json")
+	}
 }
 
 impl Handle for FsHandle {
@@ -36,10 +77,12 @@ impl Handle for FsHandle {
 		&self,
 		module_name: &ModuleName,
 	) -> Result<CatalogModule, Box<dyn UndertypedError>> {
-		let catmod_index_file_path: PathBuf =
-			self.root_path.join(&module_name.0).join("_module.json");
+		let catmod_index_file_path: PathBuf = self
+			.root_path
+			.join(&module_name.0)
+			.join(MAGIC_FILENAME_CATALOG_MODULE);
 		let reader = BufReader::new(File::open(catmod_index_file_path)?);
-		let result = serde_json::from_reader(reader)?;
+		let CatalogModuleCapsule::V1(result) = serde_json::from_reader(reader)?;
 		// TODO validate the name doesn't conflict with the path we took to get here.
 		Ok(result)
 	}
@@ -49,14 +92,230 @@ impl Handle for FsHandle {
 		module_name: &ModuleName,
 		release_name: &ReleaseName,
 	) -> Result<CatalogRelease, Box<dyn UndertypedError>> {
-		let catrel_file_path: PathBuf = self
-			.root_path
-			.join(&module_name.0)
-			.join("_releases")
-			.join(release_name.0.clone() + ".json");
+		let catrel_file_path = self.release_path(module_name, release_name);
 		let reader = BufReader::new(File::open(catrel_file_path)?);
 		let result = serde_json::from_reader(reader)?;
 		// TODO validate the name doesn't conflict with the path we took to get here.
 		Ok(result)
 	}
+
+	fn has_release(&self, module_name: &ModuleName, release_name: &ReleaseName) -> bool {
+		self.release_path(module_name, release_name).exists()
+	}
+
+	fn write_release(
+		&self,
+		module: &Module,
+		release: &CatalogRelease,
+	) -> Result<(), Box<dyn UndertypedError>> {
+		let module_name = &module.name;
+		let module_dir = self.root_path.join(&module_name.0);
+		fs::create_dir_all(&module_dir)?;
+		// Serializes concurrent releases of the same module, so neither of them
+		// loses its entry in `_module.json`.  Released when the file is closed.
+		let module_dir_lock = File::open(&module_dir)?;
+		module_dir_lock.lock()?;
+
+		let catrel_file_path = self.release_path(module_name, &release.release_name);
+		if catrel_file_path.exists() {
+			return Err(format!("release '{}' already exists", release.release_name).into());
+		}
+
+		let catmod_index_file_path = module_dir.join(MAGIC_FILENAME_CATALOG_MODULE);
+		let mut catalog_module = if catmod_index_file_path.exists() {
+			self.load_module(module_name)?
+		} else {
+			CatalogModule {
+				name: module_name.0.clone(),
+				releases: Default::default(),
+				metadata: module.metadata.clone(),
+			}
+		};
+
+		let release_json = serde_json::to_string_pretty(release)?;
+		let cid = cid_of(release_json.as_bytes());
+		(catalog_module.releases).insert(release.release_name.clone(), cid);
+		let module_json = serde_json::to_string_pretty(&CatalogModuleCapsule::V1(catalog_module))?;
+
+		// Releases are immutable: never replace one, even if it was written without taking the lock.
+		write_file(&catrel_file_path, release_json.as_bytes(), false)?;
+		if let Err(err) = write_file(&catmod_index_file_path, module_json.as_bytes(), true) {
+			// A release the module doesn't list would make retries fail with "already exists".
+			let _ = fs::remove_file(&catrel_file_path);
+			return Err(err);
+		}
+		Ok(())
+	}
+
+	fn load_replay(
+		&self,
+		module_name: &ModuleName,
+		cid: &str,
+	) -> Result<ReplayCapsule, Box<dyn UndertypedError>> {
+		let replay_path = self.replay_path(module_name, cid);
+		let reader = BufReader::new(File::open(replay_path)?);
+		let result = serde_json::from_reader(reader)?;
+		Ok(result)
+	}
+
+	fn write_replay(
+		&self,
+		module_name: &ModuleName,
+		replay: &ReplayCapsule,
+	) -> Result<String, Box<dyn UndertypedError>> {
+		let replay_json = serde_json::to_string_pretty(replay)?;
+		let cid = cid_of(replay_json.as_bytes());
+		write_file(
+			&self.replay_path(module_name, &cid),
+			replay_json.as_bytes(),
+			true,
+		)?;
+		Ok(cid)
+	}
+}
+
+/// Multicodec of JSON documents.
+const CODEC_JSON: u64 = 0x0200;
+/// Multicodec of sha2-384 multihashes.
+const MULTIHASH_SHA2_384: u64 = 0x20;
+
+/// CIDv1 of a JSON document, as base32 multibase string (e.g. "bagaaiera...").
+fn cid_of(content: &[u8]) -> String {
+	let digest = Sha384::digest(content);
+	let mut bytes = Vec::new();
+	for value in [1, CODEC_JSON, MULTIHASH_SHA2_384, digest.len() as u64] {
+		push_varint(&mut bytes, value);
+	}
+	bytes.extend_from_slice(&digest);
+	format!("b{}", base32_lower(&bytes))
+}
+
+/// Unsigned varint, as used by multiformats.
+fn push_varint(bytes: &mut Vec<u8>, mut value: u64) {
+	while value >= 0x80 {
+		bytes.push((value as u8 & 0x7f) | 0x80);
+		value >>= 7;
+	}
+	bytes.push(value as u8);
+}
+
+/// RFC 4648 base32 in lower case and without padding.
+fn base32_lower(bytes: &[u8]) -> String {
+	const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+	let mut encoded = String::new();
+	let mut buffer = 0u16;
+	let mut bits = 0;
+	for &byte in bytes {
+		buffer = (buffer << 8) | byte as u16;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			encoded.push(ALPHABET[(buffer >> bits) as usize & 0x1f] as char);
+		}
+	}
+	if bits > 0 {
+		encoded.push(ALPHABET[(buffer << (5 - bits)) as usize & 0x1f] as char);
+	}
+	encoded
+}
+
+/// Write the file at `path` atomically, so a crash never leaves a truncated file in the catalog.
+/// Fails if the file already exists, unless `overwrite` is set.
+fn write_file(
+	path: &Path,
+	content: &[u8],
+	overwrite: bool,
+) -> Result<(), Box<dyn UndertypedError>> {
+	let parent = path
+		.parent()
+		.ok_or("catalog file has no parent directory")?;
+	fs::create_dir_all(parent)?;
+	let mut file = tempfile::NamedTempFile::new_in(parent)?;
+	file.write_all(content)?;
+	file.as_file().sync_all()?;
+	if overwrite {
+		file.persist(path)?;
+	} else {
+		file.persist_noclobber(path)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn module() -> Module {
+		Module {
+			name: ModuleName("warpforge.io/example".to_owned()),
+			metadata: Default::default(),
+		}
+	}
+
+	fn release(name: &str) -> CatalogRelease {
+		CatalogRelease {
+			release_name: ReleaseName(name.to_owned()),
+			items: Default::default(),
+			metadata: Default::default(),
+		}
+	}
+
+	#[test]
+	fn cid_is_cidv1_of_json_with_sha384() {
+		// Version 1, codec json, sha2-384 of 48 bytes: [0x01, 0x80, 0x04, 0x20, 0x30], then the digest.
+		assert_eq!(
+			cid_of(b"{}"),
+			"bagaaiibq2krdxr4d4ovdr5ab4e6hjccqke34jfkkp7migmprlf6f75yrchoia7dtocs3fawg3jkbyvxn42pta"
+		);
+	}
+
+	#[test]
+	fn write_release_adds_release_to_module() {
+		let dir = tempfile::tempdir().unwrap();
+		let handle = FsHandle::new(dir.path());
+		let module = module();
+		let v1 = ReleaseName("v1".to_owned());
+		assert!(!handle.has_release(&module.name, &v1));
+
+		handle.write_release(&module, &release("v1")).unwrap();
+		handle.write_release(&module, &release("v2")).unwrap();
+		assert!(handle.has_release(&module.name, &v1));
+		let loaded = handle.load_release(&module.name, &v1).unwrap();
+		assert_eq!(loaded.release_name, v1);
+
+		let catalog_module = handle.load_module(&module.name).unwrap();
+		assert_eq!(catalog_module.name, module.name.0);
+		let names: Vec<_> = (catalog_module.releases.keys())
+			.map(|name| name.0.as_str())
+			.collect();
+		assert_eq!(names, ["v1", "v2"]);
+		assert!(catalog_module.releases[&v1].starts_with("bagaaiibq"));
+
+		let err = handle.write_release(&module, &release("v1")).unwrap_err();
+		assert!(err.to_string().contains("already exists"), "{err}");
+
+		// No temporary files are left behind.
+		let module_dir = dir.path().join(&module.name.0);
+		assert_eq!(fs::read_dir(&module_dir).unwrap().count(), 2);
+		let releases_dir = module_dir.join(MAGIC_DIRNAME_CATALOG_RELEASES);
+		assert_eq!(fs::read_dir(releases_dir).unwrap().count(), 2);
+	}
+
+	#[test]
+	fn concurrent_releases_are_all_recorded() {
+		let dir = tempfile::tempdir().unwrap();
+		std::thread::scope(|scope| {
+			for i in 0..8 {
+				let root = dir.path();
+				scope.spawn(move || {
+					let handle = FsHandle::new(root);
+					(handle.write_release(&module(), &release(&format!("v{i}")))).unwrap();
+				});
+			}
+		});
+
+		let handle = FsHandle::new(dir.path());
+		let catalog_module = handle.load_module(&module().name).unwrap();
+		assert_eq!(catalog_module.releases.len(), 8);
+	}
 }
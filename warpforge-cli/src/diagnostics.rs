@@ -0,0 +1,420 @@
+//! Reporting of validation errors.
+//!
+//! Humans get ariadne reports printed as soon as errors are found.
+//! Machines (editors, review bots) get a single JSON or SARIF document,
+//! which is written once all files have been reported.
+
+use std::{
+	ffi::OsStr,
+	fs, io,
+	path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+use serde_json::json;
+use warpforge_terminal::{log_global, logln, Level};
+use warpforge_validate::ValidationError;
+
+const SARIF_SCHEMA: &str = "https://json.schemastore.org/sarif-2.1.0.json";
+const SARIF_VERSION: &str = "2.1.0";
+const TOOL_NAME: &str = "warpforge";
+/// Every check we have rejects the document, so all diagnostics are errors.
+const SEVERITY: &str = "error";
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DiagnosticsFormat {
+	/// Pretty reports meant to be read in a terminal.
+	#[default]
+	Human,
+	/// A JSON document listing all diagnostics.
+	Json,
+	/// A SARIF v2.1.0 log, for tools consuming static analysis results.
+	Sarif,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Diagnostic {
+	pub file: String,
+	pub severity: &'static str,
+	/// Short machine readable identifier of the kind of error.
+	pub code: &'static str,
+	pub message: String,
+	pub label: Option<String>,
+	pub note: Option<String>,
+	/// Byte span in the source.
+	pub span: Option<ByteSpan>,
+	pub start: Option<LineColumn>,
+	pub end: Option<LineColumn>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct ByteSpan {
+	pub start: usize,
+	pub end: usize,
+}
+
+/// One-based line and column.  Columns are counted in unicode code points.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineColumn {
+	pub line: usize,
+	pub column: usize,
+}
+
+/// Where the machine readable document is written.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Destination {
+	#[default]
+	Stdout,
+	/// For commands with other output on stdout, which would mix with the document.
+	Stderr,
+	File(PathBuf),
+}
+
+pub struct Diagnostics {
+	format: DiagnosticsFormat,
+	destination: Destination,
+	diagnostics: Vec<Diagnostic>,
+	count: usize,
+}
+
+impl Diagnostics {
+	pub fn new(format: DiagnosticsFormat) -> Self {
+		Self {
+			format,
+			destination: Destination::default(),
+			diagnostics: Vec::new(),
+			count: 0,
+		}
+	}
+
+	pub fn with_destination(mut self, destination: Destination) -> Self {
+		self.destination = destination;
+		self
+	}
+
+	pub fn format(&self) -> DiagnosticsFormat {
+		self.format
+	}
+
+	/// Number of errors reported so far.
+	pub fn count(&self) -> usize {
+		self.count
+	}
+
+	/// Report all errors contained in a failed validation of `source`,
+	/// which was read from the file at `path`.
+	pub fn report(
+		&mut self,
+		err: &warpforge_validate::Error,
+		source: &str,
+		path: impl AsRef<Path>,
+	) {
+		let warpforge_validate::Error::Invalid { errors } = err;
+		self.count += errors.len();
+		match self.format {
+			DiagnosticsFormat::Human => display_errors(errors, source, path),
+			DiagnosticsFormat::Json | DiagnosticsFormat::Sarif => {
+				let file = path.as_ref().to_string_lossy();
+				(self.diagnostics)
+					.extend(errors.iter().map(|err| to_diagnostic(err, source, &file)));
+			}
+		}
+	}
+
+	/// Report an error that is not tied to a position in a file,
+	/// e.g. because the file could not be read.
+	pub fn report_message(&mut self, path: impl AsRef<Path>, message: impl Into<String>) {
+		let message = message.into();
+		self.count += 1;
+		match self.format {
+			DiagnosticsFormat::Human => {
+				let path = path.as_ref().display();
+				log_global(Level::Error, format!("{path}: {message}\n"));
+			}
+			DiagnosticsFormat::Json | DiagnosticsFormat::Sarif => {
+				self.diagnostics.push(Diagnostic {
+					file: path.as_ref().to_string_lossy().into_owned(),
+					severity: SEVERITY,
+					code: "file",
+					message,
+					label: None,
+					note: None,
+					span: None,
+					start: None,
+					end: None,
+				});
+			}
+		}
+	}
+
+	/// Write the machine readable document, if one was requested.
+	pub fn finish(self) -> io::Result<()> {
+		let document = match self.format {
+			DiagnosticsFormat::Human => return Ok(()),
+			DiagnosticsFormat::Json => json!({ "diagnostics": self.diagnostics }),
+			DiagnosticsFormat::Sarif => to_sarif(&self.diagnostics),
+		};
+		// Serializing a `serde_json::Value` cannot fail.
+		let document = serde_json::to_string_pretty(&document).unwrap();
+		match self.destination {
+			Destination::Stdout => logln!("{document}"),
+			Destination::Stderr => log_global(Level::Error, document + "\n"),
+			Destination::File(path) => fs::write(path, document + "\n")?,
+		}
+		Ok(())
+	}
+}
+
+fn to_diagnostic(err: &ValidationError, source: &str, file: &str) -> Diagnostic {
+	let span = err.span(source);
+	let (start, end) = match (&span, err) {
+		(Some(span), _) => (
+			Some(line_column(source, span.start)),
+			Some(line_column(source, span.end)),
+		),
+		// serde_json can point behind the end of the source (e.g. on EOF),
+		// where no byte offset exists.  It still knows line and column.
+		(None, ValidationError::Serde(serde_err)) if serde_err.line() > 0 => {
+			let position = LineColumn {
+				line: serde_err.line(),
+				column: serde_err.column(),
+			};
+			(Some(position), Some(position))
+		}
+		(None, _) => (None, None),
+	};
+
+	Diagnostic {
+		file: file.to_owned(),
+		severity: SEVERITY,
+		code: error_code(err),
+		message: err.to_string(),
+		label: err.label().map(ToOwned::to_owned),
+		note: err.note().map(ToOwned::to_owned),
+		span: span.map(|span| ByteSpan {
+			start: span.start,
+			end: span.end,
+		}),
+		start,
+		end,
+	}
+}
+
+fn error_code(err: &ValidationError) -> &'static str {
+	match err {
+		ValidationError::Serde(err) if err.is_syntax() || err.is_eof() => "syntax",
+		ValidationError::Serde(_) => "schema",
+		ValidationError::TrailingComma(_) => "trailing-comma",
+		ValidationError::Custom(_) => "invalid",
+	}
+}
+
+fn line_column(source: &str, offset: usize) -> LineColumn {
+	let offset = offset.min(source.len());
+	let before = source.get(..offset).unwrap_or(source);
+	let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+	LineColumn {
+		line: before.matches('\n').count() + 1,
+		column: before[line_start..].chars().count() + 1,
+	}
+}
+
+fn to_sarif(diagnostics: &[Diagnostic]) -> serde_json::Value {
+	let results: Vec<_> = (diagnostics.iter())
+		.map(|diagnostic| {
+			let mut text = diagnostic.message.clone();
+			if let Some(note) = &diagnostic.note {
+				text = format!("{text}\nnote: {note}");
+			}
+
+			let mut region = serde_json::Map::new();
+			if let (Some(start), Some(end)) = (diagnostic.start, diagnostic.end) {
+				region.insert("startLine".into(), start.line.into());
+				region.insert("startColumn".into(), start.column.into());
+				region.insert("endLine".into(), end.line.into());
+				region.insert("endColumn".into(), end.column.into());
+			}
+			if let Some(span) = diagnostic.span {
+				region.insert("byteOffset".into(), span.start.into());
+				region.insert("byteLength".into(), (span.end - span.start).into());
+			}
+			if let Some(label) = &diagnostic.label {
+				region.insert("message".into(), json!({ "text": label }));
+			}
+
+			let mut physical_location = json!({
+				"artifactLocation": { "uri": sarif_uri(&diagnostic.file) },
+			});
+			if !region.is_empty() {
+				physical_location["region"] = region.into();
+			}
+
+			json!({
+				"ruleId": diagnostic.code,
+				"level": diagnostic.severity,
+				"message": { "text": text },
+				"locations": [{ "physicalLocation": physical_location }],
+			})
+		})
+		.collect();
+
+	json!({
+		"$schema": SARIF_SCHEMA,
+		"version": SARIF_VERSION,
+		"runs": [{
+			"tool": {
+				"driver": {
+					"name": TOOL_NAME,
+					"version": env!("CARGO_PKG_VERSION"),
+				}
+			},
+			"columnKind": "unicodeCodePoints",
+			"results": results,
+		}]
+	})
+}
+
+/// SARIF wants URI references; relative paths are fine, but must use forward slashes.
+fn sarif_uri(file: &str) -> String {
+	file.replace(std::path::MAIN_SEPARATOR, "/")
+}
+
+fn display_errors(errors: &[ValidationError], source: &str, path: impl AsRef<Path>) {
+	use ariadne::{ColorGenerator, IndexType, Label, Report, ReportKind, Source};
+
+	let file_name = (path.as_ref().file_name())
+		.and_then(OsStr::to_str)
+		.unwrap_or("");
+	let color_primary = ColorGenerator::new().next();
+
+	let trailing_errors: Vec<_> = (errors.iter())
+		.filter(|err| err.is_trailing_comma())
+		.collect();
+	if !trailing_errors.is_empty() {
+		let first_span = (trailing_errors.iter())
+			.filter_map(|err| err.span(source))
+			.next()
+			.unwrap_or_default();
+		let mut report = Report::build(ReportKind::Error, (file_name, first_span))
+			.with_config(ariadne::Config::default().with_index_type(IndexType::Byte))
+			.with_message("found trailing comma(s)");
+		for trailing in trailing_errors {
+			let Some(span) = trailing.span(source) else {
+				continue;
+			};
+			report = report.with_label(
+				Label::new((file_name, span))
+					.with_message("trailing comma")
+					.with_color(color_primary),
+			);
+		}
+
+		print_ariadne_report(report.finish(), (file_name, Source::from(source)));
+	}
+
+	for err in errors.iter().filter(|err| !err.is_trailing_comma()) {
+		let span = err.span(source);
+		let mut report = Report::build(
+			ReportKind::Error,
+			(file_name, span.clone().unwrap_or_default()),
+		)
+		.with_config(ariadne::Config::default().with_index_type(IndexType::Byte))
+		.with_message(format!("{err}"));
+
+		if let Some(span) = span {
+			if span == (0..0) {
+				continue;
+			}
+			let label = err.label().unwrap_or("here");
+			report = report.with_label(
+				Label::new((file_name, span))
+					.with_message(label)
+					.with_color(color_primary),
+			);
+			if let Some(note) = err.note() {
+				report = report.with_note(note);
+			}
+		}
+
+		print_ariadne_report(report.finish(), (file_name, Source::from(source)));
+	}
+}
+
+fn print_ariadne_report<S, C>(report: ariadne::Report<'_, S>, cache: C)
+where
+	S: ariadne::Span,
+	C: ariadne::Cache<S::SourceId>,
+{
+	let mut message = Vec::new();
+	let result = report.write(cache, &mut message);
+	if result.is_err() {
+		return;
+	}
+	message.push(b'\n');
+	if let Ok(message) = String::from_utf8(message) {
+		log_global(Level::Error, message);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn line_column_counts_from_one() {
+		let source = "{\n  \"ä\": 1,\n}";
+		assert_eq!(line_column(source, 0), LineColumn { line: 1, column: 1 });
+		assert_eq!(line_column(source, 2), LineColumn { line: 2, column: 1 });
+		// 'ä' is two bytes but one column.
+		assert_eq!(line_column(source, 9), LineColumn { line: 2, column: 7 });
+		assert_eq!(line_column(source, 100), LineColumn { line: 3, column: 2 });
+	}
+
+	#[test]
+	fn json_diagnostics_for_trailing_comma() {
+		let source = "{\"formula\": {},}";
+		let err = warpforge_validate::validate_formula(source).err().unwrap();
+
+		let mut diagnostics = Diagnostics::new(DiagnosticsFormat::Json);
+		diagnostics.report(&err, source, "dir/formula.json");
+
+		let first = &diagnostics.diagnostics[0];
+		assert_eq!(first.file, "dir/formula.json");
+		assert_eq!(first.code, "trailing-comma");
+		assert_eq!(first.severity, "error");
+		assert_eq!(
+			(first.span.unwrap().start, first.span.unwrap().end),
+			(14, 15)
+		);
+		assert_eq!(
+			first.start,
+			Some(LineColumn {
+				line: 1,
+				column: 15
+			})
+		);
+
+		let sarif = to_sarif(&diagnostics.diagnostics);
+		let result = &sarif["runs"][0]["results"][0];
+		assert_eq!(result["ruleId"], "trailing-comma");
+		assert_eq!(result["level"], "error");
+		let region = &result["locations"][0]["physicalLocation"]["region"];
+		assert_eq!(region["startColumn"], 15);
+		assert_eq!(region["byteOffset"], 14);
+		assert_eq!(region["byteLength"], 1);
+	}
+
+	#[test]
+	fn document_written_to_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("diagnostics.json");
+
+		let diagnostics = Diagnostics::new(DiagnosticsFormat::Json)
+			.with_destination(Destination::File(path.clone()));
+		diagnostics.finish().unwrap();
+
+		let document: serde_json::Value =
+			serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap();
+		assert_eq!(document, json!({ "diagnostics": [] }));
+	}
+}
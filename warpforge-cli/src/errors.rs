@@ -31,17 +31,38 @@ pub enum Error {
 	// 	cause: ErrorCause,
 	// },
 
+	/// ValidationFailed is returned by `check`, if any of the checked files is invalid.
+	/// The individual problems have already been reported when this is returned.
+	#[error("validation failed: found {count} error(s)")]
+	ValidationFailed { count: usize },
+
 	// User-level "404"-like error.
 	#[error("catalog entry doesn't exist -- there is no value referenced as {reference}")]
 	CatalogEntryNotExists {
 		reference: warpforge_api::catalog::CatalogRef,
 	},
 
+	/// Releases are immutable: publishing a release under an existing name is refused.
+	#[error("catalog release already exists: {module_name}:{release_name}")]
+	CatalogReleaseExists {
+		module_name: warpforge_api::catalog::ModuleName,
+		release_name: warpforge_api::catalog::ReleaseName,
+	},
+
+	/// Replaying a release produced different wares than the ones recorded in the release.
+	/// The individual items have already been reported when this is returned.
+	#[error("replay does not match release: {count} item(s) differ")]
+	ReplayMismatch { count: usize },
+
 	/// Catch-all error for failing to look something up or write something in a catalog.
 	/// Probably contains a filesystem IO error or similar.
 	#[error("error accessing catalog: {cause}")]
 	CatalogAccess { cause: ErrorCause },
 
+	/// Failure to read or maintain the image cache, including finding corrupt blobs in it.
+	#[error("error accessing image cache: {cause}")]
+	ImageCache { cause: ErrorCause },
+
 	// Transparent wrapper for executor errors.
 	#[error(transparent)]
 	Executor(#[from] warpforge_executors::Error),
@@ -51,11 +72,17 @@ impl Error {
 	pub fn code(&self) -> i32 {
 		match self {
 			Error::InvalidArguments { .. } => 1,
+			Error::ValidationFailed { .. } => 3,
 			Error::BizarreEnvironment { .. } => 4,
 			// Error::MissingPlugin { .. } => 7,
 			Error::CatalogEntryNotExists { .. } => 14,
 			Error::CatalogAccess { .. } => 15,
+			Error::Executor(warpforge_executors::Error::Timeout { .. }) => 20,
+			Error::Executor(warpforge_executors::Error::Cancelled) => 21,
 			Error::Executor(..) => 16,
+			Error::CatalogReleaseExists { .. } => 17,
+			Error::ReplayMismatch { .. } => 18,
+			Error::ImageCache { .. } => 19,
 		}
 	}
 }
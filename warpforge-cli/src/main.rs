@@ -1,13 +1,12 @@
 use clap::error::ErrorKind;
 use clap::Parser;
-use std::env;
-use std::path;
 
 use warpforge_terminal::logln;
 use warpforge_terminal::Logger;
 
 mod cmds;
 mod dab;
+mod diagnostics;
 mod errors;
 
 use errors::*;
@@ -17,7 +16,8 @@ fn main() {
 
 	let result = main2();
 	if let Err(e) = &result {
-		logln!("{}", e);
+		// Errors go to stderr, so stdout stays parsable for machine readable output.
+		warpforge_terminal::error!("{}", e);
 	}
 
 	// Wait for all messages to be printed to stdout.
@@ -49,17 +49,13 @@ fn main2() -> Result<(), Error> {
 	//   - 2: to have a func on my command strugs that receives a call, rather than have to make this dispatch table.
 	match &cli.subcommand {
 		Some(cmds::Subcommands::Run(cmd)) => return cmds::run::execute(&cli, cmd),
+		Some(cmds::Subcommands::Check(cmd)) => return cmds::check::execute(&cli, cmd),
+		Some(cmds::Subcommands::Cache(cmd)) => return cmds::cache::execute(cmd),
 		Some(cmds::Subcommands::Catalog(cmd)) => match &cmd.subcommand {
 			cmds::catalog::Subcommands::ReadItem(cmd) => {
-				let user_home = env::var("HOME")
-					.map_err(|e| Error::BizarreEnvironment { cause: Box::new(e) })?;
-
 				// Create the catalog data access broker.  Store in a box just so we can have dynamic dispatch.  (This is architecture astronauting, but I wanna know that I know how to do this.)
-				//TODO: check for a root workspace above $CWD before $HOME/.warphome
 				let catalog_handle: Box<dyn dab::catalog::Handle> =
-					Box::new(dab::catalog::FsHandle::new(
-						path::Path::new(&user_home).join(".warphome/catalogs/warpsys"),
-					));
+					Box::new(dab::catalog::FsHandle::new(dab::catalog_path()?));
 
 				let catalog_release = catalog_handle
 					.load_release(&cmd.catalog_ref.module_name, &cmd.catalog_ref.release_name)
@@ -77,6 +73,7 @@ fn main2() -> Result<(), Error> {
 					}
 				}
 			}
+			cmds::catalog::Subcommands::Replay(cmd) => return cmds::catalog::replay(cmd),
 		},
 		Some(cmds::Subcommands::Ware(cmd)) => match &cmd.subcommand {
 			cmds::ware::Subcommands::Unpack(cmd) => {
@@ -0,0 +1,33 @@
+//! Cancellation of running formulas and plots.
+
+use std::sync::{
+	atomic::{AtomicBool, Ordering},
+	Arc,
+};
+
+use crate::{Error, Result};
+
+/// Shared flag to stop running formulas and plots, e.g. when the user presses Ctrl-C.
+///
+/// Clones share the flag: cancelling any of them cancels all.
+/// Running containers are killed, and work that hasn't started yet is skipped.
+#[derive(Clone, Default, Debug)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+	pub fn cancel(&self) {
+		self.0.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.0.load(Ordering::SeqCst)
+	}
+
+	/// Fail with [Error::Cancelled] once cancelled.  Called before starting any new work.
+	pub(crate) fn check(&self) -> Result<()> {
+		match self.is_cancelled() {
+			true => Err(Error::Cancelled),
+			false => Ok(()),
+		}
+	}
+}
@@ -1,9 +1,13 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, str::FromStr};
+
+use oci_unpack::{auth::DockerConfig, Mirror, Platform, PullConfig};
+
+use crate::{cancel::CancellationToken, limits::Limits, runtime::Runtime, Error, Result};
 
 #[derive(Clone, Default, Debug)]
 pub struct Context {
-	/// Path to OCI Runtime executable used to run containers in this context.
-	pub runtime: PathBuf,
+	/// OCI Runtime used to run containers in this context.
+	pub runtime: Runtime,
 
 	/// Absolute path that determines the host path of mounts.
 	/// This is used as the prefix, when a formula specifies a relative mount path.
@@ -20,4 +24,114 @@ pub struct Context {
 	///
 	/// If no [Self::image_cache] is specified, images are always pulled freshly from the registry.
 	pub image_cache: Option<PathBuf>,
+
+	/// Path to the cache of unpacked images.
+	///
+	/// If a [Self::snapshot_cache] is specified, images are unpacked only once and mounted
+	/// read-only as the container root, using an overlayfs.  Otherwise they are unpacked for every run.
+	pub snapshot_cache: Option<PathBuf>,
+
+	/// Never contact a registry: images must already be in the [Self::image_cache],
+	/// and tags must have been resolved before.
+	pub offline: bool,
+
+	/// Docker config file (usually `~/.docker/config.json`), providing credentials for container registries.
+	///
+	/// If no [Self::docker_config] is specified, registries are accessed anonymously.
+	pub docker_config: Option<PathBuf>,
+
+	/// Mirrors to pull images through, per registry.
+	/// Images keep their original reference as identity, regardless of the mirror they were pulled from.
+	pub registry_mirrors: Vec<Mirror>,
+
+	/// Platform to select from multi-arch images.
+	///
+	/// If no [Self::platform] is specified, linux on the architecture we are running on is used.
+	pub platform: Option<Platform>,
+
+	/// Path to a local warehouse, where packed outputs are stored by their digest.
+	///
+	/// If no [Self::warehouse] is specified, outputs are only emitted to [Self::output_path].
+	pub warehouse: Option<PathBuf>,
+
+	/// JSON Patches ([RFC 6902](https://www.rfc-editor.org/rfc/rfc6902)) applied in order to the
+	/// container spec of every container, after all the patches Warpforge applies itself.
+	///
+	/// The patched spec is validated before running the container.
+	pub spec_patches: Vec<json_patch::Patch>,
+
+	/// Resource limits and timeout of every container.
+	pub limits: Limits,
+
+	/// Cancels formulas and plots running in this context.
+	pub cancellation: CancellationToken,
+
+	/// When to open an interactive shell in the container of a formula, after its action ran.
+	pub debug_shell: DebugShell,
+}
+
+/// When to open a debug shell in a formula's container.
+///
+/// The shell runs in the same container setup as the formula's action, with the same mounts,
+/// environment and root filesystem, including any changes the action made to them.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum DebugShell {
+	#[default]
+	Never,
+	/// After the action failed.
+	OnFailure,
+	/// After every action.
+	Always,
+}
+
+impl DebugShell {
+	/// Whether to open a shell after an action exited with `exit_code`.
+	pub(crate) fn wanted(self, exit_code: Option<i32>) -> bool {
+		match self {
+			Self::Never => false,
+			Self::OnFailure => exit_code != Some(0),
+			Self::Always => true,
+		}
+	}
+}
+
+/// Parses "never", "on-failure" or "always".
+impl FromStr for DebugShell {
+	type Err = String;
+
+	fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+		match s {
+			"never" => Ok(Self::Never),
+			"on-failure" => Ok(Self::OnFailure),
+			"always" => Ok(Self::Always),
+			_ => Err(format!(
+				"unknown debug shell mode '{s}', expected one of: never, on-failure, always"
+			)),
+		}
+	}
+}
+
+impl Context {
+	/// Configuration for pulling images in this context.
+	pub(crate) fn pull_config(&self) -> Result<PullConfig> {
+		let credentials = match &self.docker_config {
+			Some(path) => {
+				Some(
+					DockerConfig::load(path).map_err(|err| Error::SystemSetupError {
+						msg: "failed to load registry credentials".into(),
+						cause: Box::new(err),
+					})?,
+				)
+			}
+			None => None,
+		};
+		Ok(PullConfig {
+			cache: self.image_cache.clone(),
+			offline: self.offline,
+			credentials,
+			mirrors: self.registry_mirrors.clone(),
+			platform: self.platform.clone().unwrap_or_else(Platform::current),
+			..PullConfig::default()
+		})
+	}
 }
@@ -28,6 +28,14 @@ pub enum Error {
 	#[error("{msg}")]
 	SystemSetupCauseless { msg: String },
 
+	/// The container ran longer than its timeout, and was killed.
+	#[error("container timed out after {}s", timeout.as_secs())]
+	Timeout { timeout: std::time::Duration },
+
+	/// The run was cancelled, e.g. by Ctrl-C.  Running containers were killed.
+	#[error("cancelled")]
+	Cancelled,
+
 	#[error("{msg}: {cause}")]
 	Catchall { msg: String, cause: ErrorCause },
 
@@ -7,6 +7,9 @@
 /// (In practice: when we're running subprocesses for plugins, they generally
 /// have line-oriented protocols, e.g. JSONL.  For interactive appearances
 /// on containers, however, we need to relay input more or less constantly.)
+///
+/// Interactive containers, like debug shells, currently bypass events:
+/// they are attached to the terminal directly, see [crate::execute::Executor::run_interactive].
 #[derive(Debug)]
 pub struct Event {
 	pub topic: String,
@@ -1,13 +1,14 @@
+use std::fs;
 use std::io::{BufRead, BufReader, Lines};
-use std::path::PathBuf;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::thread;
-use std::{fs, process::Command};
+use std::time::{Duration, Instant};
 
-use crossbeam_channel::Sender;
-use str_cat::os_str_cat;
+use crossbeam_channel::{RecvTimeoutError, Sender};
 
-use crate::{Error, Result};
+use crate::{cancel::CancellationToken, Error, Result};
 
 pub struct Executor {
 	/// Path to use for:
@@ -23,16 +24,55 @@ pub struct Executor {
 
 	/// File to write logs.
 	pub log_file: PathBuf,
+
+	/// Kills the running container when cancelled.
+	pub cancellation: CancellationToken,
+}
+
+/// Why the watchdog killed a container.
+enum Interruption {
+	Cancelled,
+	Timeout(Duration),
 }
 
 impl Executor {
-	pub fn run(&self, task: &crate::ContainerParams, outbox: Sender<crate::Event>) -> Result<()> {
+	/// Run a container, relaying its output to `outbox`.  Returns the exit code of the container,
+	/// which is also sent as last event.
+	pub fn run(
+		&self,
+		task: &crate::ContainerParams,
+		outbox: Sender<crate::Event>,
+	) -> Result<Option<i32>> {
+		self.cancellation.check()?;
 		self.prep_bundledir(task)?;
-		self.container_exec(task, outbox)?;
-		Ok(())
+		let result = self.container_exec(task, outbox);
+		if let Err(Error::Cancelled | Error::Timeout { .. }) = result {
+			self.cleanup(task);
+		}
+		result
 	}
 
-	fn prep_bundledir(&self, task: &crate::ContainerParams) -> Result<()> {
+	/// Remove the bundle and overlay directories of a finished or killed container, and whatever state the runtime kept.
+	fn cleanup(&self, task: &crate::ContainerParams) {
+		let _ = (task.runtime.delete_command(&self.log_file, &task.ident))
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.status();
+		for dir in [
+			self.ersatz_dir.join(&task.ident),
+			self.ersatz_dir.join("overlays"),
+		] {
+			// Files owned by subordinate ids can't be removed by the invoking user.
+			if let Some(Err(err)) = (task.id_map.as_ref()).map(|id_map| id_map.reclaim(&dir)) {
+				warpforge_terminal::warn!("{err}");
+			}
+			if let Err(err) = remove_dir_all(&dir) {
+				warpforge_terminal::warn!("failed to remove {}: {err}", dir.display());
+			}
+		}
+	}
+
+	pub(crate) fn prep_bundledir(&self, task: &crate::ContainerParams) -> Result<()> {
 		// Build the config data.
 		let mut spec = crate::oci::oci_spec_base();
 
@@ -55,35 +95,67 @@ impl Executor {
 		// todo: apply mutations here.
 		let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
 			{ "op": "add", "path": "/process/args", "value": task.command },
+			{ "op": "replace", "path": "/process/terminal", "value": task.terminal },
+			{ "op": "replace", "path": "/process/cwd", "value": task.cwd },
+			{ "op": "replace", "path": "/process/user", "value": {"uid": task.uid, "gid": task.gid} },
 			{ "op": "replace", "path": "/root/path", "value": task.root_path }, // FIXME: time to get the rest of the supply chain implemented :D
-			{ "op": "add", "path": "/linux/uidMappings", "value":
-			   [{"containerID": 0, "hostID": uid, "size": 1}]},
-			{ "op": "add", "path": "/linux/gidMappings", "value":
-			   [{"containerID": 0, "hostID": gid, "size": 1}]},
-			{ "op": "add", "path": "/linux/namespaces/-", "value": {"type": "user"}},
 		]))
 		.unwrap();
 		json_patch::patch(&mut spec, &p).unwrap();
+		(task.runtime).patch_spec(
+			&mut spec,
+			(task.uid, task.gid),
+			(uid, gid),
+			task.id_map.as_ref(),
+		);
+		task.limits.patch_spec(&mut spec)?;
 
 		// add mount specs
 		use crate::oci::ToOCIMount;
-		for (_dest, ms) in task.mounts.iter() {
+		for (dest, ms) in task.mounts.iter() {
+			// A mount on "/" has to come before all others, which would be hidden by it otherwise.
+			let path = if dest == "/" {
+				"/mounts/0"
+			} else {
+				"/mounts/-"
+			};
 			let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
-				{ "op": "add", "path": "/mounts/-", "value": ms.to_oci_mount() },
+				{ "op": "add", "path": path, "value": ms.to_oci_mount() },
 			]))
 			.unwrap();
 			json_patch::patch(&mut spec, &p).unwrap();
 		}
 
-		// add environment variables
+		// add environment variables, replacing defaults of the same name (e.g. PATH)
 		for (var, val) in task.environment.iter() {
+			let prefix = format!("{var}=");
+			let existing = (spec["process"]["env"].as_array().unwrap().iter())
+				.position(|entry| entry.as_str().unwrap().starts_with(&prefix));
+			let path = match existing {
+				Some(index) => format!("/process/env/{index}"),
+				None => "/process/env/-".into(),
+			};
+			let op = if existing.is_some() { "replace" } else { "add" };
 			let p: json_patch::Patch = serde_json::from_value(serde_json::json!([
-				{ "op": "add", "path": "/process/env/-", "value": format!("{var}={val}")}
+				{ "op": op, "path": path, "value": format!("{var}={val}")}
 			]))
 			.unwrap();
 			json_patch::patch(&mut spec, &p).unwrap();
 		}
 
+		// apply the user's patches last, so they can override everything above
+		for (index, patch) in task.spec_patches.iter().enumerate() {
+			json_patch::patch(&mut spec, patch).map_err(|e| Error::SystemSetupError {
+				msg: format!("failed to apply spec patch #{}", index + 1),
+				cause: Box::new(e),
+			})?;
+		}
+		if !task.spec_patches.is_empty() {
+			crate::oci::validate_spec(&spec).map_err(|reason| Error::SystemSetupCauseless {
+				msg: format!("spec patches produced an invalid container spec: {reason}"),
+			})?;
+		}
+
 		// Write it out.
 		let cfg_dir = self.ersatz_dir.join(&task.ident);
 		fs::create_dir_all(&cfg_dir).map_err(|e| {
@@ -126,15 +198,13 @@ impl Executor {
 		&self,
 		task: &crate::ContainerParams,
 		outbox: Sender<crate::Event>,
-	) -> Result<()> {
-		let mut cmd = Command::new(&task.runtime);
-		cmd.arg(os_str_cat!("--log=", self.log_file));
-		cmd.arg("--debug");
-		cmd.arg("run");
-		cmd.arg(os_str_cat!("--bundle=", self.ersatz_dir.join(&task.ident)));
-		cmd.arg(&task.ident); // container name.
+	) -> Result<Option<i32>> {
+		let bundle_dir = self.ersatz_dir.join(&task.ident);
+		let mut cmd = (task.runtime).run_command(&self.log_file, &bundle_dir, &task.ident);
 
 		cmd.stdin(Stdio::null());
+		// Keep Ctrl-C in the terminal from reaching the container: cancellation kills it instead.
+		cmd.process_group(0);
 		cmd.stdout(Stdio::piped());
 		cmd.stderr(Stdio::piped());
 
@@ -154,6 +224,51 @@ impl Executor {
 			}
 		})?;
 
+		// Kill the container once it runs longer than its timeout, or is cancelled.
+		// Dropping `done` tells the watchdog that the container has exited.
+		let (done, done_recv) = crossbeam_channel::bounded::<()>(0);
+		let watchdog = {
+			let runtime = task.runtime.clone();
+			let log_file = self.log_file.clone();
+			let ident = task.ident.clone();
+			let pid = child.id() as usize;
+			let timeout = task.limits.timeout;
+			let cancellation = self.cancellation.clone();
+			let start = Instant::now();
+			thread::spawn(move || {
+				let poll = Duration::from_millis(100);
+				let interruption = loop {
+					if done_recv.recv_timeout(poll) != Err(RecvTimeoutError::Timeout) {
+						return None;
+					}
+					if cancellation.is_cancelled() {
+						break Interruption::Cancelled;
+					}
+					if let Some(timeout) = timeout.filter(|&timeout| start.elapsed() >= timeout) {
+						break Interruption::Timeout(timeout);
+					}
+				};
+				// The container might not be created yet, so keep trying until the runtime exits.
+				for attempt in 0.. {
+					let _ = (runtime.kill_command(&log_file, &ident))
+						.stdout(Stdio::null())
+						.stderr(Stdio::null())
+						.status();
+					if attempt >= 10 {
+						// The runtime itself hangs, so there may be no container to kill.
+						// It leads its own process group, which also holds processes it started.
+						use syscalls::{syscall, Sysno};
+						let pgid = -(pid as isize) as usize;
+						let _ = unsafe { syscall!(Sysno::kill, pgid, 9) }; // SIGKILL
+					}
+					if done_recv.recv_timeout(poll) != Err(RecvTimeoutError::Timeout) {
+						break;
+					}
+				}
+				Some(interruption)
+			})
+		};
+
 		// Take handles to the IO before we spawn the exit wait.
 		// (The exit wait future takes ownership of the `child` value.)
 		let stdout = BufReader::new(
@@ -193,6 +308,8 @@ impl Executor {
 			msg: "failed to get child exit code".into(),
 			cause: Box::new(err),
 		})?;
+		drop(done);
+		let killed = watchdog.join().unwrap();
 
 		outbox
 			.send(crate::Event {
@@ -201,7 +318,36 @@ impl Executor {
 			})
 			.expect("channel must not be closed");
 
-		Ok(())
+		match killed {
+			Some(Interruption::Cancelled) => Err(Error::Cancelled),
+			Some(Interruption::Timeout(timeout)) => Err(Error::Timeout { timeout }),
+			None => Ok(status.code()),
+		}
+	}
+
+	/// Run a container attached to the terminal of this process, e.g. for a debug shell.
+	/// Returns the exit code of the container.
+	///
+	/// Cancellation is only checked before the container starts: the user ends the container by
+	/// exiting it, and neither the cancellation token nor the time limit interrupt it.
+	/// The container's state and bundle are removed once it exited.
+	pub fn run_interactive(&self, task: &crate::ContainerParams) -> Result<Option<i32>> {
+		self.cancellation.check()?;
+		self.prep_bundledir(task)?;
+
+		let bundle_dir = self.ersatz_dir.join(&task.ident);
+		let mut cmd = (task.runtime).run_command(&self.log_file, &bundle_dir, &task.ident);
+		// The runtime connects the container's terminal to the stdio it inherits.
+		cmd.stdin(Stdio::inherit());
+		cmd.stdout(Stdio::inherit());
+		cmd.stderr(Stdio::inherit());
+		let status = cmd.status();
+		self.cleanup(task);
+		let status = status.map_err(|e| Error::SystemSetupError {
+			msg: "failed to spawn containerization process".into(),
+			cause: Box::new(e),
+		})?;
+		Ok(status.code())
 	}
 
 	fn send_container_output<T: BufRead>(
@@ -224,6 +370,26 @@ impl Executor {
 	}
 }
 
+/// Remove a directory, including directories without permissions, like the work directories of overlayfs.
+fn remove_dir_all(path: &Path) -> std::io::Result<()> {
+	use std::os::unix::fs::PermissionsExt;
+	match fs::symlink_metadata(path) {
+		Ok(meta) if meta.is_dir() => {
+			fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+			for entry in fs::read_dir(path)? {
+				let entry = entry?;
+				if entry.file_type()?.is_dir() {
+					remove_dir_all(&entry.path())?;
+				}
+			}
+			fs::remove_dir_all(path)
+		}
+		Ok(_) => fs::remove_file(path),
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+		Err(err) => Err(err),
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	use std::{path::PathBuf, thread};
@@ -250,11 +416,12 @@ mod tests {
 		let cfg = crate::execute::Executor {
 			ersatz_dir: path.join("run"),
 			log_file: path.join("log"),
+			cancellation: Default::default(),
 		};
 		let (gather_chan, gather_chan_recv) = crossbeam_channel::bounded::<crate::Event>(32);
 		let params = crate::ContainerParams {
 			ident: "containernamegoeshere".into(),
-			runtime: "runc".into(),
+			runtime: Default::default(),
 			command: vec![
 				"/bin/sh".to_string(),
 				"-c".to_string(),
@@ -262,6 +429,13 @@ mod tests {
 			],
 			mounts: { IndexMap::new() },
 			root_path: bundle_path.join("rootfs"),
+			cwd: "/".into(),
+			uid: 0,
+			gid: 0,
+			spec_patches: Vec::new(),
+			limits: Default::default(),
+			terminal: false,
+			id_map: None,
 
 			environment: IndexMap::from([
 				("MSG".into(), "hello, from environment variables!".into()),
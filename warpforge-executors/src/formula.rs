@@ -1,9 +1,9 @@
 use crossbeam_channel::Sender;
 use indexmap::IndexMap;
 use oci_client::Reference;
-use oci_unpack::{pull_and_unpack, PullConfig};
+use oci_unpack::{digest::Digest, pull_and_unpack, snapshot::pull_and_snapshot};
 use rand::distributions::{Alphanumeric, DistString};
-use std::io::Write;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
 use std::{fs, thread};
 use warpforge_api::formula::{
@@ -13,10 +13,13 @@ use warpforge_api::formula::{
 use warpforge_api::plot::LocalLabel;
 use warpforge_terminal::{logln, Bar};
 
-use crate::context::Context;
+use crate::context::{Context, DebugShell};
 use crate::events::EventBody;
 use crate::execute::Executor;
+use crate::idmap::IdMap;
+use crate::image::ImageProcess;
 use crate::pack::{pack_outputs, IntermediateOutput, OutputPacktype};
+use crate::runtime::RuntimeProfile;
 use crate::{to_string_or_panic, ContainerParams, Error, Event, MountSpec, Output, Result};
 
 pub struct Formula<'a> {
@@ -34,6 +37,7 @@ pub fn run_formula(formula: FormulaAndContext, context: &Context) -> Result<Vec<
 		executor: Executor {
 			ersatz_dir: temporary_dir.path().join("run"),
 			log_file: temporary_dir.path().join("log"), // TODO: Find a better more persistent location for logs.
+			cancellation: context.cancellation.clone(),
 		},
 		context,
 	};
@@ -65,6 +69,8 @@ pub fn run_formula(formula: FormulaAndContext, context: &Context) -> Result<Vec<
 
 impl<'a> Formula<'a> {
 	const CONTAINER_BASE_PATH: &'static str = "/.warpforge.container";
+	/// Shell for debugging, which images have to provide.
+	const DEBUG_SHELL: &'static str = "/bin/sh";
 
 	pub fn container_script_path() -> PathBuf {
 		PathBuf::from(Self::CONTAINER_BASE_PATH).join("script")
@@ -155,6 +161,12 @@ impl<'a> Formula<'a> {
 	) -> Result<Vec<Output>> {
 		let formula::FormulaCapsule::V1(formula) = formula_and_context.formula;
 
+		// Fail before running the action, whose effects would be lost without a shell.
+		if self.context.debug_shell != DebugShell::Never && !std::io::stdin().is_terminal() {
+			let msg = "a debug shell requires stdin to be a terminal".into();
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+
 		let progress = Bar::new(5, "setup container");
 
 		let Some(input) = formula.inputs.get(&"/".to_string()) else {
@@ -173,6 +185,13 @@ impl<'a> Formula<'a> {
 			let msg = "formula inputs of type 'oci' are required to contain digest".into();
 			return Err(Error::SystemSetupCauseless { msg });
 		};
+		// Fail before pulling, if the digest can't be verified.
+		reference_digest
+			.parse::<Digest>()
+			.map_err(|err| Error::SystemSetupError {
+				msg: "unsupported digest of 'oci' input".into(),
+				cause: Box::new(err),
+			})?;
 
 		let (mut mounts, environment) = self.setup_inputs(formula.inputs)?;
 
@@ -195,21 +214,83 @@ impl<'a> Formula<'a> {
 		let ident = format!("warpforge-{random_suffix}");
 
 		let bundle_path = self.executor.ersatz_dir.join(&ident);
-		let pull_config = PullConfig {
-			cache: self.context.image_cache.clone(),
-			..PullConfig::default()
+		let root_path = bundle_path.join("rootfs");
+		let pull_config = self.context.pull_config()?;
+		let map_err = |err| Error::SystemSetupError {
+			msg: "failed to obtain image".into(),
+			cause: Box::new(err),
 		};
-		let bundle = pull_and_unpack(&reference, &bundle_path, &pull_config).map_err(|err| {
-			Error::SystemSetupError {
-				msg: "failed to obtain image".into(),
-				cause: Box::new(err),
+		// Keeps the snapshot from being removed while the container runs.
+		let mut _snapshot_lock = None;
+		// gVisor takes care of users itself, the other runtimes can map subordinate ids.
+		let subordinate = || match self.context.runtime.profile {
+			RuntimeProfile::Gvisor => None,
+			RuntimeProfile::Runc | RuntimeProfile::Crun => IdMap::subordinate(),
+		};
+		let mut snapshot = None;
+		if let Some(snapshot_cache) = &self.context.snapshot_cache {
+			let image =
+				pull_and_snapshot(&reference, snapshot_cache, &pull_config).map_err(map_err)?;
+			// Recorded owners are applied to the rootfs itself, which a shared snapshot can't be.
+			let bundle_dir = image.rootfs.parent().unwrap_or(&image.rootfs);
+			let recorded = oci_unpack::rootless::read(bundle_dir).map_err(map_err)?;
+			if recorded.is_empty() || subordinate().is_none() {
+				_snapshot_lock = Some(image.lock);
+				snapshot = Some((image.info, image.rootfs));
 			}
-		})?;
-		if bundle.manifest_digest != reference_digest {
+		}
+		let (bundle, image_rootfs) = match snapshot {
+			Some((info, rootfs)) => {
+				// The snapshot is shared between runs: it only serves as lower directory
+				// of an overlayfs, writes go to an upper directory in this run's bundle.
+				let root_mount =
+					MountSpec::new_overlayfs(self.context, &rootfs, "/", &bundle_path)?;
+				mounts.insert("/".into(), root_mount);
+				fs::create_dir_all(&root_path).map_err(|err| Error::SystemSetupError {
+					msg: "failed to create container root directory".into(),
+					cause: Box::new(err),
+				})?;
+				(info, rootfs)
+			}
+			None => {
+				let info =
+					pull_and_unpack(&reference, &bundle_path, &pull_config).map_err(map_err)?;
+				(info, root_path.clone())
+			}
+		};
+		// The reference may point to an image index, from which a manifest was selected.
+		let index_digest = bundle.index_digest.as_deref();
+		if bundle.manifest_digest != reference_digest && index_digest != Some(reference_digest) {
 			let msg = "digest of 'oci' input and actual image do not match".into();
 			return Err(Error::SystemSetupCauseless { msg });
 		}
 
+		// Without root, the image was unpacked with all files owned by the invoking user.
+		// With subordinate ids, the recorded owners are applied in the container's user namespace,
+		// otherwise the container user is the only id we can map (see `Runtime::patch_spec`).
+		let bundle_dir = image_rootfs.parent().unwrap_or(&image_rootfs);
+		let rootless_files = oci_unpack::rootless::read(bundle_dir).map_err(map_err)?;
+		let id_map = match rootless_files.is_empty() {
+			true => None,
+			false => subordinate(),
+		};
+		if let Some(id_map) = &id_map {
+			if let Err(err) = id_map.apply(&image_rootfs, &rootless_files) {
+				let _ = id_map.reclaim(&bundle_path);
+				return Err(err);
+			}
+		} else if !rootless_files.is_empty() {
+			warpforge_terminal::warn!(
+				"{} files of the image keep the invoking user as owner: \
+				 subordinate ids in /etc/subuid and /etc/subgid, newuidmap and newgidmap are required",
+				rootless_files.len()
+			);
+		}
+
+		// Environment variables of the formula take precedence over those of the image.
+		let mut process = ImageProcess::new(&bundle.config, &image_rootfs)?;
+		process.environment.extend(environment);
+
 		progress.set(3, "run container");
 
 		let params = ContainerParams {
@@ -217,14 +298,44 @@ impl<'a> Formula<'a> {
 			runtime: self.context.runtime.clone(),
 			command,
 			mounts,
-			environment,
-			root_path: bundle_path.join("rootfs"),
+			environment: process.environment,
+			root_path,
+			cwd: process.cwd,
+			uid: process.uid,
+			gid: process.gid,
+			spec_patches: self.context.spec_patches.clone(),
+			limits: self.context.limits.clone(),
+			terminal: false,
+			id_map: id_map.clone(),
 		};
-		self.executor.run(&params, outbox)?;
+		let result = (self.executor.run(&params, outbox)).and_then(|exit_code| {
+			match self.context.debug_shell.wanted(exit_code) {
+				true => self.debug_shell(params, exit_code),
+				false => Ok(()),
+			}
+		});
+		// Outputs and the rest of the run written by other container users are owned by subordinate ids.
+		if let Some(id_map) = &id_map {
+			id_map.reclaim(&self.executor.ersatz_dir)?;
+		}
+		result?;
 
 		progress.set(5, "pack outputs");
 
-		pack_outputs(&self.context.output_path, &outputs)
+		pack_outputs(self.context, &outputs)
+	}
+
+	/// Open an interactive shell in the container setup of an action, which exited with `exit_code`.
+	fn debug_shell(&self, params: ContainerParams, exit_code: Option<i32>) -> Result<()> {
+		let status = exit_code.map_or("was killed".into(), |code| format!("exited with {code}"));
+		logln!("action {status}, opening a debug shell: exit the shell to continue");
+		let params = ContainerParams {
+			command: vec![Self::DEBUG_SHELL.into()],
+			terminal: true,
+			..params
+		};
+		self.executor.run_interactive(&params)?;
+		Ok(())
 	}
 
 	/// Create all input mounts and collect environment variable inputs.
@@ -0,0 +1,286 @@
+//! Subordinate ids, which map the ownership recorded by a rootless unpack into the container.
+//!
+//! Without privileges, an image is unpacked with all files owned by the invoking user,
+//! and the owners and setuid bits of other files are recorded instead (see [oci_unpack::rootless]).
+//! Users with subordinate ids in `/etc/subuid` and `/etc/subgid` can map them into a user namespace
+//! with the setuid helpers `newuidmap` and `newgidmap`, like podman does: the invoking user becomes
+//! root of the container, and container id `n` is mapped to the `n`th subordinate id.
+//!
+//! Inside of such a namespace, the recorded owners and modes are applied to the rootfs before the
+//! container runs.  Afterwards, everything is owned by the invoking user again, so it can be removed.
+
+use std::{
+	fs,
+	io::{self, Write},
+	os::{
+		fd::AsRawFd,
+		unix::{ffi::OsStrExt, process::CommandExt},
+	},
+	path::{Path, PathBuf},
+	process::{Command, Stdio},
+};
+
+use oci_unpack::{resolve_in_rootfs, rootless::RootlessFiles};
+use syscalls::{syscall, Sysno};
+
+use crate::{Error, Result};
+
+const CLONE_NEWUSER: usize = 0x1000_0000;
+
+/// Ids of a container, mapped to host ids like in `/proc/<pid>/uid_map`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct IdMapping {
+	pub container_id: u32,
+	pub host_id: u32,
+	pub size: u32,
+}
+
+/// Mappings of user and group ids, which include subordinate ids of the invoking user.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct IdMap {
+	pub uids: Vec<IdMapping>,
+	pub gids: Vec<IdMapping>,
+}
+
+impl IdMap {
+	/// Map container root to the invoking user (`host_uid`, `host_gid`), and the other container ids
+	/// to a range of subordinate ids starting at `start` of `count` ids.
+	pub fn new(
+		(host_uid, host_gid): (u32, u32),
+		(uid_start, uid_count): (u32, u32),
+		(gid_start, gid_count): (u32, u32),
+	) -> Self {
+		let mappings = |host_id, start, count| {
+			vec![
+				IdMapping {
+					container_id: 0,
+					host_id,
+					size: 1,
+				},
+				IdMapping {
+					container_id: 1,
+					host_id: start,
+					size: count,
+				},
+			]
+		};
+		IdMap {
+			uids: mappings(host_uid, uid_start, uid_count),
+			gids: mappings(host_gid, gid_start, gid_count),
+		}
+	}
+
+	/// The subordinate ids of the invoking user, if it has some and `newuidmap` and `newgidmap` are installed.
+	pub fn subordinate() -> Option<Self> {
+		if find_program("newuidmap").is_none() || find_program("newgidmap").is_none() {
+			return None;
+		}
+		let host_uid = unsafe { syscall!(Sysno::geteuid) }.ok()? as u32;
+		let host_gid = unsafe { syscall!(Sysno::getegid) }.ok()? as u32;
+		let name = fs::read_to_string("/etc/passwd").ok().and_then(|passwd| {
+			(passwd.lines()).find_map(|line| {
+				let fields: Vec<_> = line.split(':').collect();
+				let uid = fields.get(2)?.parse::<u32>().ok()?;
+				(uid == host_uid).then(|| fields[0].to_owned())
+			})
+		});
+		let subids = |path: &str, id: u32| {
+			let content = fs::read_to_string(path).ok()?;
+			parse_subids(&content, name.as_deref(), id)
+		};
+		let uids = subids("/etc/subuid", host_uid)?;
+		let gids = subids("/etc/subgid", host_gid)?;
+		Some(IdMap::new((host_uid, host_gid), uids, gids))
+	}
+
+	/// Container id of the host id `host_id`, like the kernel maps file owners into the container.
+	pub fn container_id(mappings: &[IdMapping], host_id: u32) -> Option<u32> {
+		(mappings.iter()).find_map(|m| {
+			let offset = host_id.checked_sub(m.host_id)?;
+			(offset < m.size).then(|| m.container_id + offset)
+		})
+	}
+
+	/// Mappings in the form of the `uidMappings` and `gidMappings` of a container spec.
+	pub(crate) fn to_spec(mappings: &[IdMapping]) -> serde_json::Value {
+		(mappings.iter())
+			.map(
+				|m| serde_json::json!({"containerID": m.container_id, "hostID": m.host_id, "size": m.size}),
+			)
+			.collect()
+	}
+
+	/// Apply the owners and modes recorded by a rootless unpack to the files in `rootfs`.
+	///
+	/// Device nodes can't be created in a user namespace, so they stay empty files.
+	pub(crate) fn apply(&self, rootfs: &Path, files: &RootlessFiles) -> Result<()> {
+		let mut script = Vec::new();
+		let mut unmapped = 0;
+		for (path, attributes) in files {
+			let host_path = resolve_parent(rootfs, path)?;
+			let Ok(meta) = host_path.symlink_metadata() else {
+				continue;
+			};
+			let (uid, gid) = (attributes.uid, attributes.gid);
+			if !is_mapped(&self.uids, uid) || !is_mapped(&self.gids, gid) {
+				unmapped += 1;
+				continue;
+			}
+			write!(script, "chown -h {uid}:{gid} -- ").unwrap();
+			script.extend(quote(&host_path));
+			// Modes of symlinks are meaningless, and chmod would follow them.
+			// Chown clears setuid bits, so they are set afterwards.
+			if !meta.file_type().is_symlink() {
+				write!(script, "\nchmod {:o} -- ", attributes.mode).unwrap();
+				script.extend(quote(&host_path));
+			}
+			script.push(b'\n');
+		}
+		if unmapped > 0 {
+			warpforge_terminal::warn!(
+				"{unmapped} files of the image are owned by ids beyond the subordinate ids, they keep the invoking user as owner"
+			);
+		}
+		self.run_in_namespace(&script)
+			.map_err(|err| Error::SystemSetupError {
+				msg: "failed to apply the recorded owners of the image".into(),
+				cause: Box::new(err),
+			})
+	}
+
+	/// Make the invoking user own `dir` and everything in it again, including the files created by the container.
+	pub(crate) fn reclaim(&self, dir: &Path) -> Result<()> {
+		if !dir.exists() {
+			return Ok(());
+		}
+		let mut script = b"chown -R -h 0:0 -- ".to_vec();
+		script.extend(quote(dir));
+		script.push(b'\n');
+		self.run_in_namespace(&script)
+			.map_err(|err| Error::SystemSetupError {
+				msg: format!("failed to take back ownership of {}", dir.display()),
+				cause: Box::new(err),
+			})
+	}
+
+	/// Run a shell script as root of a user namespace with these mappings.
+	fn run_in_namespace(&self, script: &[u8]) -> io::Result<()> {
+		// The namespace is kept alive by a process waiting for its stdin to close.
+		let mut holder = Command::new("cat");
+		holder.stdin(Stdio::piped()).stdout(Stdio::null());
+		// SAFETY: unshare is async-signal-safe.
+		unsafe {
+			holder.pre_exec(|| match syscall!(Sysno::unshare, CLONE_NEWUSER) {
+				Ok(_) => Ok(()),
+				Err(errno) => Err(io::Error::from_raw_os_error(errno.into_raw())),
+			});
+		}
+		let mut holder = holder.spawn()?;
+		let result = self.run_in(holder.id(), script);
+		drop(holder.stdin.take());
+		holder.wait()?;
+		result
+	}
+
+	fn run_in(&self, pid: u32, script: &[u8]) -> io::Result<()> {
+		write_mappings(pid, "newuidmap", "uid_map", &self.uids)?;
+		write_mappings(pid, "newgidmap", "gid_map", &self.gids)?;
+
+		let namespace = fs::File::open(format!("/proc/{pid}/ns/user"))?;
+		let fd = namespace.as_raw_fd() as usize;
+		let mut shell = Command::new("sh");
+		shell.args(["-e", "-s"]).stdin(Stdio::piped());
+		// SAFETY: setns is async-signal-safe, and `namespace` outlives the child.
+		unsafe {
+			shell.pre_exec(move || match syscall!(Sysno::setns, fd, CLONE_NEWUSER) {
+				Ok(_) => Ok(()),
+				Err(errno) => Err(io::Error::from_raw_os_error(errno.into_raw())),
+			});
+		}
+		let mut shell = shell.spawn()?;
+		let written = shell.stdin.take().unwrap().write_all(script);
+		let status = shell.wait()?;
+		if !status.success() {
+			return Err(io::Error::other(format!("shell exited with {status}")));
+		}
+		written
+	}
+}
+
+/// Whether the container id `id` is mapped to a host id.
+fn is_mapped(mappings: &[IdMapping], id: u64) -> bool {
+	(mappings.iter()).any(|m| {
+		let start = u64::from(m.container_id);
+		(start..start + u64::from(m.size)).contains(&id)
+	})
+}
+
+/// Find the subordinate ids of the user `name` or `id` in the contents of `/etc/subuid` or `/etc/subgid`.
+/// Returns the first id and the number of ids.
+pub fn parse_subids(content: &str, name: Option<&str>, id: u32) -> Option<(u32, u32)> {
+	let id = id.to_string();
+	(content.lines()).find_map(|line| {
+		let mut fields = line.trim().split(':');
+		let owner = fields.next()?;
+		if owner != id && Some(owner) != name {
+			return None;
+		}
+		let start = fields.next()?.parse().ok()?;
+		let count: u32 = fields.next()?.parse().ok()?;
+		(count > 0).then_some((start, count))
+	})
+}
+
+/// Write the mappings of the user namespace of process `pid`.  Root writes them itself,
+/// other users need the setuid helper `program`, which checks the subordinate ids.
+fn write_mappings(pid: u32, program: &str, file: &str, mappings: &[IdMapping]) -> io::Result<()> {
+	if matches!(unsafe { syscall!(Sysno::geteuid) }, Ok(0)) {
+		let lines: String = (mappings.iter())
+			.map(|m| format!("{} {} {}\n", m.container_id, m.host_id, m.size))
+			.collect();
+		return fs::write(format!("/proc/{pid}/{file}"), lines);
+	}
+	let mut command = Command::new(program);
+	command.arg(pid.to_string());
+	for m in mappings {
+		command.args([m.container_id, m.host_id, m.size].map(|id| id.to_string()));
+	}
+	let status = command.status()?;
+	if !status.success() {
+		return Err(io::Error::other(format!("{program} exited with {status}")));
+	}
+	Ok(())
+}
+
+/// Resolve the parent of `path` inside of `rootfs`, but not `path` itself, which may be a symlink.
+fn resolve_parent(rootfs: &Path, path: &Path) -> Result<PathBuf> {
+	let parent = path.parent().unwrap_or(Path::new("/"));
+	let parent = resolve_in_rootfs(rootfs, parent).map_err(|err| Error::SystemSetupError {
+		msg: format!("failed to resolve {} in the image", path.display()),
+		cause: Box::new(err),
+	})?;
+	Ok(match path.file_name() {
+		Some(name) => parent.join(name),
+		None => parent,
+	})
+}
+
+/// Quote a path for the shell, which takes any bytes but `'` literally inside single quotes.
+fn quote(path: &Path) -> Vec<u8> {
+	let mut quoted = vec![b'\''];
+	for &byte in path.as_os_str().as_bytes() {
+		match byte {
+			b'\'' => quoted.extend(br"'\''"),
+			_ => quoted.push(byte),
+		}
+	}
+	quoted.push(b'\'');
+	quoted
+}
+
+fn find_program(name: &str) -> Option<PathBuf> {
+	let path = std::env::var_os("PATH")?;
+	(std::env::split_paths(&path))
+		.map(|dir| dir.join(name))
+		.find(|candidate| candidate.is_file())
+}
@@ -0,0 +1,137 @@
+//! Applying the configuration of an image (`Env`, `WorkingDir` and `User`) to its containers.
+
+use std::{fs, path::Path};
+
+use indexmap::IndexMap;
+use oci_unpack::ImageConfiguration;
+
+use crate::{Error, Result};
+
+/// How the image expects its process to be started.
+#[derive(PartialEq, Debug)]
+pub(crate) struct ImageProcess {
+	pub environment: IndexMap<String, String>,
+	pub cwd: String,
+	pub uid: u32,
+	pub gid: u32,
+}
+
+impl ImageProcess {
+	/// Read the process configuration from the image config.
+	/// User and group names are looked up in the image's `/etc/passwd` and `/etc/group`.
+	pub fn new(config: &ImageConfiguration, rootfs: &Path) -> Result<Self> {
+		let Some(config) = config.config() else {
+			return Ok(Self::default());
+		};
+
+		let mut environment = IndexMap::new();
+		for var in config.env().iter().flatten() {
+			// Variables without value are set to the empty string, like docker does.
+			let (name, value) = var.split_once('=').unwrap_or((var, ""));
+			environment.insert(name.to_owned(), value.to_owned());
+		}
+
+		let cwd = (config.working_dir().as_deref())
+			.filter(|dir| !dir.is_empty())
+			.unwrap_or("/");
+		if !cwd.starts_with('/') {
+			let msg = format!("image working directory '{cwd}' is not absolute");
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+
+		let (uid, gid) = match config.user().as_deref() {
+			Some(user) if !user.is_empty() => resolve_user(rootfs, user)?,
+			_ => (0, 0),
+		};
+
+		Ok(Self {
+			environment,
+			cwd: cwd.to_owned(),
+			uid,
+			gid,
+		})
+	}
+}
+
+impl Default for ImageProcess {
+	fn default() -> Self {
+		Self {
+			environment: IndexMap::new(),
+			cwd: "/".into(),
+			uid: 0,
+			gid: 0,
+		}
+	}
+}
+
+/// Resolve a user of the form `user`, `user:group`, `uid` or `uid:gid` to numeric ids.
+/// Without a group, the primary group of the user is used.
+pub(crate) fn resolve_user(rootfs: &Path, user: &str) -> Result<(u32, u32)> {
+	let (user, group) = match user.split_once(':') {
+		Some((user, group)) => (user, Some(group)),
+		None => (user, None),
+	};
+
+	let passwd = read_database(rootfs, "passwd")?;
+	let numeric_uid = user.parse::<u32>().ok();
+	// Fields of /etc/passwd: name, password, uid, gid, ...
+	let key = if numeric_uid.is_some() { 2 } else { 0 };
+	let entry = (passwd.iter()).find(|fields| fields.get(key).map(String::as_str) == Some(user));
+	let uid = match numeric_uid.or_else(|| entry?.get(2)?.parse().ok()) {
+		Some(uid) => uid,
+		None => {
+			let msg = format!("image user '{user}' not found in /etc/passwd");
+			return Err(Error::SystemSetupCauseless { msg });
+		}
+	};
+
+	let gid = match group {
+		Some(group) => match group.parse() {
+			Ok(gid) => gid,
+			Err(_) => {
+				let groups = read_database(rootfs, "group")?;
+				// Fields of /etc/group: name, password, gid, ...
+				let gid = (groups.iter())
+					.find(|fields| fields.first().map(String::as_str) == Some(group))
+					.and_then(|fields| fields.get(2)?.parse().ok());
+				let Some(gid) = gid else {
+					let msg = format!("image group '{group}' not found in /etc/group");
+					return Err(Error::SystemSetupCauseless { msg });
+				};
+				gid
+			}
+		},
+		// Numeric users do not need to exist, they keep group 0 then.
+		None => entry
+			.and_then(|fields| fields.get(3)?.parse().ok())
+			.unwrap_or(0),
+	};
+
+	Ok((uid, gid))
+}
+
+/// Read a colon separated database like `/etc/passwd` from the image.  Missing files are empty.
+/// Symlinks are resolved inside of the image, so they can't point to the host's databases.
+fn read_database(rootfs: &Path, name: &str) -> Result<Vec<Vec<String>>> {
+	let path =
+		oci_unpack::resolve_in_rootfs(rootfs, &Path::new("/etc").join(name)).map_err(|err| {
+			Error::SystemSetupError {
+				msg: format!("failed to resolve /etc/{name} of image"),
+				cause: Box::new(err),
+			}
+		})?;
+	let content = match fs::read_to_string(&path) {
+		Ok(content) => content,
+		Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+		Err(err) => {
+			return Err(Error::SystemSetupError {
+				msg: format!("failed to read /etc/{name} of image"),
+				cause: Box::new(err),
+			})
+		}
+	};
+	Ok((content.lines())
+		.filter(|line| !line.is_empty() && !line.starts_with('#'))
+		.map(|line| line.split(':').map(str::to_owned).collect())
+		.collect())
+}
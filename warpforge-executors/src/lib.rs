@@ -3,15 +3,22 @@ use std::path::{Path, PathBuf};
 
 use context::Context;
 use indexmap::IndexMap;
+use runtime::Runtime;
+use warpforge_api::content::{Packtype, WareID};
 
+pub mod cancel;
 pub mod context;
 mod errors;
 mod events;
 pub mod execute;
 pub mod formula;
+pub mod idmap;
+mod image;
+pub mod limits;
 mod oci;
 mod pack;
 pub mod plot;
+pub mod runtime;
 
 #[cfg(test)]
 mod tests;
@@ -29,18 +36,41 @@ pub use events::Event;
 pub struct ContainerParams {
 	ident: String,
 	/// OCI compatible container runtime.
-	runtime: PathBuf,
+	runtime: Runtime,
 	command: Vec<String>,
 	/// Mounts, mapped by destination.
 	mounts: IndexMap<String, MountSpec>,
 	environment: IndexMap<String, String>,
 	root_path: PathBuf,
+	/// Working directory of the process.
+	cwd: String,
+	uid: u32,
+	gid: u32,
+	/// User supplied patches of the container spec, see [Context::spec_patches].
+	spec_patches: Vec<json_patch::Patch>,
+	limits: limits::Limits,
+	/// Attach the container to a terminal, for interactive use.
+	terminal: bool,
+	/// Subordinate ids, which the owners recorded by a rootless unpack were applied with.
+	id_map: Option<idmap::IdMap>,
 }
 
 #[derive(PartialEq, Hash, Clone, Debug)]
 pub struct Output {
 	pub name: String,
 	pub digest: Digest,
+	/// Packtype of the ware. Outputs that are not packed are hashed as "tar".
+	pub packtype: Packtype,
+}
+
+impl Output {
+	pub fn ware_id(&self) -> WareID {
+		let Digest::Sha384(hash) = &self.digest;
+		WareID {
+			packtype: self.packtype.clone(),
+			hash: hash.clone(),
+		}
+	}
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -0,0 +1,176 @@
+//! Resource limits of containers.
+//!
+//! Memory, CPU and process limits are enforced by cgroups, which aren't always available:
+//! rootless containers can only use them if the invoking user was delegated a cgroup (e.g. by systemd).
+//! Without cgroups, these limits are skipped with a warning.
+//! Rlimits, like the number of open files, and the timeout are always enforced.
+
+use std::{fs, os::unix::fs::MetadataExt, path::Path, time::Duration};
+
+use serde_json::json;
+use warpforge_terminal::warn;
+
+use crate::Error;
+
+/// Default limit of open files in a container.
+pub const DEFAULT_OPEN_FILES: u64 = 1024;
+
+/// Period of the CPU bandwidth controller, in microseconds.
+const CPU_PERIOD: u64 = 100_000;
+
+/// Smallest quota the kernel accepts, in microseconds.
+const MIN_CPU_QUOTA: u64 = 1_000;
+
+#[derive(Clone, Default, PartialEq, Debug)]
+pub struct Limits {
+	/// Wall-clock time after which the container is killed.
+	pub timeout: Option<Duration>,
+	/// Memory in bytes, including swap.
+	pub memory: Option<u64>,
+	/// Number of CPUs the container may use, e.g. `1.5`.
+	pub cpus: Option<f64>,
+	/// Number of processes and threads.
+	pub pids: Option<u64>,
+	/// Number of open files ([DEFAULT_OPEN_FILES] if not set).
+	pub open_files: Option<u64>,
+}
+
+impl Limits {
+	fn needs_cgroups(&self) -> bool {
+		self.memory.is_some() || self.cpus.is_some() || self.pids.is_some()
+	}
+
+	/// Add the limits to a container spec.  Fails if a limit can't be enforced, like too few CPUs.
+	pub(crate) fn patch_spec(&self, spec: &mut serde_json::Value) -> crate::Result<()> {
+		if let Some(cpus) = self.cpus {
+			check_cpus(cpus).map_err(|msg| Error::SystemSetupCauseless { msg })?;
+		}
+
+		let open_files = self.open_files.unwrap_or(DEFAULT_OPEN_FILES);
+		spec["process"]["rlimits"] = json!([{
+			"type": "RLIMIT_NOFILE",
+			"hard": open_files,
+			"soft": open_files,
+		}]);
+
+		if !self.needs_cgroups() {
+			return Ok(());
+		}
+		if !cgroups_available() {
+			warn!("cgroups are not available: memory, cpu and pids limits are not enforced");
+			return Ok(());
+		}
+		let mut resources = json!({});
+		if let Some(memory) = self.memory {
+			resources["memory"] = json!({ "limit": memory, "swap": memory });
+		}
+		if let Some(cpus) = self.cpus {
+			let quota = (cpus * CPU_PERIOD as f64).round() as u64;
+			resources["cpu"] = json!({ "quota": quota, "period": CPU_PERIOD });
+		}
+		if let Some(pids) = self.pids {
+			resources["pids"] = json!({ "limit": pids });
+		}
+		spec["linux"]["resources"] = resources;
+		Ok(())
+	}
+}
+
+/// Whether the runtime can create cgroups for containers.
+///
+/// Root can always use them.  Other users need a delegated cgroup v2 hierarchy,
+/// i.e. the cgroup of this process is owned by them.
+pub(crate) fn cgroups_available() -> bool {
+	use syscalls::{syscall, Sysno};
+	let Ok(euid) = (unsafe { syscall!(Sysno::geteuid) }) else {
+		return false;
+	};
+	if euid == 0 {
+		return true;
+	}
+	let Ok(cgroups) = fs::read_to_string("/proc/self/cgroup") else {
+		return false;
+	};
+	// With cgroup v2, there is a single line: "0::/path/of/the/cgroup".
+	let Some(path) = cgroups.lines().find_map(|line| line.strip_prefix("0::")) else {
+		return false;
+	};
+	let dir = Path::new("/sys/fs/cgroup").join(path.trim_start_matches('/'));
+	(dir.metadata()).is_ok_and(|meta| meta.uid() as usize == euid)
+}
+
+/// Parse a duration like "90s", "15m", "2h" or "1h30m".  Plain numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+	let invalid = || format!("invalid duration '{s}', expected e.g. '90s', '15m' or '1h30m'");
+	if let Ok(seconds) = s.parse::<u64>() {
+		return Ok(Duration::from_secs(seconds));
+	}
+
+	if s.is_empty() {
+		return Err(invalid());
+	}
+	let mut total = 0u64;
+	let mut rest = s;
+	while !rest.is_empty() {
+		let digits = rest
+			.find(|c: char| !c.is_ascii_digit())
+			.ok_or_else(invalid)?;
+		let value: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+		let unit = match rest[digits..].chars().next() {
+			Some('s') => 1,
+			Some('m') => 60,
+			Some('h') => 60 * 60,
+			Some('d') => 24 * 60 * 60,
+			_ => return Err(invalid()),
+		};
+		total = (value.checked_mul(unit))
+			.and_then(|seconds| total.checked_add(seconds))
+			.ok_or_else(invalid)?;
+		rest = &rest[digits + 1..]; // Units are ASCII.
+	}
+	Ok(Duration::from_secs(total))
+}
+
+/// Parse a size in bytes like "512M" or "2G", with binary units.  Plain numbers are bytes.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+	let invalid = || format!("invalid size '{s}', expected e.g. '512M' or '2G'");
+	let (digits, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+		Some(index) => s.split_at(index),
+		None => (s, ""),
+	};
+	let shift = match unit {
+		"" => 0,
+		"K" | "k" => 10,
+		"M" | "m" => 20,
+		"G" | "g" => 30,
+		"T" | "t" => 40,
+		_ => return Err(invalid()),
+	};
+	let value: u64 = digits.parse().map_err(|_| invalid())?;
+	value.checked_mul(1 << shift).ok_or_else(invalid)
+}
+
+/// Parse a number of CPUs like "2" or "0.5".
+pub fn parse_cpus(s: &str) -> Result<f64, String> {
+	let cpus = s
+		.parse::<f64>()
+		.map_err(|_| format!("invalid number of CPUs '{s}', expected e.g. '2' or '0.5'"))?;
+	check_cpus(cpus)?;
+	Ok(cpus)
+}
+
+/// Check that the kernel can enforce a limit of `cpus`: quotas below [MIN_CPU_QUOTA] are rejected.
+pub fn check_cpus(cpus: f64) -> Result<(), String> {
+	if !(cpus.is_finite() && cpus > 0.0) {
+		return Err(format!(
+			"invalid number of CPUs '{cpus}', expected e.g. '2' or '0.5'"
+		));
+	}
+	if cpus * (CPU_PERIOD as f64) < MIN_CPU_QUOTA as f64 {
+		let min = MIN_CPU_QUOTA as f64 / CPU_PERIOD as f64;
+		return Err(format!(
+			"number of CPUs '{cpus}' is too small, the minimum is {min}"
+		));
+	}
+	Ok(())
+}
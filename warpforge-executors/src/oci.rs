@@ -23,7 +23,7 @@ impl ToOCIMount for crate::MountSpec {
 // (Rust has a "lazy_static" feature, but it's a crate rather than core, and doesn't seem essential here.)
 //
 // We use json values because that's what they are when they get sent to the subprocesses.
-// We also (plan to -- future work) let users amend these values by a simple JSON Patch API.
+// We also let users amend these values by a simple JSON Patch API (see `Context::spec_patches`).
 // So, overall, KISS means "just treat it like JSON all the way through".
 //
 // (Yes, there is a crate for OCI spec stuff: https://github.com/containers/oci-spec-rs --
@@ -94,3 +94,95 @@ pub fn oci_spec_base() -> serde_json::Value {
 		}
 	})
 }
+
+/// Check the parts of a container spec which runtimes require, after users patched it.
+///
+/// This isn't a full validation of the OCI runtime spec, it catches patches that
+/// removed or mangled required values, before a runtime fails with a less helpful message.
+pub fn validate_spec(spec: &serde_json::Value) -> Result<(), String> {
+	use serde_json::Value;
+
+	let is_absolute = |value: &Value| value.as_str().is_some_and(|path| path.starts_with('/'));
+	let is_string_array = |value: &Value| {
+		(value.as_array()).is_some_and(|values| values.iter().all(Value::is_string))
+	};
+	let is_id = |value: &Value| value.as_u64().is_some_and(|id| id <= u32::MAX.into());
+
+	if !spec["ociVersion"].is_string() {
+		return Err("'ociVersion' must be a string".into());
+	}
+
+	let process = &spec["process"];
+	if !is_string_array(&process["args"]) || process["args"].as_array().unwrap().is_empty() {
+		return Err("'process.args' must be a non-empty list of strings".into());
+	}
+	if !is_absolute(&process["cwd"]) {
+		return Err("'process.cwd' must be an absolute path".into());
+	}
+	if !is_id(&process["user"]["uid"]) || !is_id(&process["user"]["gid"]) {
+		return Err("'process.user' must have a numeric 'uid' and 'gid'".into());
+	}
+	if !process["env"].is_null() {
+		let valid = (process["env"].as_array()).is_some_and(|env| {
+			env.iter()
+				.all(|var| var.as_str().is_some_and(|var| var.contains('=')))
+		});
+		if !valid {
+			return Err("'process.env' must be a list of 'NAME=value' strings".into());
+		}
+	}
+
+	if spec["root"]["path"].as_str().unwrap_or_default().is_empty() {
+		return Err("'root.path' must be a non-empty string".into());
+	}
+
+	if !spec["mounts"].is_null() {
+		let Some(mounts) = spec["mounts"].as_array() else {
+			return Err("'mounts' must be a list".into());
+		};
+		for (index, mount) in mounts.iter().enumerate() {
+			if !is_absolute(&mount["destination"]) {
+				return Err(format!(
+					"'mounts[{index}].destination' must be an absolute path"
+				));
+			}
+			if !mount["options"].is_null() && !is_string_array(&mount["options"]) {
+				return Err(format!(
+					"'mounts[{index}].options' must be a list of strings"
+				));
+			}
+		}
+	}
+
+	let linux = &spec["linux"];
+	if !linux["namespaces"].is_null() {
+		let known = [
+			"pid", "network", "mount", "ipc", "uts", "user", "cgroup", "time",
+		];
+		let valid = (linux["namespaces"].as_array()).is_some_and(|namespaces| {
+			(namespaces.iter()).all(|ns| ns["type"].as_str().is_some_and(|t| known.contains(&t)))
+		});
+		if !valid {
+			return Err(format!(
+				"'linux.namespaces' must be a list of namespaces with a type of: {}",
+				known.join(", ")
+			));
+		}
+	}
+	for mappings in ["uidMappings", "gidMappings"] {
+		if linux[mappings].is_null() {
+			continue;
+		}
+		let valid = (linux[mappings].as_array()).is_some_and(|mappings| {
+			(mappings.iter())
+				.all(|m| is_id(&m["containerID"]) && is_id(&m["hostID"]) && is_id(&m["size"]))
+		});
+		if !valid {
+			return Err(format!(
+				"'linux.{mappings}' must be a list of mappings with a numeric 'containerID', 'hostID' and 'size'"
+			));
+		}
+	}
+
+	Ok(())
+}
@@ -7,9 +7,10 @@ use std::{
 use flate2::{write::GzEncoder, Compression};
 use oci_unpack::tee::WriteExt;
 use sha2::{Digest, Sha384};
+use tempfile::NamedTempFile;
 use warpforge_api::content::Packtype;
 
-use crate::{Error, Output, Result};
+use crate::{context::Context, Error, Output, Result};
 
 pub(crate) struct IntermediateOutput {
 	pub(crate) name: String,
@@ -34,10 +35,19 @@ impl OutputPacktype {
 			}
 		})
 	}
+
+	/// Packtype of the resulting ware.
+	/// Unpacked outputs are hashed (and stored in warehouses) as tar archive.
+	fn ware_packtype(&self) -> Packtype {
+		match self {
+			OutputPacktype::None => Packtype("tar".into()),
+			OutputPacktype::TarGzip => Packtype("tgz".into()),
+		}
+	}
 }
 
 pub(crate) fn pack_outputs(
-	output_dir: &Option<PathBuf>,
+	context: &Context,
 	outputs: &[IntermediateOutput],
 ) -> Result<Vec<Output>> {
 	if outputs.is_empty() {
@@ -46,7 +56,14 @@ pub(crate) fn pack_outputs(
 
 	let mut results = Vec::new();
 
-	let target_dir = output_dir.clone().unwrap_or_default();
+	if let Some(warehouse) = &context.warehouse {
+		fs::create_dir_all(warehouse).map_err(|err| Error::SystemRuntimeError {
+			msg: "failed to create warehouse directory".into(),
+			cause: Box::new(err),
+		})?;
+	}
+
+	let target_dir = context.output_path.clone().unwrap_or_default();
 	fs::create_dir_all(&target_dir).map_err(|err| Error::SystemRuntimeError {
 		msg: "failed to create directory".into(),
 		cause: Box::new(err),
@@ -67,9 +84,18 @@ pub(crate) fn pack_outputs(
 					msg: "failed to move output dir to target".into(),
 					cause: Box::new(err),
 				})?;
-				tar_dir_hash_only(name, target)?
+				match &context.warehouse {
+					Some(warehouse) => tar_dir_to_warehouse(name, target, warehouse)?,
+					None => tar_dir_hash_only(name, target)?,
+				}
+			}
+			OutputPacktype::TarGzip => {
+				let output = tgz_dir_to_file(name, host_path, &target)?;
+				if let Some(warehouse) = &context.warehouse {
+					copy_to_warehouse(&target, warehouse, &output.digest)?;
+				}
+				output
 			}
-			OutputPacktype::TarGzip => tgz_dir_to_file(name, host_path, &target)?,
 		};
 		results.push(output);
 	}
@@ -77,13 +103,100 @@ pub(crate) fn pack_outputs(
 	Ok(results)
 }
 
+/// Path of a ware inside of a local warehouse.
+///
+/// Wares are sharded by the hex encoded sha384 of their ware ID: `<hash[..3]>/<hash[3..6]>/<hash>`.
+/// The sharding follows the warpsys warehouses, but those name wares by their base58 hash,
+/// so the two layouts are not interchangeable.
+pub(crate) fn warehouse_path(warehouse: impl AsRef<Path>, digest: &crate::Digest) -> PathBuf {
+	let crate::Digest::Sha384(hash) = digest;
+	warehouse
+		.as_ref()
+		.join(hash.get(..3).unwrap_or_default())
+		.join(hash.get(3..6).unwrap_or_default())
+		.join(hash)
+}
+
 pub(crate) fn tar_dir_hash_only(name: &str, source_dir: impl AsRef<Path>) -> Result<Output> {
 	let mut digester = Sha384::new();
 	tar_dir(&source_dir, &mut digester)?;
 
 	let digest = crate::Digest::Sha384(format!("{:x}", digester.finalize()));
 	let name = name.to_owned();
-	Ok(Output { name, digest })
+	let packtype = OutputPacktype::None.ware_packtype();
+	Ok(Output {
+		name,
+		digest,
+		packtype,
+	})
+}
+
+/// Like [tar_dir_hash_only], but additionally stores the tar archive in the warehouse.
+pub(crate) fn tar_dir_to_warehouse(
+	name: &str,
+	source_dir: impl AsRef<Path>,
+	warehouse: impl AsRef<Path>,
+) -> Result<Output> {
+	let temp_file = NamedTempFile::new_in(&warehouse).map_err(|err| Error::SystemRuntimeError {
+		msg: "failed to create temporary file in warehouse".into(),
+		cause: Box::new(err),
+	})?;
+
+	let mut digester = Sha384::new();
+	let mut writer = BufWriter::new(temp_file.as_file()).tee(&mut digester);
+	tar_dir(&source_dir, &mut writer)?;
+	writer.flush().map_err(|err| Error::SystemRuntimeError {
+		msg: "failed to write ware to warehouse".into(),
+		cause: Box::new(err),
+	})?;
+	drop(writer);
+
+	let digest = crate::Digest::Sha384(format!("{:x}", digester.finalize()));
+	let ware_path = warehouse_path(&warehouse, &digest);
+	if !ware_path.exists() {
+		create_parent_dir(&ware_path)?;
+		temp_file
+			.persist(&ware_path)
+			.map_err(|err| Error::SystemRuntimeError {
+				msg: "failed to move ware into warehouse".into(),
+				cause: Box::new(err),
+			})?;
+	}
+
+	let name = name.to_owned();
+	let packtype = OutputPacktype::None.ware_packtype();
+	Ok(Output {
+		name,
+		digest,
+		packtype,
+	})
+}
+
+fn copy_to_warehouse(
+	file: impl AsRef<Path>,
+	warehouse: impl AsRef<Path>,
+	digest: &crate::Digest,
+) -> Result<()> {
+	let ware_path = warehouse_path(warehouse, digest);
+	if ware_path.exists() {
+		return Ok(()); // Content addressed: same path means same content.
+	}
+	create_parent_dir(&ware_path)?;
+	fs::copy(file, ware_path).map_err(|err| Error::SystemRuntimeError {
+		msg: "failed to copy ware into warehouse".into(),
+		cause: Box::new(err),
+	})?;
+	Ok(())
+}
+
+fn create_parent_dir(path: &Path) -> Result<()> {
+	let Some(parent) = path.parent() else {
+		return Ok(());
+	};
+	fs::create_dir_all(parent).map_err(|err| Error::SystemRuntimeError {
+		msg: "failed to create directory".into(),
+		cause: Box::new(err),
+	})
 }
 
 pub(crate) fn tgz_dir_to_file(
@@ -107,7 +220,12 @@ pub(crate) fn tgz_dir_to_file(
 
 	let digest = crate::Digest::Sha384(format!("{:x}", digester.finalize()));
 	let name = name.to_owned();
-	Ok(Output { name, digest })
+	let packtype = OutputPacktype::TarGzip.ware_packtype();
+	Ok(Output {
+		name,
+		digest,
+		packtype,
+	})
 }
 
 pub(crate) fn tar_dir(source_dir: impl AsRef<Path>, writer: impl Write) -> Result<()> {
@@ -1,6 +1,6 @@
 use indexmap::{IndexMap, IndexSet};
 use oci_client::Reference;
-use oci_unpack::{pull_image_manifest, PullConfig};
+use oci_unpack::pull_image_manifest;
 use tempfile::TempDir;
 use warpforge_api::formula::{
 	Formula, FormulaAndContext, FormulaCapsule, FormulaContext, FormulaContextCapsule,
@@ -59,6 +59,7 @@ impl<'a> PlotExecutor<'a> {
 		// TODO: Run multiple steps in parallel, when possible.
 		let mut completed_count = 0;
 		while let Some(step_name) = next_steps.pop() {
+			self.context.cancellation.check()?;
 			progress.set_text(step_name);
 
 			self.run_step(step_name)?;
@@ -103,7 +104,7 @@ impl<'a> PlotExecutor<'a> {
 			});
 		}
 
-		pack_outputs(&self.context.output_path, &outputs)
+		pack_outputs(self.context, &outputs)
 	}
 
 	fn run_step(&self, step_name: &str) -> Result<()> {
@@ -114,6 +115,8 @@ impl<'a> PlotExecutor<'a> {
 		let step_dir = self.temp_dir.path().join(step_name);
 		let context = Context {
 			output_path: Some(step_dir.join(OUTPUTS_DIR)),
+			// Only the outputs of the whole plot are stored in the warehouse.
+			warehouse: None,
 			..self.context.clone()
 		};
 
@@ -195,6 +198,7 @@ impl<'a> PlotExecutor<'a> {
 			let Output {
 				name,
 				digest: crate::Digest::Sha384(digest),
+				..
 			} = output;
 			logln!("  sha384:{digest} {name}");
 		}
@@ -208,27 +212,94 @@ impl<'a> PlotExecutor<'a> {
 			return Err(Error::SystemSetupCauseless { msg });
 		}
 
-		let mut reference: Reference =
-			(reference.parse()).map_err(|err| Error::SystemSetupError {
-				msg: "failed to parse image reference".into(),
-				cause: Box::new(err),
-			})?;
-
-		// Resolve digest if it was not specified.
-		if reference.digest().is_none() {
-			let pull_config = PullConfig {
-				cache: self.context.image_cache.clone(),
-				..PullConfig::default()
-			};
-			let digest = pull_image_manifest(&reference, &pull_config).map_err(|err| {
-				let msg = "failed to resolve OCI Reference".into();
-				let cause = Box::new(err);
-				Error::SystemSetupError { msg, cause }
-			})?;
-			reference = reference.clone_with_digest(digest);
+		let reference = resolve_reference(reference, self.context)?;
+		Ok(FormulaInput::OCIReference(reference))
+	}
+}
+
+/// Resolve the digest of an image reference, if it was not specified.
+fn resolve_reference(reference: &str, context: &Context) -> Result<String> {
+	let reference: Reference = (reference.parse()).map_err(|err| Error::SystemSetupError {
+		msg: "failed to parse image reference".into(),
+		cause: Box::new(err),
+	})?;
+	if reference.digest().is_some() {
+		return Ok(reference.to_string());
+	}
+
+	let pull_config = context.pull_config()?;
+	let digest = pull_image_manifest(&reference, &pull_config).map_err(|err| {
+		let msg = "failed to resolve OCI Reference".into();
+		let cause = Box::new(err);
+		Error::SystemSetupError { msg, cause }
+	})?;
+	Ok(reference.clone_with_digest(digest).to_string())
+}
+
+/// Copy of a plot with all 'oci' inputs pinned to the digest their tags currently resolve to.
+///
+/// Running the pinned plot later uses the same images, even when the tags were moved in the meantime.
+pub fn pin_plot(plot: &PlotCapsule, context: &Context) -> Result<PlotCapsule> {
+	let PlotCapsule::V1(plot) = plot;
+	Ok(PlotCapsule::V1(pin_plot_inputs(plot, context)?))
+}
+
+fn pin_plot_inputs(plot: &Plot, context: &Context) -> Result<Plot> {
+	let pin = |input: &PlotInput| match input {
+		PlotInput::OCIReference(reference) => Ok(PlotInput::OCIReference(resolve_reference(
+			reference, context,
+		)?)),
+		input => Ok(input.clone()),
+	};
+
+	let mut plot = plot.clone();
+	for input in plot.inputs.values_mut() {
+		*input = pin(input)?;
+	}
+	for step in plot.steps.values_mut() {
+		match step {
+			Step::Plot(sub_plot) => *sub_plot = pin_plot_inputs(sub_plot, context)?,
+			Step::Protoformula(protoformula) => {
+				for input in protoformula.inputs.values_mut() {
+					*input = pin(input)?;
+				}
+			}
 		}
+	}
+	Ok(plot)
+}
+
+/// Inputs of a plot which depend on the host, i.e. 'mount' and 'ingest' inputs, as "step: port=input".
+/// Plot inputs are listed without a step.
+pub fn host_inputs(plot: &PlotCapsule) -> Vec<String> {
+	let PlotCapsule::V1(plot) = plot;
+	let mut found = Vec::new();
+	collect_host_inputs(plot, "", &mut found);
+	found
+}
+
+fn collect_host_inputs(plot: &Plot, prefix: &str, found: &mut Vec<String>) {
+	let is_host_input =
+		|input: &PlotInput| matches!(input, PlotInput::Mount(_) | PlotInput::Ingest(_));
 
-		Ok(FormulaInput::OCIReference(reference.to_string()))
+	for (label, input) in &plot.inputs {
+		if is_host_input(input) {
+			found.push(format!("{prefix}{label}={input}"));
+		}
+	}
+	for (step_name, step) in &plot.steps {
+		match step {
+			Step::Plot(sub_plot) => {
+				collect_host_inputs(sub_plot, &format!("{prefix}{step_name}: "), found)
+			}
+			Step::Protoformula(protoformula) => {
+				for (port, input) in &protoformula.inputs {
+					if is_host_input(input) {
+						found.push(format!("{prefix}{step_name}: {port}={input}"));
+					}
+				}
+			}
+		}
 	}
 }
 
@@ -0,0 +1,204 @@
+//! Profiles of the OCI runtimes we know how to drive.
+//!
+//! Runtimes differ in their command line, and in what they expect from the container spec.
+//! For example, `runc` and `crun` need a user namespace with id mappings to run rootless,
+//! while gVisor's `runsc` ignores the mappings and fails to launch its gofer in a user namespace.
+
+use std::{
+	ffi::OsString,
+	fmt,
+	path::{Path, PathBuf},
+	process::Command,
+	str::FromStr,
+};
+
+use str_cat::os_str_cat;
+
+use crate::idmap::IdMap;
+
+/// The runtimes with a known profile.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RuntimeProfile {
+	Runc,
+	Crun,
+	Gvisor,
+}
+
+impl RuntimeProfile {
+	pub const ALL: [RuntimeProfile; 3] = [Self::Runc, Self::Crun, Self::Gvisor];
+
+	pub fn name(self) -> &'static str {
+		match self {
+			Self::Runc => "runc",
+			Self::Crun => "crun",
+			Self::Gvisor => "gvisor",
+		}
+	}
+
+	/// Name of the runtime's executable.
+	pub fn program(self) -> &'static str {
+		match self {
+			Self::Runc => "runc",
+			Self::Crun => "crun",
+			Self::Gvisor => "runsc",
+		}
+	}
+
+	fn from_name(name: &str) -> Option<Self> {
+		(Self::ALL.into_iter()).find(|profile| profile.name() == name || profile.program() == name)
+	}
+}
+
+impl fmt::Display for RuntimeProfile {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(self.name())
+	}
+}
+
+/// An OCI runtime executable, and the profile used to drive it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Runtime {
+	pub profile: RuntimeProfile,
+	pub program: PathBuf,
+}
+
+impl Runtime {
+	pub fn new(profile: RuntimeProfile) -> Self {
+		Self {
+			profile,
+			program: profile.program().into(),
+		}
+	}
+
+	/// Command running the bundle in `bundle_dir` as container `ident`.
+	pub(crate) fn run_command(&self, log_file: &Path, bundle_dir: &Path, ident: &str) -> Command {
+		let mut cmd = Command::new(&self.program);
+		cmd.args(self.global_args(log_file));
+		cmd.arg("run");
+		cmd.arg(os_str_cat!("--bundle=", bundle_dir));
+		cmd.arg(ident); // container name.
+		cmd
+	}
+
+	/// Command killing the processes of container `ident`.
+	pub(crate) fn kill_command(&self, log_file: &Path, ident: &str) -> Command {
+		let mut cmd = Command::new(&self.program);
+		cmd.args(self.global_args(log_file));
+		cmd.args(["kill", "--all", ident, "KILL"]);
+		cmd
+	}
+
+	/// Command removing container `ident` and its state, even if it's still running.
+	pub(crate) fn delete_command(&self, log_file: &Path, ident: &str) -> Command {
+		let mut cmd = Command::new(&self.program);
+		cmd.args(self.global_args(log_file));
+		cmd.args(["delete", "--force", ident]);
+		cmd
+	}
+
+	fn global_args(&self, log_file: &Path) -> Vec<OsString> {
+		match self.profile {
+			RuntimeProfile::Runc | RuntimeProfile::Crun => {
+				vec![os_str_cat!("--log=", log_file), "--debug".into()]
+			}
+			RuntimeProfile::Gvisor => {
+				let mut args = vec![
+					os_str_cat!("--debug-log=", log_file),
+					"--debug".into(),
+					// Like runc without a network namespace, containers share the host network.
+					"--network=host".into(),
+				];
+				if !is_root() {
+					args.push("--rootless".into());
+				}
+				args
+			}
+		}
+	}
+
+	/// Adapt the container spec to the runtime.
+	///
+	/// Run by root, all ids are mapped to themselves, so the ownership of the unpacked image is kept.
+	/// Otherwise, the ids of `id_map` are used, which apply the ownership recorded by a rootless unpack
+	/// (see [crate::idmap]).  Without it, the container user (`uid`, `gid`) is mapped to the invoking user
+	/// (`host_uid`, `host_gid`), as it's the only id available without privileges.
+	pub(crate) fn patch_spec(
+		&self,
+		spec: &mut serde_json::Value,
+		(uid, gid): (u32, u32),
+		(host_uid, host_gid): (usize, usize),
+		id_map: Option<&IdMap>,
+	) {
+		let (uid_mappings, gid_mappings) = if host_uid == 0 {
+			let identity = serde_json::json!([{"containerID": 0, "hostID": 0, "size": u32::MAX}]);
+			(identity.clone(), identity)
+		} else if let Some(id_map) = id_map {
+			(IdMap::to_spec(&id_map.uids), IdMap::to_spec(&id_map.gids))
+		} else {
+			(
+				serde_json::json!([{"containerID": uid, "hostID": host_uid, "size": 1}]),
+				serde_json::json!([{"containerID": gid, "hostID": host_gid, "size": 1}]),
+			)
+		};
+		let patch = match self.profile {
+			RuntimeProfile::Runc | RuntimeProfile::Crun => serde_json::json!([
+				{ "op": "add", "path": "/linux/uidMappings", "value": uid_mappings},
+				{ "op": "add", "path": "/linux/gidMappings", "value": gid_mappings},
+				{ "op": "add", "path": "/linux/namespaces/-", "value": {"type": "user"}},
+			]),
+			// gVisor runs the container in its own kernel, which takes care of users itself.
+			RuntimeProfile::Gvisor => serde_json::json!([]),
+		};
+		let patch: json_patch::Patch = serde_json::from_value(patch).unwrap();
+		json_patch::patch(spec, &patch).unwrap();
+	}
+}
+
+impl Default for Runtime {
+	fn default() -> Self {
+		Self::new(RuntimeProfile::Runc)
+	}
+}
+
+/// Parses "runc", "crun" or "gvisor", "profile=/path/to/program",
+/// or the path of a runtime executable, whose name selects the profile (e.g. "/usr/bin/runsc").
+impl FromStr for Runtime {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let unknown = |name: &str| {
+			let profiles: Vec<_> = RuntimeProfile::ALL.iter().map(|p| p.name()).collect();
+			format!(
+				"unknown runtime '{name}', expected one of: {}",
+				profiles.join(", ")
+			)
+		};
+
+		if let Some((name, program)) = s.split_once('=') {
+			let profile = RuntimeProfile::from_name(name).ok_or_else(|| unknown(name))?;
+			if program.is_empty() {
+				return Err(format!("expected 'profile=program', got '{s}'"));
+			}
+			return Ok(Self {
+				profile,
+				program: program.into(),
+			});
+		}
+
+		if !s.contains('/') {
+			let profile = RuntimeProfile::from_name(s).ok_or_else(|| unknown(s))?;
+			return Ok(Self::new(profile));
+		}
+		let program = PathBuf::from(s);
+		let name = (program.file_name())
+			.and_then(|name| name.to_str())
+			.unwrap_or(s);
+		let profile = RuntimeProfile::from_name(name).ok_or_else(|| unknown(name))?;
+		Ok(Self { profile, program })
+	}
+}
+
+fn is_root() -> bool {
+	use syscalls::{syscall, Sysno};
+	matches!(unsafe { syscall!(Sysno::geteuid) }, Ok(0))
+}
@@ -1,14 +1,32 @@
-use std::{env, path::PathBuf, thread};
+use std::{
+	env, fs,
+	os::unix::fs::PermissionsExt,
+	path::{Path, PathBuf},
+	thread,
+};
 
+use indexmap::IndexMap;
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
 use warpforge_api::formula::FormulaAndContext;
 
 use crate::{
-	context::Context, events::EventBody, execute::Executor, formula::Formula, Event, Output, Result,
+	context::Context, events::EventBody, execute::Executor, formula::Formula, limits::Limits,
+	runtime::Runtime, ContainerParams, Event, Output, Result,
 };
 
+mod cancel;
+mod debug_shell;
 mod formula;
+mod idmap;
+mod image;
+mod limits;
+mod pack;
 mod plot;
+mod runtime;
+mod snapshot;
+mod spec_patch;
 
 #[derive(PartialEq, Debug)]
 struct RunOutput {
@@ -26,7 +44,8 @@ struct RunOutputLine {
 fn default_context() -> Context {
 	let runtime = env::var("WARPFORGE_TEST_RUNTIME")
 		.unwrap_or("runc".into())
-		.into();
+		.parse()
+		.unwrap();
 	let image_cache = Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../.images"));
 	Context {
 		runtime,
@@ -43,6 +62,7 @@ fn run_formula_collect_output(
 	let executor = Executor {
 		ersatz_dir: tempdir.path().join("run"),
 		log_file: tempdir.path().join("log"),
+		cancellation: context.cancellation.clone(),
 	};
 	let (gather_chan, gather_chan_recv) = crossbeam_channel::bounded::<Event>(32);
 
@@ -78,3 +98,123 @@ fn run_formula_collect_output(
 	run_output.outputs = outputs;
 	Ok(run_output)
 }
+
+/// A container in `dir` whose runtime hangs in `run`, and fails to `kill` it:
+/// the executor has to kill the runtime itself to stop it.
+fn hanging_container(dir: &Path, limits: Limits) -> (Executor, ContainerParams) {
+	let script = "case \"$*\" in *' run '*) exec sleep 60 ;; *) exit 1 ;; esac";
+	fake_container(dir, script, limits)
+}
+
+/// A fake runc in `dir`, which runs the shell `script` with the runtime's arguments.
+/// For `run`, the fourth argument is "--bundle=<DIR>".
+fn fake_runtime(dir: &Path, script: &str) -> Runtime {
+	let runtime = dir.join("runc");
+	fs::write(&runtime, format!("#!/bin/sh\n{script}\n")).unwrap();
+	fs::set_permissions(&runtime, fs::Permissions::from_mode(0o755)).unwrap();
+	format!("runc={}", runtime.display()).parse().unwrap()
+}
+
+/// A container in `dir` with a fake runtime, which runs the shell `script`.
+fn fake_container(dir: &Path, script: &str, limits: Limits) -> (Executor, ContainerParams) {
+	let runtime = fake_runtime(dir, script);
+
+	let executor = Executor {
+		ersatz_dir: dir.join("run"),
+		log_file: dir.join("log"),
+		cancellation: Default::default(),
+	};
+	let task = ContainerParams {
+		ident: "hanging".into(),
+		runtime,
+		command: vec!["/bin/true".into()],
+		mounts: IndexMap::new(),
+		environment: IndexMap::new(),
+		root_path: dir.join("rootfs"),
+		cwd: "/".into(),
+		uid: 0,
+		gid: 0,
+		spec_patches: Vec::new(),
+		limits,
+		terminal: false,
+		id_map: None,
+	};
+	(executor, task)
+}
+
+/// A layer holding regular `files`, given as path, content and owning uid and gid.
+fn layer(files: &[(&str, &str, u64, u64)]) -> Vec<u8> {
+	let mut builder = tar::Builder::new(Vec::new());
+	for (path, content, uid, gid) in files {
+		let mut header = tar::Header::new_gnu();
+		header.set_entry_type(tar::EntryType::Regular);
+		header.set_mode(0o644);
+		header.set_uid(*uid);
+		header.set_gid(*gid);
+		header.set_size(content.len() as u64);
+		builder
+			.append_data(&mut header, path, content.as_bytes())
+			.unwrap();
+	}
+	builder.into_inner().unwrap()
+}
+
+/// Import an image consisting of `layer` into an image cache in `dir`, which `context` uses offline.
+/// Returns the 'oci' formula input of the image.
+fn local_image(dir: &Path, context: &mut Context, layer: &[u8]) -> String {
+	let layout_dir = dir.join("layout");
+	let write = |data: &[u8]| {
+		let hex = format!("{:x}", Sha256::digest(data));
+		fs::create_dir_all(layout_dir.join("blobs/sha256")).unwrap();
+		fs::write(layout_dir.join("blobs/sha256").join(&hex), data).unwrap();
+		(format!("sha256:{hex}"), data.len())
+	};
+
+	let (layer_digest, layer_size) = write(layer);
+	let config = json!({
+		"architecture": oci_unpack::Platform::current().architecture,
+		"os": "linux",
+		"rootfs": { "type": "layers", "diff_ids": [layer_digest] },
+		"history": [],
+	});
+	let (config_digest, config_size) = write(config.to_string().as_bytes());
+	let manifest = json!({
+		"schemaVersion": 2,
+		"mediaType": "application/vnd.oci.image.manifest.v1+json",
+		"config": {
+			"mediaType": "application/vnd.oci.image.config.v1+json",
+			"digest": config_digest,
+			"size": config_size,
+		},
+		"layers": [{
+			"mediaType": "application/vnd.oci.image.layer.v1.tar",
+			"digest": layer_digest,
+			"size": layer_size,
+		}],
+	});
+	let (manifest_digest, manifest_size) = write(manifest.to_string().as_bytes());
+	let index = json!({ "schemaVersion": 2, "manifests": [{
+		"mediaType": "application/vnd.oci.image.manifest.v1+json",
+		"digest": manifest_digest,
+		"size": manifest_size,
+	}]});
+	fs::write(layout_dir.join("index.json"), index.to_string()).unwrap();
+	fs::write(
+		layout_dir.join("oci-layout"),
+		r#"{"imageLayoutVersion":"1.0.0"}"#,
+	)
+	.unwrap();
+
+	let image_cache = dir.join("images");
+	let reference = format!("docker.io/warpforge/local@{manifest_digest}");
+	oci_unpack::layout::import_oci_layout(
+		&layout_dir,
+		&image_cache,
+		&reference.parse().unwrap(),
+		&oci_unpack::Platform::current(),
+	)
+	.unwrap();
+	context.image_cache = Some(image_cache);
+	context.offline = true;
+	format!("oci:{reference}")
+}
@@ -0,0 +1,50 @@
+use std::{
+	fs,
+	os::unix::fs::PermissionsExt,
+	thread,
+	time::{Duration, Instant},
+};
+
+use crossbeam_channel::unbounded;
+use tempfile::TempDir;
+
+use super::hanging_container;
+use crate::Error;
+
+#[test]
+fn cancel_kills_container_and_cleans_up() {
+	let tempdir = TempDir::new().unwrap();
+	let (executor, task) = hanging_container(tempdir.path(), Default::default());
+	// Overlay work directories are left without permissions by the kernel.
+	let work_dir = executor.ersatz_dir.join("overlays/mount-/work/work");
+	fs::create_dir_all(&work_dir).unwrap();
+	fs::set_permissions(&work_dir, fs::Permissions::from_mode(0o000)).unwrap();
+
+	let cancellation = executor.cancellation.clone();
+	let canceller = thread::spawn(move || {
+		thread::sleep(Duration::from_millis(300));
+		cancellation.cancel();
+	});
+
+	let start = Instant::now();
+	let (outbox, _events) = unbounded();
+	let err = executor.run(&task, outbox).unwrap_err();
+	canceller.join().unwrap();
+	assert!(matches!(err, Error::Cancelled), "{err}");
+	assert!(start.elapsed() < Duration::from_secs(30));
+
+	assert!(!executor.ersatz_dir.join(&task.ident).exists());
+	assert!(!executor.ersatz_dir.join("overlays").exists());
+}
+
+#[test]
+fn cancelled_executor_does_not_start_container() {
+	let tempdir = TempDir::new().unwrap();
+	let (executor, task) = hanging_container(tempdir.path(), Default::default());
+	executor.cancellation.cancel();
+
+	let (outbox, _events) = unbounded();
+	let err = executor.run(&task, outbox).unwrap_err();
+	assert!(matches!(err, Error::Cancelled), "{err}");
+	assert!(!executor.ersatz_dir.join(&task.ident).exists());
+}
@@ -0,0 +1,82 @@
+use std::{fs, io::IsTerminal};
+
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::formula::FormulaAndContext;
+
+use super::{fake_container, fake_runtime, layer, local_image, run_formula_collect_output};
+use crate::{
+	context::{Context, DebugShell},
+	ContainerParams,
+};
+
+#[test]
+fn parse_debug_shell() {
+	assert_eq!("never".parse(), Ok(DebugShell::Never));
+	assert_eq!("on-failure".parse(), Ok(DebugShell::OnFailure));
+	assert_eq!("always".parse(), Ok(DebugShell::Always));
+	assert!("sometimes".parse::<DebugShell>().is_err());
+
+	assert!(!DebugShell::Never.wanted(Some(1)));
+	assert!(!DebugShell::OnFailure.wanted(Some(0)));
+	assert!(DebugShell::OnFailure.wanted(Some(1)));
+	assert!(DebugShell::OnFailure.wanted(None));
+	assert!(DebugShell::Always.wanted(Some(0)));
+}
+
+#[test]
+fn interactive_container_gets_terminal() {
+	let tempdir = TempDir::new().unwrap();
+	// The runtime exits like a shell would, after keeping the spec it was given.
+	let script = format!(
+		"cp \"${{4#--bundle=}}/config.json\" {}\nexit 3",
+		tempdir.path().join("spec.json").display()
+	);
+	let (executor, task) = fake_container(tempdir.path(), &script, Default::default());
+	let task = ContainerParams {
+		command: vec!["/bin/sh".into()],
+		terminal: true,
+		id_map: None,
+		..task
+	};
+
+	let exit_code = executor.run_interactive(&task).unwrap();
+	assert_eq!(exit_code, Some(3));
+	assert!(!tempdir.path().join("run").join(&task.ident).exists());
+
+	let spec: serde_json::Value =
+		serde_json::from_slice(&fs::read(tempdir.path().join("spec.json")).unwrap()).unwrap();
+	assert_eq!(spec["process"]["terminal"], true);
+	assert_eq!(spec["process"]["args"], serde_json::json!(["/bin/sh"]));
+}
+
+#[test]
+fn debug_shell_without_terminal_fails_before_action() {
+	if std::io::stdin().is_terminal() {
+		eprintln!("skipped: stdin is a terminal");
+		return;
+	}
+	let tempdir = TempDir::new().unwrap();
+	let ran = tempdir.path().join("ran");
+	let mut context = Context {
+		runtime: fake_runtime(tempdir.path(), &format!("touch {}", ran.display())),
+		debug_shell: DebugShell::Always,
+		..Default::default()
+	};
+	let image = local_image(tempdir.path(), &mut context, &layer(&[]));
+
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": { "/": image },
+				"action": { "exec": { "command": ["/bin/true"] } },
+				"outputs": {},
+			}
+		},
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+	let err = run_formula_collect_output(formula_and_context, &context).unwrap_err();
+	assert!(err.to_string().contains("terminal"), "{err}");
+	assert!(!ran.exists());
+}
@@ -4,7 +4,7 @@ use flate2::read::GzDecoder;
 use serde_json::json;
 use tar::Archive;
 use tempfile::TempDir;
-use warpforge_api::formula::FormulaAndContext;
+use warpforge_api::{content::Packtype, formula::FormulaAndContext};
 
 use crate::{
 	tests::{default_context, run_formula_collect_output},
@@ -53,7 +53,8 @@ fn tgz_output() {
 	assert_eq!(result.exit_code, Some(0));
 	assert_eq!(result.outputs, vec![Output {
 		name: "output.tgz".into(),
-		digest: Digest::Sha384("64518bf7b504749270619507457adc3c86d46ccbb86c8b06508591aed483c1a5db728086dba261ff05f453dfd2c315d5".into())
+		digest: Digest::Sha384("64518bf7b504749270619507457adc3c86d46ccbb86c8b06508591aed483c1a5db728086dba261ff05f453dfd2c315d5".into()),
+		packtype: Packtype("tgz".into()),
 	}]);
 
 	// Unpack output.tar and check contents.
@@ -120,11 +121,13 @@ fn multiple_tgz_outputs() {
 	assert_eq!(result.outputs, vec![
 		Output {
 			name: "output_1.tgz".into(),
-			digest: Digest::Sha384("dbdb8a42228f80b47f18dceab1994e59820ef26fd5b74db9e7298b77907ba25c00f6920d893670d0bc366c2ed4052047".into())
+			digest: Digest::Sha384("dbdb8a42228f80b47f18dceab1994e59820ef26fd5b74db9e7298b77907ba25c00f6920d893670d0bc366c2ed4052047".into()),
+			packtype: Packtype("tgz".into()),
 		},
 		Output {
 			name: "output_2.tgz".into(),
-			digest: Digest::Sha384("885741449883286ea479ac9e71a7cab2b8f75cf25960f88168c56f3645f39c206f59932e20735f91e39ccb892f62b529".into())
+			digest: Digest::Sha384("885741449883286ea479ac9e71a7cab2b8f75cf25960f88168c56f3645f39c206f59932e20735f91e39ccb892f62b529".into()),
+			packtype: Packtype("tgz".into()),
 		},
 	]);
 }
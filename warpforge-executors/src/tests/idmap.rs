@@ -0,0 +1,105 @@
+use std::{
+	fs,
+	os::unix::fs::{symlink, MetadataExt, PermissionsExt},
+};
+
+use oci_unpack::rootless::{FileAttributes, RootlessFiles};
+use tempfile::TempDir;
+
+use crate::idmap::{parse_subids, IdMap, IdMapping};
+
+#[test]
+fn parse_subordinate_ids() {
+	let content = "alice:100000:65536\n1001:165536:65536\nbob:231072:0\n";
+	assert_eq!(
+		parse_subids(content, Some("alice"), 1000),
+		Some((100000, 65536))
+	);
+	assert_eq!(parse_subids(content, None, 1001), Some((165536, 65536)));
+	assert_eq!(parse_subids(content, Some("carol"), 1002), None);
+	// Empty ranges don't count.
+	assert_eq!(parse_subids(content, Some("bob"), 1003), None);
+}
+
+#[test]
+fn container_ids_of_host_ids() {
+	let id_map = IdMap::new((1000, 100), (100000, 65536), (200000, 65536));
+	assert_eq!(
+		id_map.uids,
+		[
+			IdMapping {
+				container_id: 0,
+				host_id: 1000,
+				size: 1
+			},
+			IdMapping {
+				container_id: 1,
+				host_id: 100000,
+				size: 65536
+			},
+		]
+	);
+	assert_eq!(IdMap::container_id(&id_map.uids, 1000), Some(0));
+	assert_eq!(IdMap::container_id(&id_map.uids, 100999), Some(1000));
+	assert_eq!(IdMap::container_id(&id_map.gids, 200000), Some(1));
+	assert_eq!(IdMap::container_id(&id_map.uids, 999), None);
+	assert_eq!(IdMap::container_id(&id_map.uids, 165536), None);
+}
+
+#[test]
+fn recorded_owners_are_applied_and_reclaimed() {
+	if !oci_unpack::rootless::is_root() {
+		eprintln!("skipped: mapping arbitrary ids requires root, or subordinate ids of the user");
+		return;
+	}
+	let tempdir = TempDir::new().unwrap();
+	let rootfs = tempdir.path().join("rootfs");
+	fs::create_dir_all(rootfs.join("bin")).unwrap();
+	fs::write(rootfs.join("bin/busybox"), "").unwrap();
+	symlink("busybox", rootfs.join("bin/su")).unwrap();
+	fs::write(rootfs.join("owned"), "").unwrap();
+	fs::write(rootfs.join("unmapped"), "").unwrap();
+
+	let attributes = |uid, gid, mode| FileAttributes {
+		uid,
+		gid,
+		mode,
+		device: None,
+	};
+	let files = RootlessFiles::from([
+		("/bin/busybox".into(), attributes(0, 0, 0o4755)),
+		("/bin/su".into(), attributes(1000, 1000, 0o777)),
+		("/owned".into(), attributes(1000, 50, 0o640)),
+		("/unmapped".into(), attributes(70000, 0, 0o600)),
+		("/missing".into(), attributes(1000, 1000, 0o644)),
+	]);
+	let id_map = IdMap::new((0, 0), (100000, 65536), (100000, 65536));
+	id_map.apply(&rootfs, &files).unwrap();
+
+	let meta = |path: &str| rootfs.join(path).symlink_metadata().unwrap();
+	assert_eq!((meta("owned").uid(), meta("owned").gid()), (100999, 100049));
+	assert_eq!(
+		IdMap::container_id(&id_map.uids, meta("owned").uid()),
+		Some(1000)
+	);
+	assert_eq!(meta("owned").permissions().mode() & 0o7777, 0o640);
+	// The symlink itself is chowned, its target keeps the recorded mode.
+	assert_eq!(meta("bin/su").uid(), 100999);
+	assert_eq!(
+		(
+			meta("bin/busybox").uid(),
+			meta("bin/busybox").mode() & 0o7777
+		),
+		(0, 0o4755)
+	);
+	// Ids beyond the subordinate ids can't be mapped.
+	assert_eq!(meta("unmapped").uid(), 0);
+	assert!(!rootfs.join("missing").exists());
+
+	id_map.reclaim(tempdir.path()).unwrap();
+	for path in ["owned", "bin/su", "bin/busybox", "unmapped"] {
+		assert_eq!((meta(path).uid(), meta(path).gid()), (0, 0), "{path}");
+	}
+	// Reclaiming a removed directory is fine.
+	id_map.reclaim(&tempdir.path().join("gone")).unwrap();
+}
@@ -0,0 +1,93 @@
+use std::fs;
+
+use oci_unpack::ImageConfiguration;
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::image::{resolve_user, ImageProcess};
+
+fn rootfs() -> TempDir {
+	let rootfs = TempDir::new().unwrap();
+	fs::create_dir(rootfs.path().join("etc")).unwrap();
+	fs::write(
+		rootfs.path().join("etc/passwd"),
+		"root:x:0:0:root:/root:/bin/sh\nbuilder:x:1000:100:builder:/home/builder:/bin/sh\n",
+	)
+	.unwrap();
+	fs::write(
+		rootfs.path().join("etc/group"),
+		"root:x:0:\nusers:x:100:\nwheel:x:10:builder\n",
+	)
+	.unwrap();
+	rootfs
+}
+
+#[test]
+fn image_process_from_config() {
+	let rootfs = rootfs();
+	let config: ImageConfiguration = serde_json::from_value(json!({
+		"architecture": "amd64",
+		"os": "linux",
+		"config": {
+			"Env": ["PATH=/opt/toolchain/bin:/usr/bin", "EMPTY"],
+			"WorkingDir": "/src",
+			"User": "builder",
+		},
+		"rootfs": { "type": "layers", "diff_ids": [] },
+		"history": [],
+	}))
+	.unwrap();
+
+	let process = ImageProcess::new(&config, rootfs.path()).unwrap();
+	assert_eq!(process.environment["PATH"], "/opt/toolchain/bin:/usr/bin");
+	assert_eq!(process.environment["EMPTY"], "");
+	assert_eq!(process.cwd, "/src");
+	assert_eq!((process.uid, process.gid), (1000, 100));
+}
+
+#[test]
+fn image_process_defaults() {
+	let config: ImageConfiguration = serde_json::from_value(json!({
+		"architecture": "amd64",
+		"os": "linux",
+		"rootfs": { "type": "layers", "diff_ids": [] },
+		"history": [],
+	}))
+	.unwrap();
+
+	let process = ImageProcess::new(&config, TempDir::new().unwrap().path()).unwrap();
+	assert_eq!(process, ImageProcess::default());
+}
+
+#[test]
+fn resolve_image_users() {
+	let rootfs = rootfs();
+	let rootfs = rootfs.path();
+	assert_eq!(resolve_user(rootfs, "root").unwrap(), (0, 0));
+	assert_eq!(resolve_user(rootfs, "1000").unwrap(), (1000, 100));
+	assert_eq!(resolve_user(rootfs, "builder:wheel").unwrap(), (1000, 10));
+	assert_eq!(resolve_user(rootfs, "1234:5678").unwrap(), (1234, 5678));
+	// Numeric users don't need to exist.
+	assert_eq!(resolve_user(rootfs, "1234").unwrap(), (1234, 0));
+	assert!(resolve_user(rootfs, "nobody").is_err());
+	assert!(resolve_user(rootfs, "builder:nogroup").is_err());
+}
+
+#[test]
+fn resolve_user_through_symlinks_inside_image() {
+	let rootfs = TempDir::new().unwrap();
+	let rootfs = rootfs.path();
+	fs::create_dir_all(rootfs.join("etc")).unwrap();
+	fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+	fs::write(
+		rootfs.join("usr/lib/passwd"),
+		"builder:x:1000:100::/:/bin/sh\n",
+	)
+	.unwrap();
+	fs::write(rootfs.join("usr/lib/group"), "wheel:x:10:builder\n").unwrap();
+	// Resolved on the host, these would point to the host's files (or nothing).
+	std::os::unix::fs::symlink("/usr/lib/passwd", rootfs.join("etc/passwd")).unwrap();
+	std::os::unix::fs::symlink("../../../../../usr/lib/group", rootfs.join("etc/group")).unwrap();
+
+	assert_eq!(resolve_user(rootfs, "builder:wheel").unwrap(), (1000, 10));
+}
@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+
+use crossbeam_channel::unbounded;
+use serde_json::json;
+use tempfile::TempDir;
+
+use super::hanging_container;
+use crate::{
+	limits::{cgroups_available, parse_cpus, parse_duration, parse_size, Limits},
+	oci::oci_spec_base,
+	Error,
+};
+
+#[test]
+fn parse_limits() {
+	assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+	assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+	assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+	assert_eq!(parse_duration("1h30m"), Ok(Duration::from_secs(90 * 60)));
+	assert_eq!(
+		parse_duration("2d"),
+		Ok(Duration::from_secs(2 * 24 * 60 * 60))
+	);
+	for invalid in ["", "m", "10x", "1.5h", "10mé", "-1s"] {
+		assert!(parse_duration(invalid).is_err(), "{invalid}");
+	}
+
+	assert_eq!(parse_size("4096"), Ok(4096));
+	assert_eq!(parse_size("512M"), Ok(512 << 20));
+	assert_eq!(parse_size("2g"), Ok(2 << 30));
+	for invalid in ["", "M", "1.5G", "2GB", "99999999999T"] {
+		assert!(parse_size(invalid).is_err(), "{invalid}");
+	}
+
+	assert_eq!(parse_cpus("2"), Ok(2.0));
+	assert_eq!(parse_cpus("0.5"), Ok(0.5));
+	for invalid in ["0", "-1", "inf", "NaN", "two"] {
+		assert!(parse_cpus(invalid).is_err(), "{invalid}");
+	}
+	// The kernel rejects quotas below 1ms per period.
+	assert_eq!(parse_cpus("0.01"), Ok(0.01));
+	assert_eq!(
+		parse_cpus("0.001"),
+		Err("number of CPUs '0.001' is too small, the minimum is 0.01".into())
+	);
+}
+
+#[test]
+fn limits_in_spec() {
+	let mut spec = oci_spec_base();
+	Limits::default().patch_spec(&mut spec).unwrap();
+	assert_eq!(
+		spec["process"]["rlimits"],
+		json!([{"type": "RLIMIT_NOFILE", "hard": 1024, "soft": 1024}])
+	);
+	assert!(spec["linux"]["resources"].is_null());
+
+	let mut spec = oci_spec_base();
+	let limits = Limits {
+		memory: Some(512 << 20),
+		cpus: Some(1.5),
+		pids: Some(64),
+		open_files: Some(4096),
+		..Default::default()
+	};
+	limits.patch_spec(&mut spec).unwrap();
+	assert_eq!(
+		spec["process"]["rlimits"],
+		json!([{"type": "RLIMIT_NOFILE", "hard": 4096, "soft": 4096}])
+	);
+	if cgroups_available() {
+		assert_eq!(
+			spec["linux"]["resources"],
+			json!({
+				"memory": {"limit": 536870912, "swap": 536870912},
+				"cpu": {"quota": 150000, "period": 100000},
+				"pids": {"limit": 64},
+			})
+		);
+	} else {
+		assert!(spec["linux"]["resources"].is_null());
+	}
+}
+
+#[test]
+fn limits_below_kernel_minimum_are_rejected() {
+	let mut spec = oci_spec_base();
+	let limits = Limits {
+		cpus: Some(0.0001),
+		..Default::default()
+	};
+	let err = limits.patch_spec(&mut spec).unwrap_err();
+	assert!(err.to_string().contains("too small"), "{err}");
+}
+
+#[test]
+fn timeout_kills_container() {
+	let tempdir = TempDir::new().unwrap();
+	let limits = Limits {
+		timeout: Some(Duration::from_secs(1)),
+		..Default::default()
+	};
+	let (executor, task) = hanging_container(tempdir.path(), limits);
+
+	let start = Instant::now();
+	let (outbox, _events) = unbounded();
+	let err = executor.run(&task, outbox).unwrap_err();
+	assert!(
+		matches!(err, Error::Timeout { timeout } if timeout == Duration::from_secs(1)),
+		"{err}"
+	);
+	assert!(start.elapsed() < Duration::from_secs(30));
+}
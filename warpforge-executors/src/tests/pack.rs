@@ -0,0 +1,31 @@
+use std::fs;
+
+use tempfile::TempDir;
+
+use crate::pack::{tar_dir_hash_only, tar_dir_to_warehouse, warehouse_path};
+
+#[test]
+fn tar_output_stored_in_warehouse() {
+	let temp_dir = TempDir::new().unwrap();
+	let source_dir = temp_dir.path().join("output");
+	fs::create_dir(&source_dir).unwrap();
+	fs::write(source_dir.join("hello.txt"), "hello warehouse").unwrap();
+	let warehouse = temp_dir.path().join("warehouse");
+	fs::create_dir(&warehouse).unwrap();
+
+	let output = tar_dir_to_warehouse("output", &source_dir, &warehouse).unwrap();
+
+	let expected = tar_dir_hash_only("output", &source_dir).unwrap();
+	assert_eq!(output, expected);
+	assert_eq!(output.ware_id().packtype.0, "tar");
+
+	let ware = fs::read(warehouse_path(&warehouse, &output.digest)).unwrap();
+	let mut archive = tar::Archive::new(ware.as_slice());
+	let names: Vec<_> = (archive.entries().unwrap())
+		.map(|entry| entry.unwrap().path().unwrap().into_owned())
+		.collect();
+	assert!(names.iter().any(|name| name.ends_with("hello.txt")));
+
+	// Storing the same content again is fine.
+	tar_dir_to_warehouse("output", &source_dir, &warehouse).unwrap();
+}
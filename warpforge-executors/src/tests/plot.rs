@@ -1,2 +1,3 @@
 mod invalid_step_graph;
+mod pinning;
 mod simple_steps;
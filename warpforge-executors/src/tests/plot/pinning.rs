@@ -0,0 +1,58 @@
+use serde_json::json;
+use warpforge_api::plot::PlotCapsule;
+
+use crate::plot::{host_inputs, pin_plot};
+use crate::tests::default_context;
+
+const PINNED: &str = "oci:docker.io/library/busybox@sha256:7b3ccabffc97de872a30dfd234fd972a66d247c8cfc69b0550f276481852627c";
+
+fn plot() -> PlotCapsule {
+	serde_json::from_value(json!({
+		"plot.v1": {
+			"inputs": {
+				"rootfs": PINNED,
+				"src": "mount:ro:/home/user/src",
+			},
+			"steps": {
+				"build": {
+					"protoformula": {
+						"inputs": {
+							"/": "pipe::rootfs",
+							"/src": "pipe::src",
+							"/cache": "mount:rw:/var/cache/build",
+						},
+						"action": {
+							"script": {
+								"interpreter": "/bin/sh",
+								"contents": ["true"]
+							}
+						},
+						"outputs": {}
+					}
+				}
+			},
+			"outputs": {}
+		}
+	}))
+	.unwrap()
+}
+
+#[test]
+fn host_inputs_lists_mounts() {
+	assert_eq!(
+		host_inputs(&plot()),
+		[
+			"src=mount:ro:/home/user/src",
+			"build: /cache=mount:rw:/var/cache/build",
+		]
+	);
+}
+
+#[test]
+fn pinned_references_are_kept() {
+	// References with digest are not resolved again, so this doesn't need a registry.
+	let pinned = pin_plot(&plot(), &default_context()).unwrap();
+	let PlotCapsule::V1(pinned) = pinned;
+	assert_eq!(pinned.inputs[0].to_string(), PINNED);
+	assert_eq!(pinned.inputs[1].to_string(), "mount:ro:/home/user/src");
+}
@@ -1,6 +1,6 @@
 use serde_json::json;
 use tempfile::TempDir;
-use warpforge_api::plot::PlotCapsule;
+use warpforge_api::{content::Packtype, plot::PlotCapsule};
 
 use crate::{context::Context, plot::run_plot, tests::default_context, Output};
 
@@ -87,6 +87,7 @@ fn plot_simple_steps() {
 	assert_eq!(outputs, vec![Output{
 		name: "output.tgz".into(),
 		digest: crate::Digest::Sha384("4616df8a46fccfa6e418d623b19ea20545cef530b369b0071a8b691d3bf2a9628d4350f065ab60f37a96515921c5e8e4".into()),
+		packtype: Packtype("tgz".into()),
 	}]);
 }
 
@@ -170,5 +171,6 @@ fn plot_simple_steps_mangled_order() {
 	assert_eq!(outputs, vec![Output{
 		name: "output.tar".into(),
 		digest: crate::Digest::Sha384("bd00d1ecdaa6988962460b5288953ba8c504f876bd2134b95aa3ef3df993f7fbc6be147898fc94b5f5cff476584d0fd4".into()),
+		packtype: Packtype("tar".into()),
 	}]);
 }
@@ -0,0 +1,181 @@
+use std::{fs, path::Path};
+
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::formula::FormulaAndContext;
+
+use super::{fake_runtime, layer, local_image, run_formula_collect_output};
+use crate::{
+	context::Context,
+	idmap::IdMap,
+	runtime::{Runtime, RuntimeProfile},
+};
+
+#[test]
+fn parse_runtimes() {
+	let runtime = |s: &str| s.parse::<Runtime>().unwrap();
+	assert_eq!(runtime("runc"), Runtime::new(RuntimeProfile::Runc));
+	assert_eq!(runtime("crun"), Runtime::new(RuntimeProfile::Crun));
+	assert_eq!(runtime("gvisor"), Runtime::new(RuntimeProfile::Gvisor));
+	assert_eq!(runtime("gvisor").program, Path::new("runsc"));
+	assert_eq!(runtime("runsc"), Runtime::new(RuntimeProfile::Gvisor));
+
+	let runtime_at = |profile, program: &str| Runtime {
+		profile,
+		program: program.into(),
+	};
+	assert_eq!(
+		runtime("/usr/local/bin/crun"),
+		runtime_at(RuntimeProfile::Crun, "/usr/local/bin/crun")
+	);
+	assert_eq!(
+		runtime("gvisor=/opt/gvisor/bin/runsc-nightly"),
+		runtime_at(RuntimeProfile::Gvisor, "/opt/gvisor/bin/runsc-nightly")
+	);
+
+	assert!("kata".parse::<Runtime>().is_err());
+	assert!("/usr/bin/docker".parse::<Runtime>().is_err());
+	assert!("runc=".parse::<Runtime>().is_err());
+}
+
+#[test]
+fn runtime_command_lines() {
+	let args = |runtime: &str| {
+		let runtime: Runtime = runtime.parse().unwrap();
+		let cmd = runtime.run_command(Path::new("/log"), Path::new("/bundle"), "ident");
+		let args: Vec<_> = (cmd.get_args())
+			.map(|arg| arg.to_str().unwrap().to_owned())
+			.collect();
+		(cmd.get_program().to_str().unwrap().to_owned(), args)
+	};
+
+	let (program, runc) = args("runc");
+	assert_eq!(program, "runc");
+	assert_eq!(
+		runc,
+		["--log=/log", "--debug", "run", "--bundle=/bundle", "ident"]
+	);
+
+	let (program, gvisor) = args("gvisor");
+	assert_eq!(program, "runsc");
+	assert_eq!(
+		&gvisor[..3],
+		["--debug-log=/log", "--debug", "--network=host"]
+	);
+	assert_eq!(
+		&gvisor[gvisor.len() - 3..],
+		["run", "--bundle=/bundle", "ident"]
+	);
+}
+
+#[test]
+fn runtime_spec_patches() {
+	let base = json!({ "linux": { "namespaces": [{"type": "pid"}] } });
+
+	let mut runc = base.clone();
+	Runtime::new(RuntimeProfile::Runc).patch_spec(&mut runc, (0, 0), (1000, 100), None);
+	assert_eq!(
+		runc,
+		json!({ "linux": {
+			"namespaces": [{"type": "pid"}, {"type": "user"}],
+			"uidMappings": [{"containerID": 0, "hostID": 1000, "size": 1}],
+			"gidMappings": [{"containerID": 0, "hostID": 100, "size": 1}],
+		}})
+	);
+
+	// Root keeps the ownership of the image files.
+	let mut runc = base.clone();
+	Runtime::new(RuntimeProfile::Runc).patch_spec(&mut runc, (1000, 1000), (0, 0), None);
+	let identity = json!([{"containerID": 0, "hostID": 0, "size": u32::MAX}]);
+	assert_eq!(runc["linux"]["uidMappings"], identity);
+	assert_eq!(runc["linux"]["gidMappings"], identity);
+
+	// Subordinate ids map the recorded owners of a rootless unpack.
+	let id_map = IdMap::new((1000, 100), (100000, 65536), (200000, 65536));
+	let mut runc = base.clone();
+	Runtime::new(RuntimeProfile::Runc).patch_spec(
+		&mut runc,
+		(1000, 1000),
+		(1000, 100),
+		Some(&id_map),
+	);
+	assert_eq!(
+		runc["linux"]["uidMappings"],
+		json!([
+			{"containerID": 0, "hostID": 1000, "size": 1},
+			{"containerID": 1, "hostID": 100000, "size": 65536},
+		])
+	);
+	assert_eq!(
+		runc["linux"]["gidMappings"],
+		json!([
+			{"containerID": 0, "hostID": 100, "size": 1},
+			{"containerID": 1, "hostID": 200000, "size": 65536},
+		])
+	);
+
+	// gVisor fails to start in a user namespace.
+	let mut gvisor = base.clone();
+	Runtime::new(RuntimeProfile::Gvisor).patch_spec(&mut gvisor, (0, 0), (1000, 100), None);
+	assert_eq!(gvisor, base);
+}
+
+#[test]
+fn image_file_owner_is_kept_in_container() {
+	if !oci_unpack::rootless::is_root() {
+		eprintln!("skipped: a rootless unpack can't keep the owner of image files");
+		return;
+	}
+	let tempdir = TempDir::new().unwrap();
+	let spec_path = tempdir.path().join("spec.json");
+	let owner_path = tempdir.path().join("owner");
+
+	// The runtime keeps the spec and the host owner of the file, instead of running the container.
+	let script = format!(
+		"cp \"${{4#--bundle=}}/config.json\" {}; stat -c %u \"${{4#--bundle=}}/rootfs/owned\" > {}",
+		spec_path.display(),
+		owner_path.display()
+	);
+	let mut context = Context {
+		runtime: fake_runtime(tempdir.path(), &script),
+		..Default::default()
+	};
+	let image = local_image(
+		tempdir.path(),
+		&mut context,
+		&layer(&[("owned", "mine", 1000, 1000)]),
+	);
+
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": { "/": image },
+				"action": { "exec": { "command": ["/bin/true"] } },
+				"outputs": {},
+			}
+		},
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+	let result = run_formula_collect_output(formula_and_context, &context).unwrap();
+	assert_eq!(result.exit_code, Some(0));
+
+	// Map the host owner into the container, like the kernel does for the user namespace.
+	let host_owner: u64 = fs::read_to_string(owner_path)
+		.unwrap()
+		.trim()
+		.parse()
+		.unwrap();
+	let spec: serde_json::Value = serde_json::from_slice(&fs::read(spec_path).unwrap()).unwrap();
+	let container_owner = (spec["linux"]["uidMappings"].as_array().unwrap().iter()).find_map(|m| {
+		let (container_id, host_id, size) = (
+			m["containerID"].as_u64().unwrap(),
+			m["hostID"].as_u64().unwrap(),
+			m["size"].as_u64().unwrap(),
+		);
+		(host_id..host_id + size)
+			.contains(&host_owner)
+			.then(|| container_id + host_owner - host_id)
+	});
+	assert_eq!(container_owner, Some(1000));
+}
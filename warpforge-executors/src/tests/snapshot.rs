@@ -0,0 +1,67 @@
+use std::fs;
+
+use serde_json::json;
+use tempfile::TempDir;
+use warpforge_api::formula::FormulaAndContext;
+
+use super::{fake_runtime, layer, local_image, run_formula_collect_output};
+use crate::context::Context;
+
+#[test]
+fn snapshot_is_mounted_as_root() {
+	let tempdir = TempDir::new().unwrap();
+	let spec_path = tempdir.path().join("spec.json");
+	let input_dir = tempdir.path().join("input");
+	fs::create_dir(&input_dir).unwrap();
+
+	// The runtime keeps the spec it was given, instead of running the container.
+	let script = format!(
+		"cp \"${{4#--bundle=}}/config.json\" {}",
+		spec_path.display()
+	);
+	let snapshot_cache = tempdir.path().join("snapshots");
+	let mut context = Context {
+		runtime: fake_runtime(tempdir.path(), &script),
+		snapshot_cache: Some(snapshot_cache.clone()),
+		..Default::default()
+	};
+	let image = local_image(
+		tempdir.path(),
+		&mut context,
+		&layer(&[("greeting", "hello", 0, 0)]),
+	);
+
+	let formula_and_context: FormulaAndContext = serde_json::from_value(json!({
+		"formula": {
+			"formula.v1": {
+				"inputs": {
+					"/input": format!("mount:ro:{}", input_dir.display()),
+					"/": image,
+				},
+				"action": { "exec": { "command": ["/bin/true"] } },
+				"outputs": {},
+			}
+		},
+		"context": { "context.v1": { "warehouses": {} } }
+	}))
+	.unwrap();
+	let result = run_formula_collect_output(formula_and_context, &context).unwrap();
+	assert_eq!(result.exit_code, Some(0));
+
+	let manifest_digest = image.rsplit_once('@').unwrap().1.replace(':', "/");
+	let snapshot_rootfs = snapshot_cache.join(manifest_digest).join("rootfs");
+	assert_eq!(
+		fs::read_to_string(snapshot_rootfs.join("greeting")).unwrap(),
+		"hello"
+	);
+
+	// The overlay on "/" comes first, so it doesn't hide the other mounts.
+	let spec: serde_json::Value = serde_json::from_slice(&fs::read(spec_path).unwrap()).unwrap();
+	let root = &spec["mounts"][0];
+	assert_eq!(root["destination"], "/");
+	assert_eq!(root["type"], "overlay");
+	let lowerdir = format!("lowerdir={}", snapshot_rootfs.display());
+	assert_eq!(root["options"][0], lowerdir.as_str());
+	let mounts = spec["mounts"].as_array().unwrap();
+	assert!(mounts.iter().any(|mount| mount["destination"] == "/input"));
+}
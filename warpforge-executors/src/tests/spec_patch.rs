@@ -0,0 +1,109 @@
+use std::fs;
+
+use indexmap::IndexMap;
+use serde_json::json;
+use tempfile::TempDir;
+
+use crate::{execute::Executor, ContainerParams, Error};
+
+fn prep_bundle(spec_patches: serde_json::Value) -> crate::Result<serde_json::Value> {
+	let tempdir = TempDir::new().unwrap();
+	let executor = Executor {
+		ersatz_dir: tempdir.path().join("run"),
+		log_file: tempdir.path().join("log"),
+		cancellation: Default::default(),
+	};
+	let task = ContainerParams {
+		ident: "patched".into(),
+		runtime: Default::default(),
+		command: vec!["/bin/true".into()],
+		mounts: IndexMap::new(),
+		environment: IndexMap::from([("VAR".into(), "value".into())]),
+		root_path: tempdir.path().join("rootfs"),
+		cwd: "/".into(),
+		uid: 0,
+		gid: 0,
+		spec_patches: serde_json::from_value(spec_patches).unwrap(),
+		limits: Default::default(),
+		terminal: false,
+		id_map: None,
+	};
+	executor.prep_bundledir(&task)?;
+
+	let config = fs::read(tempdir.path().join("run/patched/config.json")).unwrap();
+	Ok(serde_json::from_slice(&config).unwrap())
+}
+
+#[test]
+fn spec_patches_apply_after_builtin_patches() {
+	let spec = prep_bundle(json!([
+		[
+			{ "op": "replace", "path": "/hostname", "value": "patched" },
+			{ "op": "add", "path": "/process/env/-", "value": "EXTRA=1" },
+		],
+		[
+			// Sees the values set by Warpforge, and the first patch.
+			{ "op": "test", "path": "/process/args/0", "value": "/bin/true" },
+			{ "op": "test", "path": "/hostname", "value": "patched" },
+			{ "op": "replace", "path": "/process/cwd", "value": "/tmp" },
+		],
+	]))
+	.unwrap();
+
+	assert_eq!(spec["hostname"], "patched");
+	assert_eq!(spec["process"]["cwd"], "/tmp");
+	let env = spec["process"]["env"].as_array().unwrap();
+	assert!(env.contains(&json!("VAR=value")));
+	assert_eq!(env.last().unwrap(), "EXTRA=1");
+}
+
+#[test]
+fn failing_spec_patches_are_reported() {
+	let err = prep_bundle(json!([[
+		{ "op": "remove", "path": "/no/such/value" },
+	]]))
+	.unwrap_err();
+	assert!(
+		matches!(&err, Error::SystemSetupError { msg, .. } if msg == "failed to apply spec patch #1"),
+		"{err}"
+	);
+}
+
+#[test]
+fn invalid_patched_specs_are_rejected() {
+	let cases = [
+		(
+			json!({ "op": "remove", "path": "/process/args" }),
+			"'process.args' must be a non-empty list of strings",
+		),
+		(
+			json!({ "op": "replace", "path": "/process/cwd", "value": "tmp" }),
+			"'process.cwd' must be an absolute path",
+		),
+		(
+			json!({ "op": "add", "path": "/process/env/-", "value": "NOVALUE" }),
+			"'process.env' must be a list of 'NAME=value' strings",
+		),
+		(
+			json!({ "op": "add", "path": "/mounts/-", "value": { "destination": "relative" } }),
+			"'mounts[2].destination' must be an absolute path",
+		),
+		(
+			json!({ "op": "add", "path": "/linux/namespaces/-", "value": { "type": "bogus" } }),
+			"'linux.namespaces' must be a list of namespaces with a type of: pid, network, mount, ipc, uts, user, cgroup, time",
+		),
+		(
+			json!({ "op": "replace", "path": "/process/user/uid", "value": -1 }),
+			"'process.user' must have a numeric 'uid' and 'gid'",
+		),
+	];
+
+	for (patch, reason) in cases {
+		let err = prep_bundle(json!([[patch]])).unwrap_err();
+		let expected = format!("spec patches produced an invalid container spec: {reason}");
+		assert!(
+			matches!(&err, Error::SystemSetupCauseless { msg } if *msg == expected),
+			"{err}"
+		);
+	}
+}
@@ -6,7 +6,14 @@ use std::{
 
 use json_with_position::{JsonPath, PathPart, TargetHint};
 use oci_client::Reference;
-use warpforge_api::formula::FormulaAndContext;
+use serde::de::DeserializeOwned;
+use warpforge_api::{
+	catalog::{CatalogModuleCapsule, CatalogRelease},
+	content::WareID,
+	formula::FormulaAndContext,
+	module::ModuleCapsule,
+	plot::{PlotCapsule, PlotInput, PlotOutput},
+};
 use warpforge_terminal::{debug, warn};
 
 /// Maximal number of trailing comma errors that we include in validation result.
@@ -20,14 +27,66 @@ pub fn validate_formula(formula: &str) -> Result<ValidatedFormula> {
 	// [issue #160]: https://github.com/serde-rs/json/issues/160
 
 	let mut validator = Validator::parse_json_value(formula)?;
-	validator.validate_formula()?;
-	validator.finish_formula()
+	validator.validate(|value| check_formula(value, false));
+	let formula = validator.finish()?;
+	Ok(ValidatedFormula { formula })
+}
+
+/// Validate a plot, as found in the `plot.wf` file of a module.
+///
+/// Besides the structure, this checks that all pipes reference existing
+/// plot inputs or step outputs.
+pub fn validate_plot(plot: &str) -> Result<ValidatedPlot> {
+	let mut validator = Validator::parse_json_value(plot)?;
+	validator.validate(check_plot_capsule);
+	let plot = validator.finish()?;
+	Ok(ValidatedPlot { plot })
+}
+
+/// Validate a module, as found in the `module.wf` file.
+pub fn validate_module(module: &str) -> Result<ValidatedModule> {
+	let mut validator = Validator::parse_json_value(module)?;
+	validator.validate(check_module);
+	let module = validator.finish()?;
+	Ok(ValidatedModule { module })
+}
+
+/// Validate the `_module.json` file of a catalog module.
+pub fn validate_catalog_module(module: &str) -> Result<ValidatedCatalogModule> {
+	let mut validator = Validator::parse_json_value(module)?;
+	validator.validate(check_catalog_module);
+	let module = validator.finish()?;
+	Ok(ValidatedCatalogModule { module })
+}
+
+/// Validate a release file (`_releases/<name>.json`) of a catalog module.
+pub fn validate_catalog_release(release: &str) -> Result<ValidatedCatalogRelease> {
+	let mut validator = Validator::parse_json_value(release)?;
+	validator.validate(check_catalog_release);
+	let release = validator.finish()?;
+	Ok(ValidatedCatalogRelease { release })
 }
 
 pub struct ValidatedFormula {
 	pub formula: FormulaAndContext,
 }
 
+pub struct ValidatedModule {
+	pub module: ModuleCapsule,
+}
+
+pub struct ValidatedPlot {
+	pub plot: PlotCapsule,
+}
+
+pub struct ValidatedCatalogModule {
+	pub module: CatalogModuleCapsule,
+}
+
+pub struct ValidatedCatalogRelease {
+	pub release: CatalogRelease,
+}
+
 struct Validator<'a> {
 	modified_json: Option<Vec<u8>>,
 	errors: Vec<ValidationError>,
@@ -120,30 +179,44 @@ impl<'a> Validator<'a> {
 		})
 	}
 
-	fn finish_formula(mut self) -> Result<ValidatedFormula> {
+	fn finish<T: DeserializeOwned>(mut self) -> Result<T> {
 		let deserialize_err = if self.errors.is_empty() {
 			// Setting self.parsed to Value::default here:
 			// Don't use self.parsed anymore from here on.
 			let parsed = mem::take(&mut self.parsed);
 			match serde_json::from_value(parsed) {
-				Ok(validated) => return Ok(ValidatedFormula { formula: validated }),
+				Ok(validated) => return Ok(validated),
 				Err(err) => Some(err),
 			}
 		} else {
 			None
 		};
 
-		self.finish_error(deserialize_err)
+		self.finish_error::<T>(deserialize_err)
 	}
 
-	fn finish_error<T>(mut self, deserialize_err: Option<serde_json::Error>) -> Result<T> {
+	fn finish_error<T: DeserializeOwned>(
+		mut self,
+		deserialize_err: Option<serde_json::Error>,
+	) -> Result<T> {
 		// Parse again with serde_json::from_slice to get line and column in error.
 		// serde_json::from_value populates line and column with 0.
 		let json = (self.modified_json.as_deref()).unwrap_or(self.json.as_bytes());
-		let parse_result = serde_json::from_slice::<FormulaAndContext>(json);
+		let parse_result = serde_json::from_slice::<T>(json);
 		match (parse_result, deserialize_err) {
 			(Err(err), _) => {
-				self.errors.push(ValidationError::Serde(err));
+				// Our own checks usually found the same problem already and
+				// have a better message for it, so we skip serde's duplicate.
+				let offset = find_byte_offset(json, err.line(), err.column());
+				let duplicate = offset.is_some_and(|offset| {
+					(self.errors.iter())
+						.filter(|error| matches!(error, ValidationError::Custom(_)))
+						.filter_map(|error| error.span(self.json))
+						.any(|span| span.start <= offset && offset <= span.end)
+				});
+				if !duplicate {
+					self.errors.push(ValidationError::Serde(err));
+				}
 			}
 			(Ok(_), None) => {}
 			(Ok(_), Some(err)) => {
@@ -157,10 +230,11 @@ impl<'a> Validator<'a> {
 		})
 	}
 
-	fn validate_formula(&mut self) -> Result<()> {
-		let errors = self.check_formula(&self.parsed, false);
+	/// Run `check` on the parsed json and collect its errors with their positions.
+	fn validate(&mut self, check: impl FnOnce(&serde_json::Value) -> Vec<PathError>) {
+		let errors = check(&self.parsed);
 		if errors.is_empty() {
-			return Ok(());
+			return;
 		}
 
 		let json = (self.modified_json.as_deref()).unwrap_or(self.json.as_bytes());
@@ -168,7 +242,7 @@ impl<'a> Validator<'a> {
 			debug!("failed to get position of some errors");
 			self.errors
 				.extend(errors.into_iter().map(|path_err| path_err.inner));
-			return Ok(());
+			return;
 		};
 
 		for mut error in errors {
@@ -178,63 +252,273 @@ impl<'a> Validator<'a> {
 			error.inner.try_set_span(span);
 			self.errors.push(error.inner);
 		}
+	}
+}
+
+fn check_formula(value: &serde_json::Value, protoformula: bool) -> Vec<PathError> {
+	expect_key(value, "formula", |value| {
+		expect_key(value, "formula.v1", |value| {
+			let mut errors = expect_key(value, "inputs", |value| {
+				check_formula_inputs(value, protoformula)
+			});
+			errors.append(&mut expect_key(value, "action", |_value| {
+				Vec::with_capacity(0) // TODO
+			}));
+			errors.append(&mut expect_key(value, "outputs", |_value| {
+				Vec::with_capacity(0) // TODO
+			}));
+
+			errors
+		})
+	})
+}
+
+fn check_formula_inputs(value: &serde_json::Value, protoformula: bool) -> Vec<PathError> {
+	expect_key(value, "/", |value| {
+		expect_string(value, |value| {
+			let Some(oci) = value.strip_prefix("oci:") else {
+				return PathError::custom("formula input '/' currently has to be of type 'oci'");
+			};
+			check_oci_reference(oci, !protoformula)
+		})
+	})
 
-		Ok(())
+	// TODO: Add more checks here.
+}
+
+fn check_oci_reference(oci: &str, require_digest: bool) -> Vec<PathError> {
+	let reference = match oci.parse::<Reference>() {
+		Ok(reference) => reference,
+		Err(err) => {
+			return PathError::custom(format!("failed to parse oci reference: {err}"));
+		}
+	};
+
+	if require_digest && reference.digest().is_none() {
+		return PathError::build("formula inputs of type 'oci' are required to contain digest")
+			.with_label("invalid oci reference")
+			.with_note("use '@' to add a digest: \"oci:docker.io/library/busybox@sha256:<DIGEST>\"")
+			.finish();
 	}
 
-	fn check_formula(&self, value: &serde_json::Value, protoformula: bool) -> Vec<PathError> {
-		expect_key(value, "formula", |value| {
-			expect_key(value, "formula.v1", |value| {
-				let mut errors = expect_key(value, "inputs", |value| {
-					self.check_formula_inputs(value, protoformula)
-				});
-				errors.append(&mut expect_key(value, "action", |value| {
-					Vec::with_capacity(0) // TODO
-				}));
-				errors.append(&mut expect_key(value, "outputs", |value| {
-					Vec::with_capacity(0) // TODO
-				}));
-
-				errors
+	Vec::with_capacity(0)
+}
+
+fn check_plot_capsule(value: &serde_json::Value) -> Vec<PathError> {
+	expect_key(value, "plot.v1", |plot| check_plot(plot, None))
+}
+
+/// Check a plot.  `parent` is the enclosing plot of a nested plot,
+/// whose inputs are piped in from the steps of the parent.
+fn check_plot(plot: &serde_json::Value, parent: Option<&serde_json::Value>) -> Vec<PathError> {
+	let mut errors = expect_key(plot, "inputs", |inputs| {
+		expect_object_iterate(inputs, |(_, input)| {
+			expect_plot_input(input, |input| match (input, parent) {
+				(PlotInput::OCIReference(oci), _) => check_oci_reference(&oci, false),
+				(PlotInput::Pipe(pipe), Some(parent)) => {
+					check_pipe(parent, &pipe.step_name, &pipe.label.0)
+				}
+				(PlotInput::Pipe(_), None) => PathError::build("plot inputs may not contain pipes")
+					.with_label("pipe")
+					.finish(),
+				_ => Vec::with_capacity(0),
+			})
+		})
+	});
+
+	errors.append(&mut expect_key(plot, "steps", |steps| {
+		expect_object_iterate(steps, |(_, step)| check_step(plot, step))
+	}));
+
+	errors.append(&mut expect_key(plot, "outputs", |outputs| {
+		expect_object_iterate(outputs, |(_, output)| {
+			expect_string(output, |output| match output.parse::<PlotOutput>() {
+				Ok(PlotOutput::Pipe(pipe)) if pipe.step_name.is_empty() => {
+					PathError::build("plot outputs have to reference a step output")
+						.with_label("missing step name")
+						.finish()
+				}
+				Ok(PlotOutput::Pipe(pipe)) => check_pipe(plot, &pipe.step_name, &pipe.label.0),
+				Err(err) => PathError::custom(format!("invalid plot output: {err}")),
 			})
 		})
+	}));
+
+	errors
+}
+
+fn check_step(plot: &serde_json::Value, step: &serde_json::Value) -> Vec<PathError> {
+	let Some(object) = step.as_object() else {
+		return PathError::custom("expected object");
+	};
+
+	if object.contains_key("plot") {
+		return expect_key(step, "plot", |sub_plot| check_plot(sub_plot, Some(plot)));
 	}
 
-	fn check_formula_inputs(
-		&self,
-		value: &serde_json::Value,
-		protoformula: bool,
-	) -> Vec<PathError> {
-		expect_key(value, "/", |value| {
-			expect_string(value, |value| {
-				let Some(oci) = value.strip_prefix("oci:") else {
-					return PathError::custom(
-						"formula input '/' currently has to be of type 'oci'",
-					);
-				};
-
-				let reference = match oci.parse::<Reference>() {
-					Ok(reference) => reference,
-					Err(err) => {
-						return PathError::custom(format!("failed to parse oci reference: {err}"));
-					}
-				};
-
-				if !protoformula && reference.digest().is_none() {
-					return PathError::build(
-						"formula inputs of type 'oci' are required to contain digest",
-					)
-					.with_label("invalid oci reference")
-					.with_note("use '@' to add a digest: \"oci:docker.io/library/busybox@sha256:<DIGEST>\"")
-					.finish();
+	expect_key(step, "protoformula", |protoformula| {
+		let mut errors = expect_key(protoformula, "inputs", |inputs| {
+			let mut errors = expect_object_iterate(inputs, |(_, input)| {
+				expect_plot_input(input, |input| match input {
+					PlotInput::Pipe(pipe) => check_pipe(plot, &pipe.step_name, &pipe.label.0),
+					PlotInput::OCIReference(oci) => check_oci_reference(&oci, false),
+					_ => Vec::with_capacity(0),
+				})
+			});
+			errors.append(&mut expect_key(inputs, "/", |root| {
+				expect_plot_input(root, |input| check_protoformula_root(plot, input))
+			}));
+			errors
+		});
+		errors.append(&mut expect_key(protoformula, "action", |_value| {
+			Vec::with_capacity(0) // TODO
+		}));
+		errors.append(&mut expect_key(protoformula, "outputs", |_value| {
+			Vec::with_capacity(0) // TODO
+		}));
+		errors
+	})
+}
+
+/// The root input of a protoformula has to be an oci image,
+/// either directly or piped from the plot inputs.
+fn check_protoformula_root(plot: &serde_json::Value, input: PlotInput) -> Vec<PathError> {
+	let is_oci = match input {
+		PlotInput::OCIReference(_) => true,
+		PlotInput::Pipe(pipe) if pipe.step_name.is_empty() => {
+			let plot_input = (plot.get("inputs"))
+				.and_then(|inputs| inputs.get(&pipe.label.0))
+				.and_then(serde_json::Value::as_str);
+			match plot_input {
+				// Inputs of nested plots can be piped in from the parent plot,
+				// whose pipe is checked where it is declared.
+				Some(plot_input) => {
+					plot_input.starts_with("oci:") || plot_input.starts_with("pipe:")
 				}
+				None => return Vec::with_capacity(0), // Reported by `check_pipe`.
+			}
+		}
+		_ => false,
+	};
 
-				Vec::with_capacity(0)
-			})
-		})
+	if is_oci {
+		return Vec::with_capacity(0);
+	}
+	PathError::build("protoformula input '/' currently has to be of type 'oci'")
+		.with_note("use an 'oci' plot input and pipe it to '/': \"pipe::<INPUT>\"")
+		.finish()
+}
+
+fn check_pipe(plot: &serde_json::Value, step_name: &str, label: &str) -> Vec<PathError> {
+	if step_name.is_empty() {
+		let exists = (plot.get("inputs"))
+			.and_then(serde_json::Value::as_object)
+			.is_some_and(|inputs| inputs.contains_key(label));
+		if exists {
+			return Vec::with_capacity(0);
+		}
+		return PathError::build(format!("pipe references unknown plot input '{label}'"))
+			.with_label("unknown input")
+			.finish();
+	}
 
-		// TODO: Add more checks here.
+	let Some(step) = (plot.get("steps")).and_then(|steps| steps.get(step_name)) else {
+		return PathError::build(format!("pipe references unknown step '{step_name}'"))
+			.with_label("unknown step")
+			.finish();
+	};
+
+	let outputs = (step.get("protoformula"))
+		.or_else(|| step.get("plot"))
+		.and_then(|step| step.get("outputs"))
+		.and_then(serde_json::Value::as_object);
+	if outputs.is_some_and(|outputs| outputs.contains_key(label)) {
+		return Vec::with_capacity(0);
 	}
+	PathError::build(format!(
+		"pipe references unknown output '{label}' of step '{step_name}'"
+	))
+	.with_label("unknown output")
+	.finish()
+}
+
+fn check_module(value: &serde_json::Value) -> Vec<PathError> {
+	expect_key(value, "module.v1", |module| {
+		let mut errors = expect_key(module, "name", |name| {
+			expect_string(name, check_module_name)
+		});
+		if module.get("metadata").is_some() {
+			errors.append(&mut expect_key(module, "metadata", |metadata| {
+				expect_object_iterate(metadata, |(_, value)| expect_string(value, |_| Vec::new()))
+			}));
+		}
+		errors
+	})
+}
+
+/// Module names are used as paths inside of catalogs (e.g. "warpsys.org/bash"),
+/// so they have to be usable as relative paths.
+fn check_module_name(name: &str) -> Vec<PathError> {
+	let note = "module names look like a path without leading slash: \"warpsys.org/bash\"";
+	if name.is_empty() {
+		return PathError::build("module name must not be empty")
+			.with_note(note)
+			.finish();
+	}
+	let invalid_segment =
+		(name.split('/')).find(|segment| segment.is_empty() || *segment == "." || *segment == "..");
+	if let Some(segment) = invalid_segment {
+		return PathError::build(format!("module name contains invalid segment '{segment}'"))
+			.with_label("invalid module name")
+			.with_note(note)
+			.finish();
+	}
+	if name
+		.chars()
+		.any(|c| c.is_whitespace() || c.is_control() || c == ':')
+	{
+		return PathError::build("module name must not contain whitespace or ':'")
+			.with_label("invalid module name")
+			.with_note(note)
+			.finish();
+	}
+	Vec::with_capacity(0)
+}
+
+fn check_catalog_module(value: &serde_json::Value) -> Vec<PathError> {
+	expect_key(value, "catalogmodule.v1", |module| {
+		let mut errors = expect_key(module, "name", |name| {
+			expect_string(name, check_module_name)
+		});
+		errors.append(&mut expect_key(module, "releases", |releases| {
+			expect_object_iterate(releases, |(_, cid)| expect_string(cid, |_| Vec::new()))
+		}));
+		errors.append(&mut expect_key(module, "metadata", |metadata| {
+			expect_object_iterate(metadata, |(_, value)| expect_string(value, |_| Vec::new()))
+		}));
+		errors
+	})
+}
+
+fn check_catalog_release(release: &serde_json::Value) -> Vec<PathError> {
+	let mut errors = expect_key(release, "releaseName", |name| {
+		expect_string(name, |_| Vec::new())
+	});
+	errors.append(&mut expect_key(release, "items", |items| {
+		expect_object_iterate(items, |(_, ware_id)| {
+			expect_string(ware_id, |ware_id| match ware_id.parse::<WareID>() {
+				Ok(_) => Vec::with_capacity(0),
+				Err(err) => PathError::build(format!("invalid ware id: {err}"))
+					.with_label("invalid ware id")
+					.with_note("ware ids look like \"tar:<HASH>\"")
+					.finish(),
+			})
+		})
+	}));
+	errors.append(&mut expect_key(release, "metadata", |metadata| {
+		expect_object_iterate(metadata, |(_, value)| expect_string(value, |_| Vec::new()))
+	}));
+	errors
 }
 
 fn find_byte_offset(src: &[u8], line: usize, column: usize) -> Option<usize> {
@@ -341,6 +625,16 @@ fn expect_string<'a>(
 	inspect(string)
 }
 
+fn expect_plot_input(
+	value: &serde_json::Value,
+	inspect: impl FnOnce(PlotInput) -> Vec<PathError>,
+) -> Vec<PathError> {
+	expect_string(value, |value| match value.parse::<PlotInput>() {
+		Ok(input) => inspect(input),
+		Err(err) => PathError::custom(format!("invalid plot input: {err}")),
+	})
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -487,3 +781,140 @@ impl PathErrorBuilder {
 		}]
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn messages(result: Result<impl Sized>) -> Vec<String> {
+		let Err(Error::Invalid { errors }) = result else {
+			return Vec::new();
+		};
+		errors.iter().map(ToString::to_string).collect()
+	}
+
+	#[test]
+	fn plot_valid() {
+		let plot = r#"{
+			"plot.v1": {
+				"inputs": { "image": "oci:docker.io/busybox:latest" },
+				"steps": {
+					"one": {
+						"protoformula": {
+							"inputs": { "/": "pipe::image" },
+							"action": { "exec": { "command": ["/bin/true"] } },
+							"outputs": { "out": { "from": "/out" } }
+						}
+					},
+					"two": {
+						"protoformula": {
+							"inputs": { "/": "pipe::image", "/in": "pipe:one:out" },
+							"action": { "exec": { "command": ["/bin/true"] } },
+							"outputs": { "out": { "from": "/out" } }
+						}
+					}
+				},
+				"outputs": { "result": "pipe:two:out" }
+			}
+		}"#;
+		assert!(validate_plot(plot).is_ok());
+	}
+
+	#[test]
+	fn plot_reports_all_dangling_pipes() {
+		let plot = r#"{
+			"plot.v1": {
+				"inputs": { "image": "oci:docker.io/busybox:latest" },
+				"steps": {
+					"one": {
+						"protoformula": {
+							"inputs": { "/": "pipe::nope", "/in": "pipe:missing:out" },
+							"action": { "exec": { "command": ["/bin/true"] } },
+							"outputs": { "out": { "from": "/out" } }
+						}
+					}
+				},
+				"outputs": { "result": "pipe:one:wrong" }
+			}
+		}"#;
+		assert_eq!(
+			messages(validate_plot(plot)),
+			vec![
+				"pipe references unknown plot input 'nope'",
+				"pipe references unknown step 'missing'",
+				"pipe references unknown output 'wrong' of step 'one'",
+			]
+		);
+	}
+
+	#[test]
+	fn nested_plot_inputs_pipe_from_parent() {
+		let plot = r#"{
+			"plot.v1": {
+				"inputs": { "image": "oci:docker.io/busybox:latest" },
+				"steps": {
+					"one": {
+						"protoformula": {
+							"inputs": { "/": "pipe::image" },
+							"action": { "exec": { "command": ["/bin/true"] } },
+							"outputs": { "out": { "from": "/out" } }
+						}
+					},
+					"nested": {
+						"plot": {
+							"inputs": { "image": "pipe::image", "in": "pipe:one:out", "bad": "pipe:one:nope" },
+							"steps": {
+								"inner": {
+									"protoformula": {
+										"inputs": { "/": "pipe::image", "/in": "pipe::in" },
+										"action": { "exec": { "command": ["/bin/true"] } },
+										"outputs": { "out": { "from": "/out" } }
+									}
+								}
+							},
+							"outputs": { "out": "pipe:inner:out" }
+						}
+					}
+				},
+				"outputs": { "result": "pipe:nested:out" }
+			}
+		}"#;
+		assert_eq!(
+			messages(validate_plot(plot)),
+			vec!["pipe references unknown output 'nope' of step 'one'"]
+		);
+	}
+
+	#[test]
+	fn module_name() {
+		assert!(validate_module(r#"{"module.v1": {"name": "warpsys.org/bash"}}"#).is_ok());
+		assert_eq!(
+			messages(validate_module(
+				r#"{"module.v1": {"name": "warpsys.org//bash"}}"#
+			)),
+			vec!["module name contains invalid segment ''"],
+		);
+		assert_eq!(
+			messages(validate_module(r#"{"module.v1": {"name": "bash:v1"}}"#)),
+			vec!["module name must not contain whitespace or ':'"],
+		);
+	}
+
+	#[test]
+	fn catalog_release_invalid_ware_id() {
+		let release = r#"{
+			"releaseName": "v1.0",
+			"items": { "amd64": "nocolon" },
+			"metadata": {}
+		}"#;
+		let result = validate_catalog_release(release);
+		let Err(Error::Invalid { errors }) = &result else {
+			panic!("expected validation to fail");
+		};
+		assert_eq!(errors[0].label(), Some("invalid ware id"));
+		assert_eq!(
+			errors[0].span(release),
+			release.find("\"nocolon\"").map(|start| start..start + 9)
+		);
+	}
+}